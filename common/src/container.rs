@@ -0,0 +1,440 @@
+//! Versioned container format for TAM bytecode.
+//!
+//! A container starts with a fixed header:
+//!
+//! | bytes | field                                           |
+//! |-------|-------------------------------------------------|
+//! | 4     | magic number `\x7fTAM`                          |
+//! | 2     | format version                                  |
+//! | 2     | number of sections                              |
+//! | 4     | entry point, as a code address                  |
+//! | 4     | CRC-32 of the rest of the file, header included |
+//!
+//! The header is followed by the sections, each of which is a 2-byte kind, a
+//! 4-byte length in bytes, and that many bytes of payload. All values are
+//! big-endian. Sections of unknown kinds are skipped when reading.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::{Cursor, Read},
+};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+
+use crate::{debug::DebugInfo, instruction::Instruction};
+
+/// Magic number at the start of every container.
+pub const MAGIC: [u8; 4] = *b"\x7fTAM";
+
+/// Version of the format written by this crate.
+pub const VERSION: u16 = 1;
+
+const HEADER_LEN: usize = 16;
+
+/// Offset of the checksum in the header, which covers every byte but its own.
+const CHECKSUM_OFFSET: usize = 12;
+
+const SECTION_CODE: u16 = 1;
+const SECTION_DATA: u16 = 2;
+const SECTION_SYMBOLS: u16 = 3;
+const SECTION_DEBUG: u16 = 4;
+
+/// Represents the ways reading or writing a container can fail.
+#[derive(Debug, PartialEq)]
+pub enum ContainerError {
+    /// Indicate the bytes do not start with the magic number.
+    BadMagic,
+    /// Indicate the container was written by an unsupported version of the format.
+    UnsupportedVersion(u16),
+    /// Indicate the container ends part way through a header, section or record.
+    Truncated,
+    /// Indicate the contents do not match the checksum in the header.
+    ChecksumMismatch(u32, u32),
+    /// Indicate the entry point is beyond the largest code address.
+    InvalidEntry(u32),
+    /// Indicate a section of the given kind appears more than once.
+    DuplicateSection(u16),
+    /// Indicate a section of the given kind has a malformed payload.
+    BadSection(u16),
+    /// Indicate a symbol name is too long for its 2-byte length to record.
+    NameTooLong(String),
+}
+
+impl Display for ContainerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::BadMagic => write!(f, "not a TAM bytecode file"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported bytecode version {v}"),
+            Self::Truncated => write!(f, "bytecode file is truncated"),
+            Self::ChecksumMismatch(expected, found) => write!(
+                f,
+                "bytecode file is corrupt: checksum is {found:08x}, expected {expected:08x}"
+            ),
+            Self::InvalidEntry(entry) => {
+                write!(f, "entry point {entry:#x} is not a code address")
+            }
+            Self::DuplicateSection(kind) => write!(f, "duplicate section of kind {kind}"),
+            Self::BadSection(kind) => write!(f, "malformed section of kind {kind}"),
+            Self::NameTooLong(name) => {
+                let prefix: String = name.chars().take(16).collect();
+                write!(f, "symbol name `{prefix}...` is too long")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {}
+
+/// Which store a symbol's address refers to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SymbolKind {
+    /// An address in the code store.
+    Code,
+    /// An address in the data store, relative to SB.
+    Data,
+}
+
+/// A named address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub addr: u16,
+}
+
+/// A program and its associated data, as stored in a bytecode file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Container {
+    /// Code address at which execution starts.
+    pub entry: u16,
+    /// Instructions to load into the code store.
+    pub code: Vec<Instruction>,
+    /// Words to load into the data store at SB before the program runs.
+    pub data: Vec<i16>,
+    /// Names of code and data addresses.
+    pub symbols: Vec<Symbol>,
+    /// Debug information, stored verbatim.
+    pub debug: Vec<u8>,
+}
+
+impl Container {
+    /// Check if `bytes` start with the container magic number.
+    pub fn is_container(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    /// Construct a container from a legacy bytecode file, which is a bare sequence
+    /// of big-endian instructions.
+    ///
+    /// Any trailing bytes that do not make up a whole instruction are ignored.
+    pub fn from_raw(bytes: &[u8]) -> Container {
+        let code = bytes
+            .chunks_exact(4)
+            .map(|w| Instruction::from(u32::from_be_bytes([w[0], w[1], w[2], w[3]])))
+            .collect();
+        Container {
+            code,
+            ..Container::default()
+        }
+    }
+
+    /// Get the names of the code addresses in the symbol table.
+    pub fn code_labels(&self) -> HashMap<String, usize> {
+        self.symbols
+            .iter()
+            .filter(|sym| sym.kind == SymbolKind::Code)
+            .map(|sym| (sym.name.clone(), sym.addr as usize))
+            .collect()
+    }
+
+    /// Decode the debug information, if the container has any.
+    pub fn debug_info(&self) -> Option<DebugInfo> {
+        if self.debug.is_empty() {
+            return None;
+        }
+        DebugInfo::from_bytes(&self.debug)
+    }
+
+    /// Encode the container, failing if a symbol name is too long to be recorded.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ContainerError> {
+        let mut sections = Vec::new();
+        let mut count = 0;
+
+        let mut code = Vec::new();
+        for instr in &self.code {
+            code.write_u32::<BE>(u32::from(*instr)).unwrap();
+        }
+        write_section(&mut sections, &mut count, SECTION_CODE, &code);
+
+        let mut data = Vec::new();
+        for word in &self.data {
+            data.write_i16::<BE>(*word).unwrap();
+        }
+        write_section(&mut sections, &mut count, SECTION_DATA, &data);
+
+        let mut symbols = Vec::new();
+        for sym in &self.symbols {
+            let len = u16::try_from(sym.name.len())
+                .map_err(|_| ContainerError::NameTooLong(sym.name.clone()))?;
+            symbols.write_u8(sym.kind as u8).unwrap();
+            symbols.write_u16::<BE>(sym.addr).unwrap();
+            symbols.write_u16::<BE>(len).unwrap();
+            symbols.extend_from_slice(sym.name.as_bytes());
+        }
+        write_section(&mut sections, &mut count, SECTION_SYMBOLS, &symbols);
+        write_section(&mut sections, &mut count, SECTION_DEBUG, &self.debug);
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + sections.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.write_u16::<BE>(VERSION).unwrap();
+        bytes.write_u16::<BE>(count).unwrap();
+        bytes.write_u32::<BE>(self.entry as u32).unwrap();
+        bytes.write_u32::<BE>(crc32(&[&bytes, &sections])).unwrap();
+        bytes.extend_from_slice(&sections);
+        Ok(bytes)
+    }
+
+    /// Decode a container, verifying its header and checksum.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Container, ContainerError> {
+        if !Container::is_container(bytes) {
+            return Err(ContainerError::BadMagic);
+        }
+        let mut header = Cursor::new(&bytes[MAGIC.len()..]);
+        let version = header
+            .read_u16::<BE>()
+            .map_err(|_| ContainerError::Truncated)?;
+        if version != VERSION {
+            return Err(ContainerError::UnsupportedVersion(version));
+        }
+        let count = header
+            .read_u16::<BE>()
+            .map_err(|_| ContainerError::Truncated)?;
+        let entry = header
+            .read_u32::<BE>()
+            .map_err(|_| ContainerError::Truncated)?;
+        let checksum = header
+            .read_u32::<BE>()
+            .map_err(|_| ContainerError::Truncated)?;
+
+        let body = &bytes[HEADER_LEN..];
+        let found = crc32(&[&bytes[..CHECKSUM_OFFSET], body]);
+        if found != checksum {
+            return Err(ContainerError::ChecksumMismatch(checksum, found));
+        }
+        let entry =
+            u16::try_from(entry).map_err(|_| ContainerError::InvalidEntry(entry))?;
+
+        let mut container = Container {
+            entry,
+            ..Container::default()
+        };
+        let mut seen = Vec::new();
+        let mut body = Cursor::new(body);
+        for _ in 0..count {
+            let kind = body
+                .read_u16::<BE>()
+                .map_err(|_| ContainerError::Truncated)?;
+            let len = body
+                .read_u32::<BE>()
+                .map_err(|_| ContainerError::Truncated)?;
+            if len as u64 > body.get_ref().len() as u64 - body.position() {
+                return Err(ContainerError::Truncated);
+            }
+            let mut payload = vec![0; len as usize];
+            body.read_exact(&mut payload)
+                .map_err(|_| ContainerError::Truncated)?;
+
+            if seen.contains(&kind) {
+                return Err(ContainerError::DuplicateSection(kind));
+            }
+            seen.push(kind);
+            container.read_section(kind, &payload)?;
+        }
+        Ok(container)
+    }
+
+    fn read_section(
+        &mut self,
+        kind: u16,
+        payload: &[u8],
+    ) -> Result<(), ContainerError> {
+        let bad = |_| ContainerError::BadSection(kind);
+        let mut rdr = Cursor::new(payload);
+        let remaining = |rdr: &Cursor<&[u8]>| rdr.position() < payload.len() as u64;
+        match kind {
+            SECTION_CODE => {
+                if !payload.len().is_multiple_of(4) {
+                    return Err(ContainerError::BadSection(kind));
+                }
+                while remaining(&rdr) {
+                    self.code
+                        .push(Instruction::from(rdr.read_u32::<BE>().map_err(bad)?));
+                }
+            }
+            SECTION_DATA => {
+                if !payload.len().is_multiple_of(2) {
+                    return Err(ContainerError::BadSection(kind));
+                }
+                while remaining(&rdr) {
+                    self.data.push(rdr.read_i16::<BE>().map_err(bad)?);
+                }
+            }
+            SECTION_SYMBOLS => {
+                while remaining(&rdr) {
+                    let sym_kind = match rdr.read_u8().map_err(bad)? {
+                        0 => SymbolKind::Code,
+                        1 => SymbolKind::Data,
+                        _ => return Err(ContainerError::BadSection(kind)),
+                    };
+                    let addr = rdr.read_u16::<BE>().map_err(bad)?;
+                    let len = rdr.read_u16::<BE>().map_err(bad)?;
+                    let mut name = vec![0; len as usize];
+                    rdr.read_exact(&mut name).map_err(bad)?;
+                    let name = String::from_utf8(name)
+                        .map_err(|_| ContainerError::BadSection(kind))?;
+                    self.symbols.push(Symbol {
+                        name,
+                        kind: sym_kind,
+                        addr,
+                    });
+                }
+            }
+            SECTION_DEBUG => self.debug = payload.to_vec(),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+fn write_section(out: &mut Vec<u8>, count: &mut u16, kind: u16, payload: &[u8]) {
+    if payload.is_empty() && kind != SECTION_CODE {
+        return;
+    }
+    out.write_u16::<BE>(kind).unwrap();
+    out.write_u32::<BE>(payload.len() as u32).unwrap();
+    out.extend_from_slice(payload);
+    *count += 1;
+}
+
+/// Compute the CRC-32 (IEEE 802.3) checksum of `parts`, one after another.
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for b in parts.iter().copied().flatten() {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Container {
+        Container {
+            entry: 1,
+            code: vec![Instruction::from(0x30000005), Instruction::from(0xf0000000)],
+            data: vec![-1, 2],
+            symbols: vec![
+                Symbol {
+                    name: String::from("start"),
+                    kind: SymbolKind::Code,
+                    addr: 1,
+                },
+                Symbol {
+                    name: String::from("count"),
+                    kind: SymbolKind::Data,
+                    addr: 0,
+                },
+            ],
+            debug: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(0xcbf43926, crc32(&[b"123456789"]));
+        assert_eq!(0xcbf43926, crc32(&[b"1234", b"", b"56789"]));
+    }
+
+    #[test]
+    fn round_trip() {
+        let c = sample();
+        assert_eq!(Ok(c.clone()), Container::from_bytes(&c.to_bytes().unwrap()));
+    }
+
+    #[test]
+    fn round_trip_empty() {
+        let c = Container::default();
+        assert_eq!(Ok(c.clone()), Container::from_bytes(&c.to_bytes().unwrap()));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let bytes = [0x30, 0x00, 0x00, 0x05];
+        assert_eq!(Err(ContainerError::BadMagic), Container::from_bytes(&bytes));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = sample().to_bytes().unwrap();
+        bytes[5] = 9;
+        assert_eq!(
+            Err(ContainerError::UnsupportedVersion(9)),
+            Container::from_bytes(&bytes)
+        );
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut bytes = sample().to_bytes().unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        assert!(matches!(
+            Container::from_bytes(&bytes),
+            Err(ContainerError::ChecksumMismatch(_, _))
+        ));
+    }
+
+    #[test]
+    fn corrupt_header() {
+        let mut bytes = sample().to_bytes().unwrap();
+        bytes[11] ^= 0xff;
+        assert!(matches!(
+            Container::from_bytes(&bytes),
+            Err(ContainerError::ChecksumMismatch(_, _))
+        ));
+    }
+
+    #[test]
+    fn entry_beyond_code_store() {
+        let mut bytes = sample().to_bytes().unwrap();
+        bytes[8..12].copy_from_slice(&0x10000u32.to_be_bytes());
+        let checksum = crc32(&[&bytes[..CHECKSUM_OFFSET], &bytes[HEADER_LEN..]]);
+        bytes[CHECKSUM_OFFSET..HEADER_LEN].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(
+            Err(ContainerError::InvalidEntry(0x10000)),
+            Container::from_bytes(&bytes)
+        );
+    }
+
+    #[test]
+    fn name_too_long() {
+        let mut c = sample();
+        c.symbols[0].name = "a".repeat(1 << 16);
+        assert!(matches!(c.to_bytes(), Err(ContainerError::NameTooLong(_))));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = sample().to_bytes().unwrap();
+        assert_eq!(
+            Err(ContainerError::Truncated),
+            Container::from_bytes(&bytes[..10])
+        );
+    }
+}
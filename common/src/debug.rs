@@ -0,0 +1,125 @@
+//! Debug information relating a program to the source it was assembled from.
+//!
+//! The debug section of a container holds:
+//!
+//! | bytes | field                                               |
+//! |-------|-----------------------------------------------------|
+//! | 2     | length of the source file name                      |
+//! | n     | source file name, in UTF-8                          |
+//! | 8     | source line and column of each code address         |
+//!
+//! The line and column of an address are 4 bytes each, and the addresses are in
+//! order. Lines and columns are numbered from 1, and a line of 0 marks an address
+//! with no source position. All values are big-endian.
+
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::{Cursor, Read},
+};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+
+/// A position in a source file.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct SourceLoc {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl Display for SourceLoc {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// Source positions of the instructions of a program.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    /// Path of the source file, as it was given to the assembler.
+    pub file: String,
+    /// Source position of the instruction at each code address.
+    pub locs: Vec<SourceLoc>,
+}
+
+impl DebugInfo {
+    /// Get the source position of the instruction at `addr`, if it is known.
+    pub fn loc(&self, addr: usize) -> Option<SourceLoc> {
+        self.locs.get(addr).copied().filter(|loc| loc.line != 0)
+    }
+
+    /// Get the source line of the instruction at `addr`, if it is known.
+    pub fn line(&self, addr: usize) -> Option<u32> {
+        self.loc(addr).map(|loc| loc.line)
+    }
+
+    /// Describe the source position of the instruction at `addr` as
+    /// `file:line:col`, if it is known.
+    pub fn describe(&self, addr: usize) -> Option<String> {
+        self.loc(addr).map(|loc| format!("{}:{loc}", self.file))
+    }
+
+    /// Encode the debug information as the payload of a debug section.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_u16::<BE>(self.file.len() as u16).unwrap();
+        bytes.extend_from_slice(self.file.as_bytes());
+        for loc in &self.locs {
+            bytes.write_u32::<BE>(loc.line).unwrap();
+            bytes.write_u32::<BE>(loc.col).unwrap();
+        }
+        bytes
+    }
+
+    /// Decode the payload of a debug section, returning `None` if it is malformed.
+    pub fn from_bytes(bytes: &[u8]) -> Option<DebugInfo> {
+        let mut rdr = Cursor::new(bytes);
+        let len = rdr.read_u16::<BE>().ok()?;
+        let mut file = vec![0; len as usize];
+        rdr.read_exact(&mut file).ok()?;
+        let file = String::from_utf8(file).ok()?;
+
+        let rest = &bytes[rdr.position() as usize..];
+        if !rest.len().is_multiple_of(8) {
+            return None;
+        }
+        let locs: Vec<SourceLoc> = rest
+            .chunks_exact(8)
+            .map(|w| SourceLoc {
+                line: u32::from_be_bytes([w[0], w[1], w[2], w[3]]),
+                col: u32::from_be_bytes([w[4], w[5], w[6], w[7]]),
+            })
+            .collect();
+        // A known position is numbered from 1.
+        if locs.iter().any(|loc| loc.line != 0 && loc.col == 0) {
+            return None;
+        }
+        Some(DebugInfo { file, locs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let loc = |line, col| SourceLoc { line, col };
+        let info = DebugInfo {
+            file: String::from("fac.tasm"),
+            locs: vec![loc(1, 1), loc(2, 9), loc(0, 0), loc(4, 3)],
+        };
+        assert_eq!(Some(info.clone()), DebugInfo::from_bytes(&info.to_bytes()));
+        assert_eq!(Some(2), info.line(1));
+        assert_eq!(Some(String::from("fac.tasm:2:9")), info.describe(1));
+        assert_eq!(None, info.line(2));
+        assert_eq!(None, info.describe(9));
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(None, DebugInfo::from_bytes(&[]));
+        assert_eq!(None, DebugInfo::from_bytes(&[0, 4, b'a']));
+        assert_eq!(None, DebugInfo::from_bytes(&[0, 0, 0, 0, 0, 1]));
+        assert_eq!(None, DebugInfo::from_bytes(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0]));
+    }
+}
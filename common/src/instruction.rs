@@ -1,7 +1,4 @@
-use std::{
-    fmt::{Display, Formatter, Result},
-    u32,
-};
+use std::fmt::{Display, Formatter, Result};
 
 /// A single TAM instruction.
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -40,50 +37,415 @@ impl From<Instruction> for u32 {
     }
 }
 
-fn get_reg_name(r: u8) -> String {
-    let rname = match r {
-        0 => "cb",
-        1 => "ct",
-        2 => "pb",
-        3 => "pt",
-        4 => "sb",
-        5 => "st",
-        6 => "hb",
-        7 => "ht",
-        8 => "lb",
-        9 => "l1",
-        10 => "l2",
-        11 => "l3",
-        12 => "l4",
-        13 => "l5",
-        14 => "l6",
-        15 => "cp",
-        _ => "",
-    };
-    String::from(rname)
+/// Represents a field of an instruction that does not decode to a known value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Indicate an opcode that does not name an instruction.
+    InvalidOpcode(u8),
+    /// Indicate a register index outside the register file.
+    InvalidRegister(u8),
+    /// Indicate an offset from PB that does not name a primitive routine.
+    InvalidPrimitive(i16),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::InvalidOpcode(op) => write!(f, "invalid opcode {op}"),
+            Self::InvalidRegister(r) => write!(f, "invalid register {r}"),
+            Self::InvalidPrimitive(d) => write!(f, "invalid primitive {d}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// TAM instruction opcodes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Opcode {
+    Load = 0,
+    LoadA = 1,
+    LoadI = 2,
+    LoadL = 3,
+    Store = 4,
+    StoreI = 5,
+    Call = 6,
+    CallI = 7,
+    Return = 8,
+    Push = 10,
+    Pop = 11,
+    Jump = 12,
+    JumpI = 13,
+    JumpIf = 14,
+    Halt = 15,
+}
+
+impl Opcode {
+    /// Every opcode, in numeric order.
+    pub const ALL: [Opcode; 15] = [
+        Opcode::Load,
+        Opcode::LoadA,
+        Opcode::LoadI,
+        Opcode::LoadL,
+        Opcode::Store,
+        Opcode::StoreI,
+        Opcode::Call,
+        Opcode::CallI,
+        Opcode::Return,
+        Opcode::Push,
+        Opcode::Pop,
+        Opcode::Jump,
+        Opcode::JumpI,
+        Opcode::JumpIf,
+        Opcode::Halt,
+    ];
+
+    /// Get the assembler mnemonic for the opcode.
+    pub fn name(self) -> &'static str {
+        match self {
+            Opcode::Load => "load",
+            Opcode::LoadA => "loada",
+            Opcode::LoadI => "loadi",
+            Opcode::LoadL => "loadl",
+            Opcode::Store => "store",
+            Opcode::StoreI => "storei",
+            Opcode::Call => "call",
+            Opcode::CallI => "calli",
+            Opcode::Return => "return",
+            Opcode::Push => "push",
+            Opcode::Pop => "pop",
+            Opcode::Jump => "jump",
+            Opcode::JumpI => "jumpi",
+            Opcode::JumpIf => "jumpif",
+            Opcode::Halt => "halt",
+        }
+    }
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        Opcode::ALL
+            .into_iter()
+            .find(|op| *op as u8 == value)
+            .ok_or(DecodeError::InvalidOpcode(value))
+    }
+}
+
+/// TAM registers, in the order they are indexed by instructions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Register {
+    CB = 0,
+    CT = 1,
+    PB = 2,
+    PT = 3,
+    SB = 4,
+    ST = 5,
+    HB = 6,
+    HT = 7,
+    LB = 8,
+    L1 = 9,
+    L2 = 10,
+    L3 = 11,
+    L4 = 12,
+    L5 = 13,
+    L6 = 14,
+    CP = 15,
+}
+
+impl Register {
+    /// Every register, in index order.
+    pub const ALL: [Register; 16] = [
+        Register::CB,
+        Register::CT,
+        Register::PB,
+        Register::PT,
+        Register::SB,
+        Register::ST,
+        Register::HB,
+        Register::HT,
+        Register::LB,
+        Register::L1,
+        Register::L2,
+        Register::L3,
+        Register::L4,
+        Register::L5,
+        Register::L6,
+        Register::CP,
+    ];
+
+    /// Get the assembler name of the register.
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::CB => "cb",
+            Register::CT => "ct",
+            Register::PB => "pb",
+            Register::PT => "pt",
+            Register::SB => "sb",
+            Register::ST => "st",
+            Register::HB => "hb",
+            Register::HT => "ht",
+            Register::LB => "lb",
+            Register::L1 => "l1",
+            Register::L2 => "l2",
+            Register::L3 => "l3",
+            Register::L4 => "l4",
+            Register::L5 => "l5",
+            Register::L6 => "l6",
+            Register::CP => "cp",
+        }
+    }
+}
+
+impl TryFrom<u8> for Register {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        Register::ALL
+            .get(value as usize)
+            .copied()
+            .ok_or(DecodeError::InvalidRegister(value))
+    }
+}
+
+/// Primitive routines, numbered by their offset from PB.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Primitive {
+    Id = 1,
+    Not = 2,
+    And = 3,
+    Or = 4,
+    Inc = 5,
+    Dec = 6,
+    Neg = 7,
+    Add = 8,
+    Sub = 9,
+    Mul = 10,
+    Div = 11,
+    Mod = 12,
+    Lt = 13,
+    Le = 14,
+    Ge = 15,
+    Gt = 16,
+    Eq = 17,
+    Ne = 18,
+    Eol = 19,
+    Eof = 20,
+    Get = 21,
+    Put = 22,
+    GetEol = 23,
+    PutEol = 24,
+    GetInt = 25,
+    PutInt = 26,
+    New = 27,
+    Dispose = 28,
+}
+
+impl Primitive {
+    /// Every primitive, in order of offset from PB.
+    pub const ALL: [Primitive; 28] = [
+        Primitive::Id,
+        Primitive::Not,
+        Primitive::And,
+        Primitive::Or,
+        Primitive::Inc,
+        Primitive::Dec,
+        Primitive::Neg,
+        Primitive::Add,
+        Primitive::Sub,
+        Primitive::Mul,
+        Primitive::Div,
+        Primitive::Mod,
+        Primitive::Lt,
+        Primitive::Le,
+        Primitive::Ge,
+        Primitive::Gt,
+        Primitive::Eq,
+        Primitive::Ne,
+        Primitive::Eol,
+        Primitive::Eof,
+        Primitive::Get,
+        Primitive::Put,
+        Primitive::GetEol,
+        Primitive::PutEol,
+        Primitive::GetInt,
+        Primitive::PutInt,
+        Primitive::New,
+        Primitive::Dispose,
+    ];
+
+    /// Get the name used to call the primitive in assembly.
+    pub fn name(self) -> &'static str {
+        self.info().0
+    }
+
+    /// Get the number of words the primitive pops and pushes.
+    ///
+    /// `eq` and `ne` compare objects of any size; the counts given are for
+    /// single-word objects.
+    pub fn arity(self) -> (usize, usize) {
+        let (_, args, results, _) = self.info();
+        (args, results)
+    }
+
+    /// Get a description of the primitive's effect on the stack, with the top of
+    /// the stack on the right.
+    pub fn effect(self) -> &'static str {
+        self.info().3
+    }
+
+    fn info(self) -> (&'static str, usize, usize, &'static str) {
+        match self {
+            Primitive::Id => ("id", 1, 1, "x -> x"),
+            Primitive::Not => ("not", 1, 1, "t -> not t"),
+            Primitive::And => ("and", 2, 1, "t1 t2 -> t1 and t2"),
+            Primitive::Or => ("or", 2, 1, "t1 t2 -> t1 or t2"),
+            Primitive::Inc => ("inc", 1, 1, "i -> i + 1"),
+            Primitive::Dec => ("dec", 1, 1, "i -> i - 1"),
+            Primitive::Neg => ("neg", 1, 1, "i -> -i"),
+            Primitive::Add => ("add", 2, 1, "i1 i2 -> i1 + i2"),
+            Primitive::Sub => ("sub", 2, 1, "i1 i2 -> i1 - i2"),
+            Primitive::Mul => ("mul", 2, 1, "i1 i2 -> i1 * i2"),
+            Primitive::Div => ("div", 2, 1, "i1 i2 -> i1 / i2"),
+            Primitive::Mod => ("mod", 2, 1, "i1 i2 -> i1 mod i2"),
+            Primitive::Lt => ("lt", 2, 1, "i1 i2 -> i1 < i2"),
+            Primitive::Le => ("le", 2, 1, "i1 i2 -> i1 <= i2"),
+            Primitive::Ge => ("ge", 2, 1, "i1 i2 -> i1 >= i2"),
+            Primitive::Gt => ("gt", 2, 1, "i1 i2 -> i1 > i2"),
+            Primitive::Eq => ("eq", 3, 1, "v1 v2 n -> v1 = v2, for n-word values"),
+            Primitive::Ne => ("ne", 3, 1, "v1 v2 n -> v1 /= v2, for n-word values"),
+            Primitive::Eol => ("eol", 0, 1, "-> true if the next input is end-of-line"),
+            Primitive::Eof => ("eof", 0, 1, "-> true if there is no more input"),
+            Primitive::Get => ("get", 1, 0, "a -> ; read a character into address a"),
+            Primitive::Put => ("put", 1, 0, "c -> ; write character c"),
+            Primitive::GetEol => ("geteol", 0, 0, "-> ; skip input up to end-of-line"),
+            Primitive::PutEol => ("puteol", 0, 0, "-> ; write end-of-line"),
+            Primitive::GetInt => {
+                ("getint", 1, 0, "a -> ; read an integer into address a")
+            }
+            Primitive::PutInt => ("putint", 1, 0, "i -> ; write integer i"),
+            Primitive::New => ("new", 1, 1, "n -> a ; allocate n words on the heap"),
+            Primitive::Dispose => {
+                ("dispose", 2, 0, "n a -> ; free n words at address a")
+            }
+        }
+    }
+}
+
+impl TryFrom<i16> for Primitive {
+    type Error = DecodeError;
+
+    fn try_from(value: i16) -> std::result::Result<Self, Self::Error> {
+        Primitive::ALL
+            .into_iter()
+            .find(|p| *p as i16 == value)
+            .ok_or(DecodeError::InvalidPrimitive(value))
+    }
+}
+
+impl TryFrom<u8> for Primitive {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        Primitive::try_from(value as i16)
+    }
+}
+
+impl Instruction {
+    /// Decode the opcode of the instruction.
+    pub fn opcode(&self) -> std::result::Result<Opcode, DecodeError> {
+        Opcode::try_from(self.op)
+    }
+
+    /// Decode the register the instruction's address is relative to.
+    pub fn register(&self) -> std::result::Result<Register, DecodeError> {
+        Register::try_from(self.r)
+    }
+}
+
+fn reg_name(r: u8) -> &'static str {
+    Register::try_from(r).map_or("??", Register::name)
 }
 
 impl Display for Instruction {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        let Instruction { op, r, n, d } = self;
-        let reg_name = get_reg_name(*r);
-        match op {
-            0 => write!(f, "load    {n}, [{reg_name}{d:+}]"),
-            1 => write!(f, "loada   [{reg_name}{d:+}]"),
-            2 => write!(f, "loadi   {n}"),
-            3 => write!(f, "loadl   {d}"),
-            4 => write!(f, "store   {n}, [{reg_name}{d:+}]"),
-            5 => write!(f, "storei  {n}"),
-            6 => write!(f, "call    [{reg_name}{d:+}]"),
-            7 => write!(f, "calli"),
-            8 => write!(f, "return  {n}, {d}"),
-            10 => write!(f, "push    {d}"),
-            11 => write!(f, "pop"),
-            12 => write!(f, "jump    [{reg_name}{d:+}]"),
-            13 => write!(f, "jumpi"),
-            14 => write!(f, "jumpif  {n}, [{reg_name}{d:+}]"),
-            15 => write!(f, "halt"),
-            _ => write!(f, ""),
+        let Instruction { op, r, n, d } = *self;
+        let Ok(opcode) = Opcode::try_from(op) else {
+            return write!(f, "<invalid opcode {op}>");
+        };
+
+        let name = opcode.name();
+        let reg = reg_name(r);
+        match opcode {
+            Opcode::Load | Opcode::Store | Opcode::JumpIf => {
+                write!(f, "{name:<8}{n}, [{reg}{d:+}]")
+            }
+            Opcode::LoadA | Opcode::Jump => write!(f, "{name:<8}[{reg}{d:+}]"),
+            Opcode::LoadI | Opcode::StoreI => write!(f, "{name:<8}{n}"),
+            Opcode::LoadL | Opcode::Push => write!(f, "{name:<8}{d}"),
+            Opcode::Call => match Primitive::try_from(d) {
+                Ok(prim) if r == Register::PB as u8 => {
+                    write!(f, "{name:<8}{}", prim.name())
+                }
+                _ => write!(f, "{name:<8}{}, [{reg}{d:+}]", reg_name(n)),
+            },
+            Opcode::Return | Opcode::Pop => write!(f, "{name:<8}{n}, {d}"),
+            Opcode::CallI | Opcode::JumpI | Opcode::Halt => write!(f, "{name}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcode_round_trip() {
+        for op in Opcode::ALL {
+            assert_eq!(Ok(op), Opcode::try_from(op as u8));
         }
+        assert_eq!(Err(DecodeError::InvalidOpcode(9)), Opcode::try_from(9));
+        assert_eq!(Err(DecodeError::InvalidOpcode(16)), Opcode::try_from(16));
+    }
+
+    #[test]
+    fn register_round_trip() {
+        for (i, reg) in Register::ALL.into_iter().enumerate() {
+            assert_eq!(Ok(reg), Register::try_from(i as u8));
+        }
+        assert_eq!(
+            Err(DecodeError::InvalidRegister(16)),
+            Register::try_from(16)
+        );
+    }
+
+    #[test]
+    fn primitive_round_trip() {
+        for (i, prim) in Primitive::ALL.into_iter().enumerate() {
+            assert_eq!(Ok(prim), Primitive::try_from(i as i16 + 1));
+        }
+        assert_eq!(
+            Err(DecodeError::InvalidPrimitive(0)),
+            Primitive::try_from(0i16)
+        );
+        assert_eq!(
+            Err(DecodeError::InvalidPrimitive(29)),
+            Primitive::try_from(29i16)
+        );
+    }
+
+    #[test]
+    fn display() {
+        let show = |op, r, n, d| Instruction { op, r, n, d }.to_string();
+        assert_eq!("load    1, [sb+2]", show(0, 4, 1, 2));
+        assert_eq!("call    lb, [cb+9]", show(6, 0, 8, 9));
+        assert_eq!("call    putint", show(6, 2, 0, 26));
+        assert_eq!("pop     1, 2", show(11, 0, 1, 2));
+        assert_eq!("halt", show(15, 0, 0, 0));
+        assert_eq!("<invalid opcode 9>", show(9, 0, 0, 0));
     }
 }
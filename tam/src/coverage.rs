@@ -0,0 +1,141 @@
+//! Code coverage of a running program.
+//!
+//! [`Coverage`] records how many times each code address executes and which way
+//! each `jumpif` goes, and writes them as lcov tracefiles against the source lines
+//! in a program's [`DebugInfo`].
+
+use std::{collections::BTreeMap, io::Write};
+
+use common::{
+    debug::DebugInfo,
+    instruction::{Instruction, Opcode},
+};
+
+/// Execution counts of the instructions of a program since the machine was last
+/// reset.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    hits: Vec<u64>,
+    /// Times each `jumpif` jumped and fell through, by its address.
+    branches: BTreeMap<usize, [u64; 2]>,
+}
+
+impl Coverage {
+    /// Count an execution of the instruction at `addr`.
+    pub(crate) fn record(&mut self, addr: usize) {
+        if addr >= self.hits.len() {
+            self.hits.resize(addr + 1, 0);
+        }
+        self.hits[addr] += 1;
+    }
+
+    /// Count a `jumpif` at `addr` that either jumped or fell through.
+    pub(crate) fn record_branch(&mut self, addr: usize, taken: bool) {
+        self.branches.entry(addr).or_default()[if taken { 0 } else { 1 }] += 1;
+    }
+
+    /// Get the number of times the instruction at `addr` was executed.
+    pub fn hits(&self, addr: usize) -> u64 {
+        self.hits.get(addr).copied().unwrap_or(0)
+    }
+
+    /// Get the number of times the `jumpif` at `addr` jumped and fell through.
+    pub fn branch(&self, addr: usize) -> [u64; 2] {
+        self.branches.get(&addr).copied().unwrap_or_default()
+    }
+
+    /// Write the coverage of `code` as an lcov tracefile, mapping each address to a
+    /// source line with `debug`.
+    ///
+    /// A line's count is that of the most executed instruction on it. Each `jumpif`
+    /// is a block of two branches: the jump and the fall through.
+    pub fn write_lcov(
+        &self,
+        out: &mut impl Write,
+        code: &[Instruction],
+        debug: &DebugInfo,
+    ) -> std::io::Result<()> {
+        let mut lines: BTreeMap<u32, u64> = BTreeMap::new();
+        let mut branches = Vec::new();
+        for (addr, instr) in code.iter().enumerate() {
+            let Some(line) = debug.line(addr) else {
+                continue;
+            };
+            let hits = self.hits(addr);
+            let count = lines.entry(line).or_default();
+            *count = (*count).max(hits);
+            if instr.op == Opcode::JumpIf as u8 {
+                branches.push((line, addr, hits, self.branch(addr)));
+            }
+        }
+
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", debug.file)?;
+        for (line, addr, hits, counts) in &branches {
+            for (i, n) in counts.iter().enumerate() {
+                if *hits == 0 {
+                    writeln!(out, "BRDA:{line},{addr},{i},-")?;
+                } else {
+                    writeln!(out, "BRDA:{line},{addr},{i},{n}")?;
+                }
+            }
+        }
+        let taken = branches.iter().flat_map(|b| b.3).filter(|n| *n > 0).count();
+        writeln!(out, "BRF:{}", branches.len() * 2)?;
+        writeln!(out, "BRH:{taken}")?;
+        for (line, count) in &lines {
+            writeln!(out, "DA:{line},{count}")?;
+        }
+        writeln!(out, "LF:{}", lines.len())?;
+        writeln!(out, "LH:{}", lines.values().filter(|n| **n > 0).count())?;
+        writeln!(out, "end_of_record")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::debug::SourceLoc;
+
+    use super::*;
+
+    fn instr(op: Opcode) -> Instruction {
+        Instruction {
+            op: op as u8,
+            r: 0,
+            n: 0,
+            d: 0,
+        }
+    }
+
+    #[test]
+    fn lcov_lines_and_branches() {
+        let code = [
+            instr(Opcode::LoadL),
+            instr(Opcode::JumpIf),
+            instr(Opcode::JumpIf),
+            instr(Opcode::Halt),
+            instr(Opcode::Halt),
+        ];
+        let debug = DebugInfo {
+            file: String::from("a.tasm"),
+            locs: [1, 2, 3, 5, 0]
+                .into_iter()
+                .map(|line| SourceLoc { line, col: 1 })
+                .collect(),
+        };
+        let mut coverage = Coverage::default();
+        for addr in [0, 1, 3] {
+            coverage.record(addr);
+        }
+        coverage.record_branch(1, true);
+
+        let mut out = Vec::new();
+        coverage.write_lcov(&mut out, &code, &debug).unwrap();
+        assert_eq!(
+            "TN:\nSF:a.tasm\n\
+             BRDA:2,1,0,1\nBRDA:2,1,1,0\nBRDA:3,2,0,-\nBRDA:3,2,1,-\nBRF:4\nBRH:1\n\
+             DA:1,1\nDA:2,1\nDA:3,0\nDA:5,1\nLF:4\nLH:3\nend_of_record\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
@@ -0,0 +1,970 @@
+//! A server for the Debug Adapter Protocol, so that editors can debug programs
+//! assembled from `.tasm` source.
+//!
+//! The server reads requests from one stream and writes responses and events to
+//! another, each message framed with a `Content-Length` header. A `launch` request
+//! loads the bytecode file named by its `program` argument. Its `args` may turn on
+//! the same checks as the command line: `--strict`, `--sanitize-heap`,
+//! `--uninit MODE`, `--heap-strategy STRATEGY`, `--max-steps N`,
+//! `--max-stack WORDS`, `--max-heap WORDS` and `--max-output BYTES`, and
+//! `--input FILE` supplies the program's input. The program's output is sent to the
+//! editor as output events.
+//!
+//! Breakpoints are set on source lines through the program's debug information,
+//! and steps go a source line at a time unless the editor asks for instruction
+//! granularity. Each TAM frame is a stack frame, with scopes for its locals at
+//! `[lb+n]`, the globals at `[sb+n]`, the registers and the heap blocks.
+//! Expressions name a register, as in `st`, or a data word, as in `[lb+3]`,
+//! `[sb-1]` or `[0x10]`.
+//!
+//! A resumed program runs a slice of steps at a time, and requests are read
+//! between slices, so that a `pause` can stop a program that does not stop by
+//! itself.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use common::{container::Container, debug::DebugInfo, instruction::Register};
+use serde_json::{json, Value};
+
+use crate::{
+    debugger::parse_num,
+    errors::TAMError,
+    gdb::DebuggerInput,
+    io::BufferIo,
+    limits::Limits,
+    machine::{Frame, Status, TAM},
+    report::{label_addr, resolve_source},
+};
+
+/// Variables reference of the registers scope.
+const REGISTERS: u64 = 1;
+/// Variables reference of the globals scope.
+const GLOBALS: u64 = 2;
+/// Variables reference of the heap scope.
+const HEAP: u64 = 3;
+/// Variables reference of the locals scope of the innermost frame. Outer frames
+/// follow on from it.
+const LOCALS: u64 = 4;
+
+/// The largest request body that is read, in bytes.
+const MAX_CONTENT_LENGTH: usize = 1 << 20;
+/// The number of steps a running program takes between checks for requests.
+const SLICE: usize = 4096;
+
+/// How far a request to resume the program runs it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    StepIn,
+    Next,
+    StepOut,
+    Instruction,
+}
+
+/// Reason the program stopped after it was resumed.
+enum Stop {
+    Step,
+    Breakpoint,
+    Halted,
+    Fault(TAMError),
+}
+
+/// A resume in progress, with the call depth and source line it started from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Run {
+    resume: Resume,
+    depth: usize,
+    line: Option<u32>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Stopped,
+    Running(Run),
+    /// The program faulted with the given exit code and can only be inspected.
+    Faulted(i32),
+    Ended,
+}
+
+/// A launched program.
+struct Session {
+    tam: TAM,
+    io: BufferIo,
+    /// Number of bytes of output already sent to the editor.
+    sent: usize,
+    labels: HashMap<String, usize>,
+    debug: Option<DebugInfo>,
+    /// Path of the program's source file.
+    source: Option<PathBuf>,
+    breakpoints: BTreeSet<usize>,
+    stop_on_entry: bool,
+    state: State,
+}
+
+impl Session {
+    fn is_running(&self) -> bool {
+        matches!(self.state, State::Running(_))
+    }
+
+    fn line(&self, addr: usize) -> Option<u32> {
+        self.debug.as_ref().and_then(|d| d.line(addr))
+    }
+
+    /// Find the first instruction on `line`, or on the next line with any.
+    fn line_addr(&self, line: u32) -> Option<(usize, u32)> {
+        let debug = self.debug.as_ref()?;
+        (0..debug.locs.len())
+            .filter_map(|addr| Some((addr, debug.line(addr)?)))
+            .filter(|(_, l)| *l >= line)
+            .min_by_key(|(addr, l)| (*l, *addr))
+    }
+
+    /// Check if `path` names the program's source file.
+    fn is_source(&self, path: &str) -> bool {
+        self.source
+            .as_ref()
+            .is_some_and(|source| source.file_name() == Path::new(path).file_name())
+    }
+
+    /// Get the code address of each stack frame, innermost first, with its TAM
+    /// frame. The outermost has no TAM frame.
+    fn stack(&self) -> Vec<(usize, Option<Frame>)> {
+        let frames = self.tam.frames();
+        let mut pc = self.tam.register(Register::CP);
+        let mut stack = Vec::with_capacity(frames.len() + 1);
+        for frame in &frames {
+            stack.push((pc, Some(*frame)));
+            pc = frame.return_addr.saturating_sub(1);
+        }
+        stack.push((pc, None));
+        stack
+    }
+
+    /// Start resuming the program from where it stopped.
+    fn start(&self, resume: Resume) -> Run {
+        Run {
+            resume,
+            depth: self.tam.call_depth(),
+            line: self.line(self.tam.register(Register::CP)),
+        }
+    }
+
+    /// Run the program for at most `steps` steps of `run`, returning why it
+    /// stopped, or `None` if it has not.
+    fn run(&mut self, run: Run, steps: usize) -> Option<Stop> {
+        let Run {
+            resume,
+            depth,
+            line,
+        } = run;
+        let by_line = self.debug.is_some() && resume != Resume::Instruction;
+        for _ in 0..steps {
+            match self.tam.step() {
+                Ok(Status::Running) => (),
+                Ok(Status::Halted) => return Some(Stop::Halted),
+                Err(e) => return Some(Stop::Fault(e)),
+            }
+
+            let cp = self.tam.register(Register::CP);
+            let now = self.line(cp);
+            let new_line = !by_line || (now.is_some() && now != line);
+            let done = match resume {
+                Resume::Continue => false,
+                Resume::Instruction => true,
+                Resume::StepIn => {
+                    new_line || (by_line && self.tam.call_depth() > depth)
+                }
+                Resume::Next => {
+                    self.tam.call_depth() < depth
+                        || (self.tam.call_depth() == depth && new_line)
+                }
+                Resume::StepOut => self.tam.call_depth() < depth,
+            };
+            if done {
+                return Some(Stop::Step);
+            }
+            if self.breakpoints.contains(&cp) {
+                return Some(Stop::Breakpoint);
+            }
+        }
+        None
+    }
+
+    fn variables(&self, reference: u64) -> Vec<Value> {
+        let tam = &self.tam;
+        let word = |name: String, addr: usize| {
+            let value = tam.data()[addr].to_string();
+            json!({"name": name, "value": value, "variablesReference": 0})
+        };
+        let frames = tam.frames();
+        match reference {
+            REGISTERS => Register::ALL
+                .iter()
+                .map(|r| {
+                    let value = tam.register(*r).to_string();
+                    json!({"name": r.name(), "value": value, "variablesReference": 0})
+                })
+                .collect(),
+            GLOBALS => {
+                let sb = tam.register(Register::SB);
+                let end = frames.last().map_or(tam.register(Register::ST), |f| f.base);
+                (sb..end)
+                    .map(|a| word(format!("[sb+{}]", a - sb), a))
+                    .collect()
+            }
+            HEAP => tam
+                .heap()
+                .blocks()
+                .map(|block| {
+                    let words = &tam.data()[block.addr..block.addr + block.size];
+                    let (name, value) =
+                        (format!("{:04x}", block.addr), format!("{words:?}"));
+                    json!({"name": name, "value": value, "variablesReference": 0})
+                })
+                .collect(),
+            _ => {
+                let i = reference.saturating_sub(LOCALS) as usize;
+                let Some(frame) = frames.get(i).filter(|_| reference >= LOCALS) else {
+                    return Vec::new();
+                };
+                let end = match i {
+                    0 => tam.register(Register::ST),
+                    _ => frames[i - 1].base,
+                };
+                (frame.base + 3..end)
+                    .map(|a| word(format!("[lb+{}]", a - frame.base), a))
+                    .collect()
+            }
+        }
+    }
+
+    /// Evaluate a register name or a data word in brackets.
+    fn evaluate(&self, expr: &str) -> Option<String> {
+        let expr = expr.trim();
+        let Some(addr) = expr.strip_prefix('[').and_then(|e| e.strip_suffix(']'))
+        else {
+            return register(expr).map(|r| self.tam.register(r).to_string());
+        };
+
+        let addr = addr.trim();
+        let addr = match addr.find(['+', '-']) {
+            _ if parse_num(addr).is_some() => parse_num(addr)?,
+            Some(i) => {
+                let offset = isize::try_from(parse_num(addr[i + 1..].trim())?).ok()?;
+                let offset = if addr.as_bytes()[i] == b'-' {
+                    offset.checked_neg()?
+                } else {
+                    offset
+                };
+                let base = self.tam.register(register(addr[..i].trim())?);
+                base.checked_add_signed(offset)?
+            }
+            None => self.tam.register(register(addr)?),
+        };
+        self.tam.data().get(addr).map(|val| val.to_string())
+    }
+}
+
+/// Find a register by its assembler name.
+fn register(name: &str) -> Option<Register> {
+    let name = name.to_lowercase();
+    Register::ALL.into_iter().find(|r| r.name() == name)
+}
+
+/// Apply the checks and limits in a launch request's `args` to `tam`, returning
+/// the program's input.
+fn configure(tam: &mut TAM, args: &[String]) -> Result<Vec<u8>, String> {
+    fn parse<T: FromStr>(s: &str) -> Result<T, String> {
+        s.parse().map_err(|_| format!("`{s}` is not a number"))
+    }
+
+    let mut input = Vec::new();
+    let mut limits = Limits::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--strict" => tam.set_strict(true),
+            "--sanitize-heap" => tam.set_sanitize(true),
+            "--uninit" => tam.set_uninit_mode(value()?.parse()?),
+            "--heap-strategy" => tam.set_heap_strategy(value()?.parse()?),
+            "--max-steps" => limits.max_steps = Some(parse(value()?)?),
+            "--max-stack" => limits.max_stack = Some(parse(value()?)?),
+            "--max-heap" => limits.max_heap = Some(parse(value()?)?),
+            "--max-output" => limits.max_output = Some(parse(value()?)?),
+            "-i" | "--input" => {
+                let path = value()?;
+                input = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+            }
+            _ => return Err(format!("unknown argument `{arg}`")),
+        }
+    }
+    tam.set_limits(limits);
+    Ok(input)
+}
+
+/// A Debug Adapter Protocol server that writes its messages to `W`.
+pub struct DapServer<W> {
+    out: W,
+    seq: u64,
+    session: Option<Session>,
+}
+
+impl<W: Write> DapServer<W> {
+    /// Construct a server that writes responses and events to `out`.
+    pub fn new(out: W) -> DapServer<W> {
+        DapServer {
+            out,
+            seq: 0,
+            session: None,
+        }
+    }
+
+    /// Serve requests read from `input` until the editor disconnects or closes the
+    /// stream, running the program while there are none.
+    pub fn serve(&mut self, input: &mut impl DebuggerInput) -> std::io::Result<()> {
+        loop {
+            if self.is_running() && !input.ready()? {
+                self.run_slice()?;
+                continue;
+            }
+
+            let mut len = None;
+            loop {
+                let mut line = String::new();
+                if input.read_line(&mut line)? == 0 {
+                    return Ok(());
+                }
+                let line = line.trim();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("Content-Length") {
+                        len = value.trim().parse::<usize>().ok();
+                    }
+                }
+            }
+            let Some(len) = len else {
+                continue;
+            };
+            if len > MAX_CONTENT_LENGTH {
+                std::io::copy(&mut input.take(len as u64), &mut std::io::sink())?;
+                let text = format!("request of {len} bytes is too large\n");
+                self.event("output", json!({"category": "stderr", "output": text}))?;
+                continue;
+            }
+
+            let mut body = vec![0; len];
+            input.read_exact(&mut body)?;
+            let request = match serde_json::from_slice(&body) {
+                Ok(request) => request,
+                Err(e) => {
+                    let text = format!("malformed request: {e}\n");
+                    self.event(
+                        "output",
+                        json!({"category": "stderr", "output": text}),
+                    )?;
+                    continue;
+                }
+            };
+            if !self.handle(&request)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handle a single request, writing its response and any events that follow.
+    /// A request to resume the program only starts it, and
+    /// [`DapServer::run_slice`] runs it.
+    ///
+    /// Returns `false` if the editor has asked to disconnect.
+    pub fn handle(&mut self, request: &Value) -> std::io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsEvaluateForHovers": true,
+                "supportsSteppingGranularity": true,
+            })),
+            "pause" => {
+                let paused = match &mut self.session {
+                    Some(session) if session.is_running() => {
+                        session.state = State::Stopped;
+                        true
+                    }
+                    _ => false,
+                };
+                self.respond(request, Ok(json!({})))?;
+                if paused {
+                    self.send_output()?;
+                    self.stopped("pause", None)?;
+                }
+                return Ok(true);
+            }
+            "launch" => {
+                let result = self.launch(args);
+                let launched = result.is_ok();
+                self.respond(request, result)?;
+                if launched {
+                    self.event("initialized", json!({}))?;
+                }
+                return Ok(true);
+            }
+            "configurationDone" => {
+                let stop_on_entry = self.session().map(|s| s.stop_on_entry);
+                self.respond(request, stop_on_entry.clone().map(|_| json!({})))?;
+                match stop_on_entry {
+                    Ok(true) => self.stopped("entry", None)?,
+                    Ok(false) => self.resume(Resume::Continue)?,
+                    Err(_) => (),
+                }
+                return Ok(true);
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let launched = self.session().map(|_| json!({}));
+                self.respond(request, launched)?;
+                let resume = match command {
+                    _ if args["granularity"] == "instruction"
+                        && command != "continue" =>
+                    {
+                        Resume::Instruction
+                    }
+                    "continue" => Resume::Continue,
+                    "next" => Resume::Next,
+                    "stepIn" => Resume::StepIn,
+                    _ => Resume::StepOut,
+                };
+                self.resume(resume)?;
+                return Ok(true);
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                if command == "terminate" {
+                    self.event("terminated", json!({}))?;
+                }
+                return Ok(command != "disconnect");
+            }
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "threads" => Ok(json!({"threads": [{"id": 1, "name": "main"}]})),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => self.scopes(args),
+            "variables" => self.session().map(|session| {
+                let reference = args["variablesReference"].as_u64().unwrap_or(0);
+                json!({"variables": session.variables(reference)})
+            }),
+            "evaluate" => self.session().and_then(|session| {
+                let expr = args["expression"].as_str().unwrap_or_default();
+                match session.evaluate(expr) {
+                    Some(result) => {
+                        Ok(json!({"result": result, "variablesReference": 0}))
+                    }
+                    None => Err(format!("cannot evaluate `{expr}`")),
+                }
+            }),
+            _ => Err(format!("unsupported request `{command}`")),
+        };
+        self.respond(request, result)?;
+        Ok(true)
+    }
+
+    fn session(&self) -> Result<&Session, String> {
+        self.session
+            .as_ref()
+            .ok_or_else(|| String::from("no program has been launched"))
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or("launch needs the path of a `program`")?;
+        let bytes = std::fs::read(program).map_err(|e| format!("{program}: {e}"))?;
+        let container =
+            Container::from_bytes(&bytes).map_err(|e| format!("{program}: {e}"))?;
+        let launch_args: Vec<String> = args["args"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|arg| arg.as_str().map(String::from))
+            .collect();
+
+        let mut tam = TAM::new(false);
+        tam.load_container(&container)
+            .map_err(|e| format!("{program}: {e}"))?;
+        let io = BufferIo::new(configure(&mut tam, &launch_args)?);
+        tam.set_io(Box::new(io.clone()));
+        tam.reset();
+
+        let debug = container.debug_info();
+        self.session = Some(Session {
+            tam,
+            io,
+            sent: 0,
+            labels: container.code_labels(),
+            source: debug.as_ref().map(|d| resolve_source(program, &d.file)),
+            debug,
+            breakpoints: BTreeSet::new(),
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+            state: State::Stopped,
+        });
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let session = self
+            .session
+            .as_mut()
+            .ok_or("no program has been launched")?;
+        let path = args["source"]["path"].as_str().unwrap_or_default();
+        let lines = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|bp| bp["line"].as_u64());
+
+        let ours = session.is_source(path);
+        if ours {
+            session.breakpoints.clear();
+        }
+        let breakpoints: Vec<Value> = lines
+            .map(
+                |line| match session.line_addr(line as u32).filter(|_| ours) {
+                    Some((addr, line)) => {
+                        session.breakpoints.insert(addr);
+                        json!({"verified": true, "line": line})
+                    }
+                    None => json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "no code at or after this line",
+                    }),
+                },
+            )
+            .collect();
+        Ok(json!({"breakpoints": breakpoints}))
+    }
+
+    fn stack_trace(&self) -> Result<Value, String> {
+        let session = self.session()?;
+        let frames: Vec<Value> = session
+            .stack()
+            .iter()
+            .enumerate()
+            .map(|(i, (pc, _))| {
+                let name =
+                    label_addr(&session.labels, *pc).unwrap_or(format!("{pc:04x}"));
+                let mut frame = json!({
+                    "id": i,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("{pc:04x}"),
+                });
+                let loc = session.debug.as_ref().and_then(|d| d.loc(*pc));
+                if let (Some(loc), Some(source)) = (loc, &session.source) {
+                    frame["line"] = json!(loc.line);
+                    frame["column"] = json!(loc.col);
+                    frame["source"] = json!({
+                        "name": source.file_name().map(|n| n.to_string_lossy()),
+                        "path": source.to_string_lossy(),
+                    });
+                }
+                frame
+            })
+            .collect();
+        Ok(json!({"stackFrames": frames, "totalFrames": frames.len()}))
+    }
+
+    fn scopes(&self, args: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let frame = args["frameId"].as_u64().unwrap_or(0);
+        let scope = |name: &str, reference: u64| {
+            json!({
+                "name": name,
+                "variablesReference": reference,
+                "expensive": false,
+            })
+        };
+        let mut scopes = Vec::new();
+        if (frame as usize) < session.tam.call_depth() {
+            scopes.push(scope("Locals", LOCALS + frame));
+        }
+        scopes.push(scope("Globals", GLOBALS));
+        scopes.push(scope("Registers", REGISTERS));
+        scopes.push(scope("Heap", HEAP));
+        Ok(json!({"scopes": scopes}))
+    }
+
+    /// Check whether the program is running, in which case
+    /// [`DapServer::run_slice`] runs it until it stops.
+    pub fn is_running(&self) -> bool {
+        self.session.as_ref().is_some_and(Session::is_running)
+    }
+
+    /// Start resuming the program, or report that it has ended if it faulted.
+    fn resume(&mut self, resume: Resume) -> std::io::Result<()> {
+        let Some(session) = &mut self.session else {
+            return Ok(());
+        };
+        match session.state {
+            State::Ended | State::Running(_) => Ok(()),
+            State::Faulted(code) => {
+                session.state = State::Ended;
+                self.exited(code)
+            }
+            State::Stopped => {
+                session.state = State::Running(session.start(resume));
+                Ok(())
+            }
+        }
+    }
+
+    /// Run the program for a slice of steps, sending its output, and report why it
+    /// stopped if it did.
+    pub fn run_slice(&mut self) -> std::io::Result<()> {
+        let Some(session) = &mut self.session else {
+            return Ok(());
+        };
+        let State::Running(run) = session.state else {
+            return Ok(());
+        };
+        let Some(stop) = session.run(run, SLICE) else {
+            return self.send_output();
+        };
+        session.state = State::Stopped;
+        if let Stop::Halted = stop {
+            session.state = State::Ended;
+        }
+        if let Stop::Fault(e) = &stop {
+            session.state = State::Faulted(e.exit_code());
+        }
+        self.send_output()?;
+
+        match stop {
+            Stop::Step => self.stopped("step", None),
+            Stop::Breakpoint => self.stopped("breakpoint", None),
+            Stop::Halted => self.exited(0),
+            Stop::Fault(e) => {
+                let text = format!("{e}\n");
+                self.event("output", json!({"category": "stderr", "output": text}))?;
+                self.stopped("exception", Some(e.to_string()))
+            }
+        }
+    }
+
+    /// Send anything the program has written since the last output event.
+    fn send_output(&mut self) -> std::io::Result<()> {
+        let Some(session) = &mut self.session else {
+            return Ok(());
+        };
+        let output = session.io.output();
+        if output.len() <= session.sent {
+            return Ok(());
+        }
+        let text = output[session.sent..].to_string();
+        session.sent = output.len();
+        self.event("output", json!({"category": "stdout", "output": text}))
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> std::io::Result<()> {
+        let mut body =
+            json!({"reason": reason, "threadId": 1, "allThreadsStopped": true});
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body)
+    }
+
+    fn exited(&mut self, code: i32) -> std::io::Result<()> {
+        self.event("exited", json!({"exitCode": code}))?;
+        self.event("terminated", json!({}))
+    }
+
+    fn respond(
+        &mut self,
+        request: &Value,
+        result: Result<Value, String>,
+    ) -> std::io::Result<()> {
+        self.seq += 1;
+        let mut response = json!({
+            "seq": self.seq,
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(&response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> std::io::Result<()> {
+        self.seq += 1;
+        let message =
+            json!({"seq": self.seq, "type": "event", "event": event, "body": body});
+        self.send(&message)
+    }
+
+    fn send(&mut self, message: &Value) -> std::io::Result<()> {
+        let body = message.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{
+        container::{Symbol, SymbolKind},
+        debug::SourceLoc,
+        instruction::Instruction,
+    };
+
+    use super::*;
+
+    fn instr(op: u8, r: u8, n: u8, d: i16) -> Instruction {
+        Instruction { op, r, n, d }
+    }
+
+    /// Write a program that prints double 6 with a routine to a temporary file,
+    /// returning its path.
+    fn program(name: &str) -> PathBuf {
+        let code = vec![
+            instr(10, 0, 0, 1), // push    1
+            instr(3, 0, 0, 6),  // loadl   6
+            instr(6, 0, 4, 7),  // call    (sb) double
+            instr(4, 4, 1, 0),  // store   1, [sb+0]
+            instr(0, 4, 1, 0),  // load    1, [sb+0]
+            instr(6, 2, 0, 26), // call    putint
+            instr(15, 0, 0, 0), // halt
+            instr(0, 8, 1, -1), // double: load 1, [lb-1]
+            instr(0, 8, 1, -1), // load    1, [lb-1]
+            instr(6, 2, 0, 8),  // call    add
+            instr(8, 0, 1, 1),  // return  1, 1
+        ];
+        let lines = [1, 2, 3, 4, 5, 6, 7, 10, 11, 12, 13];
+        let container = Container {
+            code,
+            symbols: vec![Symbol {
+                name: String::from("double"),
+                kind: SymbolKind::Code,
+                addr: 7,
+            }],
+            debug: DebugInfo {
+                file: format!("{name}.tasm"),
+                locs: lines.map(|line| SourceLoc { line, col: 9 }).to_vec(),
+            }
+            .to_bytes(),
+            ..Container::default()
+        };
+        let path = std::env::temp_dir().join(format!("{name}.tam"));
+        std::fs::write(&path, container.to_bytes().unwrap()).unwrap();
+        path
+    }
+
+    struct Client {
+        server: DapServer<Vec<u8>>,
+        seq: u64,
+    }
+
+    impl Client {
+        /// Send a request, returning the messages written in reply.
+        fn request(&mut self, command: &str, arguments: Value) -> Vec<Value> {
+            self.seq += 1;
+            let request = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            self.server.out.clear();
+            self.server.handle(&request).unwrap();
+            while self.server.is_running() {
+                self.server.run_slice().unwrap();
+            }
+
+            let out = String::from_utf8(self.server.out.clone()).unwrap();
+            out.split("Content-Length: ")
+                .skip(1)
+                .map(|msg| serde_json::from_str(msg.split_once("\r\n\r\n").unwrap().1))
+                .collect::<Result<_, _>>()
+                .unwrap()
+        }
+    }
+
+    fn launch(name: &str, stop_on_entry: bool) -> (Client, PathBuf) {
+        let path = program(name);
+        let mut client = Client {
+            server: DapServer::new(Vec::new()),
+            seq: 0,
+        };
+        client.request("initialize", json!({}));
+        let program = path.to_string_lossy();
+        let msgs = client.request(
+            "launch",
+            json!({"program": program, "stopOnEntry": stop_on_entry}),
+        );
+        assert_eq!(json!(true), msgs[0]["success"]);
+        assert_eq!("initialized", msgs[1]["event"]);
+        (client, path)
+    }
+
+    #[test]
+    fn debug_session() {
+        let (mut client, path) = launch("tam_dap_session", false);
+        let source = path.with_extension("tasm").to_string_lossy().into_owned();
+        let msgs = client.request(
+            "setBreakpoints",
+            json!({"source": {"path": source}, "breakpoints": [{"line": 8}]}),
+        );
+        assert_eq!(
+            json!([{"verified": true, "line": 10}]),
+            msgs[0]["body"]["breakpoints"]
+        );
+
+        let msgs = client.request("configurationDone", json!({}));
+        assert_eq!("breakpoint", msgs[1]["body"]["reason"]);
+
+        let msgs = client.request("stackTrace", json!({"threadId": 1}));
+        let frames = &msgs[0]["body"]["stackFrames"];
+        assert_eq!(
+            (json!("double"), json!(10), json!("0002"), json!(3)),
+            (
+                frames[0]["name"].clone(),
+                frames[0]["line"].clone(),
+                frames[1]["name"].clone(),
+                frames[1]["line"].clone()
+            )
+        );
+        assert_eq!(json!(source), frames[0]["source"]["path"]);
+
+        let msgs = client.request("scopes", json!({"frameId": 0}));
+        let scopes = &msgs[0]["body"]["scopes"];
+        assert_eq!(json!(LOCALS), scopes[0]["variablesReference"]);
+        let msgs = client.request("variables", json!({"variablesReference": GLOBALS}));
+        let globals = &msgs[0]["body"]["variables"];
+        assert_eq!(
+            (json!("[sb+1]"), json!("6")),
+            (globals[1]["name"].clone(), globals[1]["value"].clone())
+        );
+        let msgs = client.request("evaluate", json!({"expression": "[lb-1]"}));
+        assert_eq!("6", msgs[0]["body"]["result"]);
+        let msgs = client.request("evaluate", json!({"expression": "[lb+x]"}));
+        assert_eq!(json!(false), msgs[0]["success"]);
+
+        let msgs = client.request("next", json!({"threadId": 1}));
+        assert_eq!("step", msgs[1]["body"]["reason"]);
+        let msgs = client.request("stackTrace", json!({"threadId": 1}));
+        assert_eq!(11, msgs[0]["body"]["stackFrames"][0]["line"]);
+
+        client.request("stepOut", json!({"threadId": 1}));
+        let msgs = client.request("stackTrace", json!({"threadId": 1}));
+        assert_eq!(4, msgs[0]["body"]["stackFrames"][0]["line"]);
+
+        let msgs = client.request("continue", json!({"threadId": 1}));
+        let events: Vec<&Value> = msgs[1..].iter().map(|msg| &msg["event"]).collect();
+        assert_eq!(vec!["output", "exited", "terminated"], events);
+        assert_eq!("12", msgs[1]["body"]["output"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stop_on_entry_and_registers() {
+        let (mut client, path) = launch("tam_dap_entry", true);
+        let msgs = client.request("configurationDone", json!({}));
+        assert_eq!("entry", msgs[1]["body"]["reason"]);
+        let msgs = client.request(
+            "stepIn",
+            json!({"threadId": 1, "granularity": "instruction"}),
+        );
+        assert_eq!("step", msgs[1]["body"]["reason"]);
+
+        let msgs =
+            client.request("variables", json!({"variablesReference": REGISTERS}));
+        let registers = msgs[0]["body"]["variables"].as_array().unwrap();
+        assert_eq!(16, registers.len());
+        assert_eq!(
+            json!({"name": "cp", "value": "1", "variablesReference": 0}),
+            registers[15]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn pause_running_program() {
+        let container = Container {
+            code: vec![instr(12, 0, 0, 0)], // loop: jump loop
+            ..Container::default()
+        };
+        let path = std::env::temp_dir().join("tam_dap_pause.tam");
+        std::fs::write(&path, container.to_bytes().unwrap()).unwrap();
+        let mut server = DapServer::new(Vec::new());
+        let launch = json!({
+            "seq": 1,
+            "command": "launch",
+            "arguments": {"program": path.to_string_lossy()},
+        });
+        server.handle(&launch).unwrap();
+        server
+            .handle(&json!({"seq": 2, "command": "configurationDone"}))
+            .unwrap();
+        server.run_slice().unwrap();
+        assert!(server.is_running());
+
+        server.out.clear();
+        server
+            .handle(&json!({"seq": 3, "command": "pause"}))
+            .unwrap();
+        assert!(!server.is_running());
+        let out = String::from_utf8(server.out).unwrap();
+        assert!(out.contains(r#""reason":"pause""#));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn evaluate_out_of_range() {
+        let (mut client, path) = launch("tam_dap_evaluate", true);
+        client.request("configurationDone", json!({}));
+        for expr in ["[lb-0x8000000000000000]", "[lb+0x8000000000000000]"] {
+            let msgs = client.request("evaluate", json!({"expression": expr}));
+            assert_eq!(json!(false), msgs[0]["success"]);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn serve_framed_requests() {
+        let body = r#"{"seq":1,"type":"request","command":"threads"}"#;
+        let input = format!("Content-Length: {}\r\n\r\n{body}", body.len());
+        let mut server = DapServer::new(Vec::new());
+        server.serve(&mut input.as_bytes()).unwrap();
+        let out = String::from_utf8(server.out).unwrap();
+        assert!(out.starts_with("Content-Length: "));
+        assert!(out.contains(r#""threads":[{"id":1,"name":"main"}]"#));
+    }
+
+    #[test]
+    fn serve_oversized_request() {
+        let body = r#"{"seq":1,"type":"request","command":"threads"}"#;
+        let input = format!(
+            "Content-Length: {}\r\n\r\n{}Content-Length: {}\r\n\r\n{body}",
+            MAX_CONTENT_LENGTH + 1,
+            " ".repeat(MAX_CONTENT_LENGTH + 1),
+            body.len()
+        );
+        let mut server = DapServer::new(Vec::new());
+        server.serve(&mut input.as_bytes()).unwrap();
+        let out = String::from_utf8(server.out).unwrap();
+        assert!(out.contains("is too large"));
+        assert!(out.contains(r#""threads":[{"id":1,"name":"main"}]"#));
+    }
+}
@@ -0,0 +1,616 @@
+//! Interactive debugger for programs running on a [`TAM`].
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::Write,
+};
+
+use common::instruction::{Instruction, Register};
+
+use crate::{
+    errors::TAMError,
+    machine::{Status, TAM},
+    report::{describe_addr, format_registers},
+};
+
+const HELP: &str = "\
+commands:
+  run                    restart the program from the beginning
+  step | s               execute one instruction, stepping into calls
+  next | n               execute one instruction, stepping over calls
+  finish | fin           run until the current routine returns
+  continue | c           run until a breakpoint, watchpoint or halt
+  reverse-step | rs [N]  undo the last N instructions, by default 1
+  reverse-continue | rc  run backwards until a breakpoint or watchpoint
+  last-write | lw ADDR   find the last instruction that wrote to ADDR
+  break | b LOC          set a breakpoint at a code address or label
+  delete | d LOC         remove a breakpoint
+  watch | w ADDR         stop when the data word at ADDR changes
+  unwatch ADDR           remove a watchpoint
+  info                   list breakpoints and watchpoints
+  registers | regs       print the register file
+  stack [FROM [TO]]      print the stack, or the given range of it
+  heap                   print the heap
+  x ADDR [COUNT]         print COUNT data words starting at ADDR
+  backtrace | bt         print the active frames
+  list | l [COUNT]       print the next COUNT instructions
+  help | h               print this message
+  quit | q               leave the debugger";
+
+/// Reason execution stopped after a command that resumes the program.
+enum Stop {
+    Step,
+    Breakpoint(usize),
+    Watchpoint(usize, i16, i16),
+    Halted,
+    Fault(TAMError),
+    /// Running backwards reached the oldest step in the history.
+    Start,
+}
+
+/// Debugger wrapping a [`TAM`] with breakpoints and watchpoints.
+pub struct Debugger {
+    tam: TAM,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeMap<usize, i16>,
+    symbols: HashMap<String, usize>,
+    running: bool,
+}
+
+impl Debugger {
+    /// Construct a debugger for the program loaded into `tam`.
+    ///
+    /// The machine is reset and stopped before its first instruction.
+    pub fn new(mut tam: TAM) -> Debugger {
+        tam.reset();
+        Debugger {
+            tam,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            symbols: HashMap::new(),
+            running: true,
+        }
+    }
+
+    /// Provide code labels so they can be used as breakpoint locations and shown in
+    /// backtraces.
+    pub fn set_symbols(&mut self, symbols: HashMap<String, usize>) {
+        self.symbols = symbols;
+    }
+
+    /// Get the machine being debugged.
+    pub fn tam(&self) -> &TAM {
+        &self.tam
+    }
+
+    /// Read commands with `read_line` until input is exhausted or the user quits.
+    ///
+    /// Commands are read a line at a time through a callback rather than a reader so
+    /// that the program being debugged can share the same input stream.
+    pub fn repl(
+        &mut self,
+        mut read_line: impl FnMut(&mut String) -> std::io::Result<usize>,
+        out: &mut impl Write,
+    ) -> std::io::Result<()> {
+        self.show_location(out)?;
+        loop {
+            write!(out, "(tam) ")?;
+            out.flush()?;
+
+            let mut line = String::new();
+            if read_line(&mut line)? == 0 {
+                writeln!(out)?;
+                return Ok(());
+            }
+            if !self.command(&line, out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Execute a single debugger command, writing any output to `out`.
+    ///
+    /// Returns `false` if the command asks to leave the debugger.
+    pub fn command(
+        &mut self,
+        line: &str,
+        out: &mut impl Write,
+    ) -> std::io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((cmd, args)) = words.split_first() else {
+            return Ok(true);
+        };
+
+        match (*cmd, args) {
+            ("quit" | "q", []) => return Ok(false),
+            ("help" | "h", []) => writeln!(out, "{HELP}")?,
+            ("run" | "r", []) => {
+                self.tam.reset();
+                self.running = true;
+                self.update_watchpoints();
+                self.show_location(out)?;
+            }
+            ("step" | "s", []) => self.resume(out, |_| true)?,
+            ("next" | "n", []) => {
+                let depth = self.tam.call_depth();
+                self.resume(out, move |tam| tam.call_depth() <= depth)?;
+            }
+            ("finish" | "fin", []) => {
+                let depth = self.tam.call_depth();
+                if depth == 0 {
+                    writeln!(out, "not inside a routine")?;
+                } else {
+                    self.resume(out, move |tam| tam.call_depth() < depth)?;
+                }
+            }
+            ("continue" | "c", []) => self.resume(out, |_| false)?,
+            ("reverse-step" | "rs", _) if args.len() <= 1 => {
+                match args.first().map_or(Some(1), |n| parse_num(n)) {
+                    Some(n) => self.reverse(out, Some(n as u64))?,
+                    None => writeln!(out, "usage: reverse-step [N]")?,
+                }
+            }
+            ("reverse-continue" | "rc", []) => self.reverse(out, None)?,
+            ("last-write" | "lw", [addr]) => match parse_num(addr) {
+                Some(addr) if addr < self.tam.data().len() => {
+                    self.show_last_write(out, addr)?
+                }
+                _ => writeln!(out, "invalid address: {addr}")?,
+            },
+            ("break" | "b", [loc]) => match self.parse_loc(loc) {
+                Some(addr) => {
+                    self.breakpoints.insert(addr);
+                    writeln!(out, "breakpoint at {}", self.describe(addr))?;
+                }
+                None => writeln!(out, "unknown location: {loc}")?,
+            },
+            ("delete" | "d", [loc]) => match self.parse_loc(loc) {
+                Some(addr) if self.breakpoints.remove(&addr) => {
+                    writeln!(out, "deleted breakpoint at {}", self.describe(addr))?
+                }
+                _ => writeln!(out, "no breakpoint at {loc}")?,
+            },
+            ("watch" | "w", [addr]) => match parse_num(addr) {
+                Some(addr) if addr < self.tam.data().len() => {
+                    self.watchpoints.insert(addr, self.tam.data()[addr]);
+                    writeln!(out, "watching {addr:04x}")?;
+                }
+                _ => writeln!(out, "invalid address: {addr}")?,
+            },
+            ("unwatch", [addr]) => match parse_num(addr) {
+                Some(addr) if self.watchpoints.remove(&addr).is_some() => {
+                    writeln!(out, "no longer watching {addr:04x}")?
+                }
+                _ => writeln!(out, "no watchpoint at {addr}")?,
+            },
+            ("info", []) => self.show_info(out)?,
+            ("registers" | "regs", []) => self.show_registers(out)?,
+            ("stack", _) => {
+                let (sb, st) = (
+                    self.tam.register(Register::SB),
+                    self.tam.register(Register::ST),
+                );
+                match args
+                    .iter()
+                    .map(|a| parse_num(a))
+                    .collect::<Option<Vec<_>>>()
+                {
+                    Some(range) if range.len() <= 2 => {
+                        let from =
+                            range.first().map_or(sb, |off| sb.saturating_add(*off));
+                        let to = range.get(1).map_or(st, |off| {
+                            sb.saturating_add(*off).saturating_add(1)
+                        });
+                        self.show_data(out, from, to.min(st))?;
+                    }
+                    _ => writeln!(out, "usage: stack [FROM [TO]]")?,
+                }
+            }
+            ("heap", []) => {
+                let (ht, hb) = (
+                    self.tam.register(Register::HT),
+                    self.tam.register(Register::HB),
+                );
+                self.show_data(out, ht + 1, hb + 1)?;
+            }
+            ("x", [addr, count @ ..]) if count.len() <= 1 => {
+                let count = count.first().map_or(Some(1), |c| parse_num(c));
+                match (parse_num(addr), count) {
+                    (Some(addr), Some(count)) => {
+                        let end = addr.saturating_add(count).min(self.tam.data().len());
+                        self.show_data(out, addr, end)?;
+                    }
+                    _ => writeln!(out, "usage: x ADDR [COUNT]")?,
+                }
+            }
+            ("backtrace" | "bt", []) => self.show_backtrace(out)?,
+            ("list" | "l", _) if args.len() <= 1 => {
+                let count = args.first().map_or(Some(5), |c| parse_num(c));
+                match count {
+                    Some(count) => {
+                        self.show_code(out, self.tam.register(Register::CP), count)?
+                    }
+                    None => writeln!(out, "usage: list [COUNT]")?,
+                }
+            }
+            _ => writeln!(out, "unrecognised command: {}", line.trim())?,
+        }
+        Ok(true)
+    }
+
+    /// Execute instructions until `done` returns true after a step, or execution
+    /// is interrupted by a breakpoint, watchpoint, halt or fault.
+    fn resume(
+        &mut self,
+        out: &mut impl Write,
+        done: impl Fn(&TAM) -> bool,
+    ) -> std::io::Result<()> {
+        if !self.running {
+            return writeln!(
+                out,
+                "the program is not running; use `run` to restart it"
+            );
+        }
+
+        let stop = loop {
+            match self.tam.step() {
+                Err(e) => break Stop::Fault(e),
+                Ok(Status::Halted) => break Stop::Halted,
+                Ok(Status::Running) => (),
+            }
+            if let Some((addr, old, new)) = self.check_watchpoints() {
+                break Stop::Watchpoint(addr, old, new);
+            }
+            if done(&self.tam) {
+                break Stop::Step;
+            }
+            let cp = self.tam.register(Register::CP);
+            if self.breakpoints.contains(&cp) {
+                break Stop::Breakpoint(cp);
+            }
+        };
+        self.tam.flush_output()?;
+
+        match stop {
+            Stop::Step => (),
+            Stop::Breakpoint(addr) => {
+                writeln!(out, "breakpoint at {}", self.describe(addr))?
+            }
+            Stop::Watchpoint(addr, old, new) => {
+                writeln!(out, "watchpoint {addr:04x}: {old} -> {new}")?
+            }
+            Stop::Halted => {
+                self.running = false;
+                return writeln!(out, "program halted");
+            }
+            Stop::Fault(e) => {
+                self.running = false;
+                return writeln!(out, "program faulted: {e}");
+            }
+            Stop::Start => unreachable!(),
+        }
+        self.show_location(out)
+    }
+
+    /// Run backwards through the history, either `count` instructions or until a
+    /// breakpoint or watchpoint is reached.
+    fn reverse(
+        &mut self,
+        out: &mut impl Write,
+        count: Option<u64>,
+    ) -> std::io::Result<()> {
+        let Some(earliest) = self.tam.history().map(|h| h.earliest()) else {
+            return writeln!(out, "no history is being recorded");
+        };
+        let Some(earliest) = earliest else {
+            return writeln!(out, "no earlier steps in the history");
+        };
+
+        let stop = match count {
+            Some(n) => {
+                let steps = self.tam.steps().saturating_sub(n);
+                self.tam.rewind_to(steps.max(earliest));
+                self.update_watchpoints();
+                if steps < earliest {
+                    Stop::Start
+                } else {
+                    Stop::Step
+                }
+            }
+            None => loop {
+                if !self.tam.reverse_step() {
+                    break Stop::Start;
+                }
+                if let Some((addr, old, new)) = self.check_watchpoints() {
+                    break Stop::Watchpoint(addr, old, new);
+                }
+                let cp = self.tam.register(Register::CP);
+                if self.breakpoints.contains(&cp) {
+                    break Stop::Breakpoint(cp);
+                }
+            },
+        };
+        self.running = true;
+
+        match stop {
+            Stop::Breakpoint(addr) => {
+                writeln!(out, "breakpoint at {}", self.describe(addr))?
+            }
+            Stop::Watchpoint(addr, old, new) => {
+                writeln!(out, "watchpoint {addr:04x}: {old} -> {new}")?
+            }
+            Stop::Start => writeln!(
+                out,
+                "reached the start of the history at step {}",
+                self.tam.steps()
+            )?,
+            _ => (),
+        }
+        self.show_location(out)
+    }
+
+    fn show_last_write(
+        &self,
+        out: &mut impl Write,
+        addr: usize,
+    ) -> std::io::Result<()> {
+        if self.tam.history().is_none() {
+            return writeln!(out, "no history is being recorded");
+        }
+        match self.tam.last_write(addr) {
+            Some(write) => writeln!(
+                out,
+                "{addr:04x} last written at step {} by {}: {} -> {}",
+                write.step,
+                self.describe(write.loc),
+                write.old,
+                write.new
+            ),
+            None => writeln!(out, "no write to {addr:04x} in the history"),
+        }
+    }
+
+    fn check_watchpoints(&mut self) -> Option<(usize, i16, i16)> {
+        let data = self.tam.data();
+        for (addr, old) in self.watchpoints.iter_mut() {
+            let new = data[*addr];
+            if new != *old {
+                let changed = (*addr, *old, new);
+                *old = new;
+                return Some(changed);
+            }
+        }
+        None
+    }
+
+    fn update_watchpoints(&mut self) {
+        for (addr, val) in self.watchpoints.iter_mut() {
+            *val = self.tam.data()[*addr];
+        }
+    }
+
+    fn parse_loc(&self, loc: &str) -> Option<usize> {
+        self.symbols.get(loc).copied().or_else(|| parse_num(loc))
+    }
+
+    /// Describe a code address, relative to the nearest preceding label if there is
+    /// one.
+    fn describe(&self, addr: usize) -> String {
+        describe_addr(&self.symbols, addr)
+    }
+
+    fn instruction_at(&self, addr: usize) -> Option<Instruction> {
+        if addr < self.tam.register(Register::CT) {
+            Some(Instruction::from(self.tam.code()[addr]))
+        } else {
+            None
+        }
+    }
+
+    fn show_location(&self, out: &mut impl Write) -> std::io::Result<()> {
+        self.show_code(out, self.tam.register(Register::CP), 1)
+    }
+
+    fn show_code(
+        &self,
+        out: &mut impl Write,
+        from: usize,
+        count: usize,
+    ) -> std::io::Result<()> {
+        for addr in from..from.saturating_add(count) {
+            match self.instruction_at(addr) {
+                Some(instr) => writeln!(out, "{}: {instr}", self.describe(addr))?,
+                None => return writeln!(out, "{addr:04x}: <end of code>"),
+            }
+        }
+        Ok(())
+    }
+
+    fn show_data(
+        &self,
+        out: &mut impl Write,
+        from: usize,
+        to: usize,
+    ) -> std::io::Result<()> {
+        if from >= to {
+            return writeln!(out, "<empty>");
+        }
+        for addr in from..to {
+            writeln!(out, "{addr:04x}: {}", self.tam.data()[addr])?;
+        }
+        Ok(())
+    }
+
+    fn show_registers(&self, out: &mut impl Write) -> std::io::Result<()> {
+        write!(out, "{}", format_registers(&self.tam))
+    }
+
+    fn show_info(&self, out: &mut impl Write) -> std::io::Result<()> {
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            return writeln!(out, "no breakpoints or watchpoints");
+        }
+        for addr in &self.breakpoints {
+            writeln!(out, "breakpoint at {}", self.describe(*addr))?;
+        }
+        for (addr, val) in &self.watchpoints {
+            writeln!(out, "watchpoint at {addr:04x} = {val}")?;
+        }
+        Ok(())
+    }
+
+    /// Print each active frame, innermost first, by following the dynamic links.
+    fn show_backtrace(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let mut pc = self.tam.register(Register::CP);
+        let frames = self.tam.frames();
+        for (i, frame) in frames.iter().enumerate() {
+            writeln!(out, "#{i} {} lb={:04x}", self.describe(pc), frame.base)?;
+            pc = frame.return_addr.saturating_sub(1);
+        }
+        writeln!(out, "#{} {} (top level)", frames.len(), self.describe(pc))
+    }
+}
+
+/// Parse a number in decimal or as `0x`-prefixed hex.
+pub(crate) fn parse_num(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::*;
+
+    use super::*;
+    use crate::{history::HistoryConfig, io::BufferIo};
+
+    fn instr(op: u8, r: u8, n: u8, d: i16) -> Instruction {
+        Instruction { op, r, n, d }
+    }
+
+    /// A program that calls a routine which stores 7 in a global.
+    #[fixture]
+    fn debugger() -> Debugger {
+        let prog = [
+            instr(10, 0, 0, 1),
+            instr(6, 0, 4, 4),
+            instr(0, 4, 1, 0),
+            instr(15, 0, 0, 0),
+            instr(3, 0, 0, 7),
+            instr(4, 4, 1, 0),
+            instr(8, 0, 0, 0),
+        ];
+        let mut tam = TAM::new(false);
+        tam.set_io(Box::new(BufferIo::new("")));
+        tam.set_history(Some(HistoryConfig::default()));
+        tam.load_instructions(&prog).unwrap();
+        let mut dbg = Debugger::new(tam);
+        dbg.set_symbols(HashMap::from([("set".to_string(), 4)]));
+        dbg
+    }
+
+    fn run(dbg: &mut Debugger, cmds: &[&str]) -> String {
+        let mut out = Vec::new();
+        for cmd in cmds {
+            dbg.command(cmd, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[rstest]
+    #[case::x(&["x 1 18446744073709551615"], "fffe: 0\n")]
+    #[case::stack(&["step", "stack 0 18446744073709551615"], "0000: 0\n")]
+    #[case::list(&["list 18446744073709551615"], "0007: <end of code>\n")]
+    fn large_counts_stop_at_end_of_store(
+        mut debugger: Debugger,
+        #[case] cmds: &[&str],
+        #[case] end: &str,
+    ) {
+        assert!(run(&mut debugger, cmds).ends_with(end));
+    }
+
+    #[rstest]
+    fn step_into_call(mut debugger: Debugger) {
+        let out = run(&mut debugger, &["step", "step"]);
+        assert_eq!(4, debugger.tam().register(Register::CP));
+        assert!(out.ends_with("0004 <set>: loadl   7\n"));
+    }
+
+    #[rstest]
+    fn next_steps_over_call(mut debugger: Debugger) {
+        run(&mut debugger, &["step", "next"]);
+        assert_eq!(2, debugger.tam().register(Register::CP));
+        assert_eq!(0, debugger.tam().call_depth());
+    }
+
+    #[rstest]
+    fn finish_returns_to_caller(mut debugger: Debugger) {
+        let out = run(&mut debugger, &["finish", "step", "step", "finish"]);
+        assert!(out.starts_with("not inside a routine\n"));
+        assert_eq!(2, debugger.tam().register(Register::CP));
+    }
+
+    #[rstest]
+    fn breakpoint_by_label(mut debugger: Debugger) {
+        let out = run(&mut debugger, &["break set", "continue", "bt"]);
+        assert_eq!(4, debugger.tam().register(Register::CP));
+        assert!(out.contains("#0 0004 <set> lb=0001\n#1 0001 (top level)\n"));
+    }
+
+    #[rstest]
+    fn watchpoint_stops_on_write(mut debugger: Debugger) {
+        let out = run(&mut debugger, &["watch 0", "continue"]);
+        assert!(out.contains("watchpoint 0000: 0 -> 7\n"));
+        assert_eq!(6, debugger.tam().register(Register::CP));
+    }
+
+    #[rstest]
+    fn reverse_continue_to_breakpoint(mut debugger: Debugger) {
+        let out = run(&mut debugger, &["continue", "break set", "rc", "rs", "rc"]);
+        assert!(out.contains("breakpoint at 0004 <set>\n0004 <set>: loadl   7\n"));
+        assert!(out.ends_with(
+            "reached the start of the history at step 0\n0000: push    1\n"
+        ));
+        assert_eq!(0, debugger.tam().register(Register::CP));
+    }
+
+    #[rstest]
+    fn reverse_step_undoes_writes(mut debugger: Debugger) {
+        run(&mut debugger, &["watch 0", "continue", "reverse-step 2"]);
+        assert_eq!(4, debugger.tam().register(Register::CP));
+        assert_eq!(0, debugger.tam().data()[0]);
+        let out = run(&mut debugger, &["continue"]);
+        assert!(out.starts_with("watchpoint 0000: 0 -> 7\n"));
+    }
+
+    #[rstest]
+    fn last_write(mut debugger: Debugger) {
+        let out = run(&mut debugger, &["last-write 0", "continue", "lw 0"]);
+        assert_eq!(
+            "no write to 0000 in the history\n\
+             program halted\n\
+             0000 last written at step 4 by 0005 <set+1>: 0 -> 7\n",
+            out
+        );
+    }
+
+    #[rstest]
+    fn reverse_without_history() {
+        let mut tam = TAM::new(false);
+        tam.load_instructions(&[instr(15, 0, 0, 0)]).unwrap();
+        let mut debugger = Debugger::new(tam);
+        let out = run(&mut debugger, &["rs", "lw 0"]);
+        assert_eq!(
+            "no history is being recorded\nno history is being recorded\n",
+            out
+        );
+    }
+
+    #[rstest]
+    fn continue_to_halt(mut debugger: Debugger) {
+        let out = run(&mut debugger, &["continue", "step"]);
+        assert!(out.contains("program halted\n"));
+        assert!(out.ends_with("use `run` to restart it\n"));
+    }
+}
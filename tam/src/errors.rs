@@ -1,5 +1,10 @@
 use std::fmt::{Display, Error, Formatter};
 
+use crate::{heap::HeapViolation, limits::Limit};
+
+/// The status below the first exit status of a runtime error.
+pub const EXIT_BASE: i32 = 32;
+
 /// Represents different runtime errors.
 #[derive(Debug)]
 pub enum TAMError {
@@ -9,6 +14,24 @@ pub enum TAMError {
     StackOverflow(usize),
     /// Indicate there was an attempt to divide by zero.
     DivideByZero(usize),
+    /// Indicate an I/O primitive failed to read or write.
+    IOError(usize, std::io::Error),
+    /// Indicate an instruction with an opcode that does not name an instruction.
+    InvalidOpcode(usize, u8),
+    /// Indicate an attempt to pop more words than the stack holds.
+    StackUnderflow(usize),
+    /// Indicate there is no room on the heap for an allocation of the given size.
+    HeapExhausted(usize, usize),
+    /// Indicate control reached a code address past the end of the program.
+    CodeOutOfBounds(usize),
+    /// Indicate input that could not be read as the value a primitive expects.
+    BadInput(usize, String),
+    /// Indicate the program has exceeded one of the machine's resource limits.
+    LimitExceeded(usize, Limit),
+    /// Indicate the heap sanitizer found a misuse of the heap.
+    HeapViolation(usize, HeapViolation),
+    /// Indicate a read of a word that has not been initialized.
+    UninitializedRead(usize, usize),
 }
 
 impl Display for TAMError {
@@ -23,8 +46,85 @@ impl Display for TAMError {
             Self::DivideByZero(loc) => {
                 write!(f, "divide by zero attempted at loc {:04x}", loc)
             }
+            Self::IOError(loc, e) => write!(f, "I/O error at loc {:04x}: {}", loc, e),
+            Self::InvalidOpcode(loc, op) => {
+                write!(f, "invalid opcode {} at loc {:04x}", op, loc)
+            }
+            Self::StackUnderflow(loc) => {
+                write!(f, "stack underflow at loc {:04x}", loc)
+            }
+            Self::HeapExhausted(loc, n) => write!(
+                f,
+                "heap exhausted at loc {:04x}: no room for {} words",
+                loc, n
+            ),
+            Self::CodeOutOfBounds(loc) => {
+                write!(
+                    f,
+                    "no instruction at loc {:04x}, past the end of the code",
+                    loc
+                )
+            }
+            Self::BadInput(loc, msg) => {
+                write!(f, "bad input at loc {:04x}: {}", loc, msg)
+            }
+            Self::LimitExceeded(loc, limit) => {
+                write!(f, "{} exceeded at loc {:04x}", limit, loc)
+            }
+            Self::HeapViolation(loc, v) => {
+                write!(f, "heap error at loc {:04x}: {}", loc, v)
+            }
+            Self::UninitializedRead(loc, addr) => write!(
+                f,
+                "read of uninitialized word at loc {:04x}: {:04x} has not been stored to",
+                loc, addr
+            ),
+        }
+    }
+}
+
+impl TAMError {
+    /// Get the code address of the instruction that caused the error.
+    pub fn loc(&self) -> usize {
+        match self {
+            Self::SegmentationFault(loc, _)
+            | Self::StackOverflow(loc)
+            | Self::DivideByZero(loc)
+            | Self::IOError(loc, _)
+            | Self::InvalidOpcode(loc, _)
+            | Self::StackUnderflow(loc)
+            | Self::HeapExhausted(loc, _)
+            | Self::CodeOutOfBounds(loc)
+            | Self::BadInput(loc, _)
+            | Self::LimitExceeded(loc, _)
+            | Self::HeapViolation(loc, _)
+            | Self::UninitializedRead(loc, _) => *loc,
         }
     }
+
+    /// Get the status a process should exit with after the error.
+    ///
+    /// Each kind of error has its own status, from [`EXIT_BASE`]` + 1` up, which
+    /// stays clear of the statuses used for other failures: 1 when the bytecode
+    /// cannot be loaded, 2 for bad arguments, 101 for a panic and 126 and above
+    /// for the shell.
+    pub fn exit_code(&self) -> i32 {
+        EXIT_BASE
+            + match self {
+                Self::SegmentationFault(_, _) => 1,
+                Self::StackOverflow(_) => 2,
+                Self::DivideByZero(_) => 3,
+                Self::IOError(_, _) => 4,
+                Self::InvalidOpcode(_, _) => 5,
+                Self::StackUnderflow(_) => 6,
+                Self::HeapExhausted(_, _) => 7,
+                Self::CodeOutOfBounds(_) => 8,
+                Self::BadInput(_, _) => 9,
+                Self::LimitExceeded(_, _) => 10,
+                Self::HeapViolation(_, _) => 11,
+                Self::UninitializedRead(_, _) => 12,
+            }
+    }
 }
 
 pub type TAMResult<T> = Result<T, TAMError>;
@@ -0,0 +1,737 @@
+//! A stub for the GDB remote serial protocol, so that a debugger front end can
+//! control a [`TAM`] over a socket or a pipe.
+//!
+//! Every address the stub sends or accepts is a byte address. The code store and
+//! the data store are separate address spaces, told apart by where they are
+//! mapped: code word `n` is the 4 bytes from `4n`, and data word `n` is the 2
+//! bytes from [`DATA_BASE`]` + 2n`, both big-endian. A read or write cannot run
+//! from one space into the other, and the regions are described to the debugger
+//! by the memory map [`MEMORY_MAP`].
+//!
+//! The 16 registers of the machine are exposed as 32-bit big-endian byte
+//! addresses in the space they point into, in the order given by the target
+//! description [`TARGET_XML`], which marks CP as the program counter. The value of
+//! CP is therefore the address at which breakpoints are set.
+//!
+//! A continue runs until the program reaches a breakpoint, halts, faults or is
+//! interrupted by the debugger. A halt is reported as the program exiting with
+//! status 0, and a fault as a signal after which the program can be inspected
+//! but not resumed.
+
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::mpsc::{self, Receiver, TryRecvError},
+};
+
+use common::instruction::Register;
+
+use crate::{
+    errors::TAMError,
+    machine::{Status, MEM_SIZE, TAM},
+};
+
+/// Target description of the TAM register set.
+pub const TARGET_XML: &str = include_str!("../gdb/target.xml");
+
+/// Memory map of the code and data address spaces.
+pub const MEMORY_MAP: &str = include_str!("../gdb/memory-map.xml");
+
+/// Byte address at which the data store starts.
+pub const DATA_BASE: usize = 0x10_0000;
+
+/// The number of steps run between checks for an interrupt from the debugger.
+const POLL_INTERVAL: usize = 1024;
+
+/// Input from a debugger, which can be checked for an interrupt without blocking
+/// while the program runs.
+pub trait DebuggerInput: BufRead {
+    /// Check whether there is input waiting to be read.
+    fn ready(&mut self) -> std::io::Result<bool>;
+}
+
+impl DebuggerInput for &[u8] {
+    /// A slice can always be read without blocking, if only to find its end.
+    fn ready(&mut self) -> std::io::Result<bool> {
+        Ok(true)
+    }
+}
+
+impl DebuggerInput for BufReader<TcpStream> {
+    fn ready(&mut self) -> std::io::Result<bool> {
+        if !self.buffer().is_empty() {
+            return Ok(true);
+        }
+        let stream = self.get_ref();
+        stream.set_nonblocking(true)?;
+        let peeked = stream.peek(&mut [0]);
+        stream.set_nonblocking(false)?;
+        match peeked {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Input read by a background thread, for streams such as stdin that cannot be
+/// checked without blocking.
+pub struct ThreadedInput {
+    chunks: Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ThreadedInput {
+    /// Start reading `input` in the background.
+    pub fn new(mut input: impl Read + Send + 'static) -> ThreadedInput {
+        let (send, chunks) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok(n @ 1..) = input.read(&mut buf) {
+                if send.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        ThreadedInput {
+            chunks,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ThreadedInput {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for ThreadedInput {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            // A closed channel is the end of the input.
+            self.buf = self.chunks.recv().unwrap_or_default();
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.buf.len());
+    }
+}
+
+impl DebuggerInput for ThreadedInput {
+    fn ready(&mut self) -> std::io::Result<bool> {
+        if self.pos < self.buf.len() {
+            return Ok(true);
+        }
+        match self.chunks.try_recv() {
+            Ok(chunk) => {
+                self.buf = chunk;
+                self.pos = 0;
+                Ok(true)
+            }
+            Err(TryRecvError::Empty) => Ok(false),
+            Err(TryRecvError::Disconnected) => Ok(true),
+        }
+    }
+}
+
+/// Check whether register `r` holds an address in the code store.
+fn is_code_register(r: Register) -> bool {
+    matches!(
+        r,
+        Register::CB | Register::CT | Register::PB | Register::PT | Register::CP
+    )
+}
+
+/// Parse a register number in hex.
+fn parse_register(s: &str) -> Option<Register> {
+    let r = u8::from_str_radix(s, 16).ok()?;
+    Register::try_from(r).ok()
+}
+
+/// A GDB remote protocol server for a [`TAM`].
+pub struct GdbStub {
+    tam: TAM,
+    breakpoints: BTreeSet<usize>,
+    no_ack: bool,
+    /// Reply to a request to resume the program once it has ended.
+    exited: Option<String>,
+    /// Text for the debugger's console, to send before the next reply.
+    console: Vec<String>,
+    detached: bool,
+}
+
+impl GdbStub {
+    /// Construct a stub for the program loaded into `tam`.
+    ///
+    /// The machine is reset and stopped before its first instruction.
+    pub fn new(mut tam: TAM) -> GdbStub {
+        tam.reset();
+        GdbStub {
+            tam,
+            breakpoints: BTreeSet::new(),
+            no_ack: false,
+            exited: None,
+            console: Vec::new(),
+            detached: false,
+        }
+    }
+
+    /// Get the machine being debugged.
+    pub fn tam(&self) -> &TAM {
+        &self.tam
+    }
+
+    /// Serve packets read from `input`, writing replies to `output`, until the
+    /// debugger detaches, kills the program or closes the connection.
+    pub fn serve(
+        &mut self,
+        input: &mut impl DebuggerInput,
+        output: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let mut last = String::new();
+        loop {
+            let mut byte = [0];
+            if input.read(&mut byte)? == 0 {
+                return Ok(());
+            }
+            match byte[0] {
+                b'$' => (),
+                b'-' if !self.no_ack => {
+                    write_packet(output, &last)?;
+                    continue;
+                }
+                // Acknowledgements, and interrupts while the program is stopped.
+                _ => continue,
+            }
+
+            let mut body = Vec::new();
+            input.read_until(b'#', &mut body)?;
+            if body.pop() != Some(b'#') {
+                return Ok(());
+            }
+            let mut sum = [0; 2];
+            input.read_exact(&mut sum)?;
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+                == Some(checksum(&body));
+            if !self.no_ack {
+                output.write_all(if valid { b"+" } else { b"-" })?;
+                output.flush()?;
+            }
+            if !valid {
+                continue;
+            }
+
+            // While the program runs, the debugger may only send an interrupt.
+            let mut interrupted = || -> bool {
+                let mut byte = [0];
+                while input.ready().unwrap_or(true) {
+                    match input.read(&mut byte) {
+                        Ok(1) if byte[0] == 0x03 => return true,
+                        Ok(1) => (),
+                        _ => return true,
+                    }
+                }
+                false
+            };
+            let reply =
+                self.dispatch(&String::from_utf8_lossy(&body), &mut interrupted);
+            for text in self.console.drain(..) {
+                write_packet(output, &format!("O{}", hex(text.as_bytes())))?;
+            }
+            if let Some(reply) = reply {
+                write_packet(output, &reply)?;
+                last = reply;
+            }
+            if self.detached {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handle the body of a single packet, returning the body of the reply, or
+    /// `None` if the packet has no reply.
+    ///
+    /// Packets the stub does not support get an empty reply, as the protocol
+    /// requires.
+    pub fn handle(&mut self, packet: &str) -> Option<String> {
+        self.dispatch(packet, &mut || false)
+    }
+
+    /// Handle a packet, checking `interrupted` while the program runs.
+    fn dispatch(
+        &mut self,
+        packet: &str,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Option<String> {
+        let cmd = packet.get(..1).unwrap_or_default();
+        let args = packet.get(1..).unwrap_or_default();
+        let reply = match cmd {
+            "?" => self.exited.clone().unwrap_or_else(|| String::from("S05")),
+            "g" => Register::ALL.map(|r| self.read_register(r)).concat(),
+            "G" => self.write_registers(args),
+            "p" => match parse_register(args) {
+                Some(r) => self.read_register(r),
+                None => error(),
+            },
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "Z" => self.breakpoint(args, true),
+            "z" => self.breakpoint(args, false),
+            "s" => self.resume(args, true, interrupted),
+            "c" => self.resume(args, false, interrupted),
+            "H" | "!" => ok(),
+            "D" => {
+                self.detached = true;
+                ok()
+            }
+            "k" => {
+                self.detached = true;
+                return None;
+            }
+            "q" | "Q" | "v" => self.query(packet),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return xfer(TARGET_XML, args);
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:memory-map:read::") {
+            return xfer(MEMORY_MAP, args);
+        }
+        if packet.starts_with("vRun;") {
+            self.tam.reset();
+            self.exited = None;
+            return String::from("S05");
+        }
+        match packet.split(':').next().unwrap_or_default() {
+            "qSupported" => String::from(
+                "PacketSize=4000;qXfer:features:read+;qXfer:memory-map:read+;\
+                     QStartNoAckMode+",
+            ),
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                ok()
+            }
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            _ => String::new(),
+        }
+    }
+
+    /// Get register `r` as the byte address of the word it points to.
+    fn read_register(&self, r: Register) -> String {
+        let word = self.tam.register(r);
+        let addr = if is_code_register(r) {
+            word * 4
+        } else {
+            DATA_BASE + word * 2
+        };
+        format!("{:08x}", addr as u32)
+    }
+
+    /// Get the word a byte address written to register `r` points to, if it is
+    /// the start of a word in the register's address space.
+    fn register_word(r: Register, addr: usize) -> Option<usize> {
+        let (base, size) = if is_code_register(r) {
+            (0, 4)
+        } else {
+            (DATA_BASE, 2)
+        };
+        let offset = addr.checked_sub(base)?;
+        (offset % size == 0 && offset / size <= MEM_SIZE).then_some(offset / size)
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let words: Option<Vec<usize>> = Register::ALL
+            .into_iter()
+            .map(|r| {
+                let i = r as usize * 8;
+                let addr = usize::from_str_radix(args.get(i..i + 8)?, 16).ok()?;
+                Self::register_word(r, addr)
+            })
+            .collect();
+        match words {
+            Some(words) if args.len() == 128 => {
+                for (r, word) in Register::ALL.into_iter().zip(words) {
+                    self.tam.set_register(r, word);
+                }
+                ok()
+            }
+            _ => error(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(r, val)| {
+            let r = parse_register(r)?;
+            let addr = u32::from_str_radix(val, 16).ok()?;
+            Some((r, Self::register_word(r, addr as usize)?))
+        });
+        match parsed {
+            Some((r, word)) => {
+                self.tam.set_register(r, word);
+                ok()
+            }
+            _ => error(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_pair(args) else {
+            return error();
+        };
+        let Some(end) = addr.checked_add(len) else {
+            return error();
+        };
+        let bytes: Vec<u8> =
+            (addr..end).map_while(|addr| self.read_byte(addr)).collect();
+        if bytes.is_empty() && len > 0 {
+            error()
+        } else {
+            hex(&bytes)
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return error();
+        };
+        let (Some((addr, len)), Some(bytes)) = (parse_pair(range), unhex(data)) else {
+            return error();
+        };
+        if bytes.len() != len {
+            return error();
+        }
+        for (i, byte) in bytes.into_iter().enumerate() {
+            match addr.checked_add(i) {
+                Some(addr) if self.write_byte(addr, byte) => (),
+                _ => return error(),
+            }
+        }
+        ok()
+    }
+
+    fn read_byte(&self, addr: usize) -> Option<u8> {
+        if addr < MEM_SIZE * 4 {
+            Some(self.tam.code()[addr / 4].to_be_bytes()[addr % 4])
+        } else if (DATA_BASE..DATA_BASE + MEM_SIZE * 2).contains(&addr) {
+            let offset = addr - DATA_BASE;
+            Some(self.tam.data()[offset / 2].to_be_bytes()[offset % 2])
+        } else {
+            None
+        }
+    }
+
+    fn write_byte(&mut self, addr: usize, byte: u8) -> bool {
+        if addr < MEM_SIZE * 4 {
+            let word = &mut self.tam.code_mut()[addr / 4];
+            let mut bytes = word.to_be_bytes();
+            bytes[addr % 4] = byte;
+            *word = u32::from_be_bytes(bytes);
+            true
+        } else if (DATA_BASE..DATA_BASE + MEM_SIZE * 2).contains(&addr) {
+            let offset = addr - DATA_BASE;
+            let word = &mut self.tam.data_mut()[offset / 2];
+            let mut bytes = word.to_be_bytes();
+            bytes[offset % 2] = byte;
+            *word = i16::from_be_bytes(bytes);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Insert or remove a software or hardware breakpoint.
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.split(',');
+        if !matches!(fields.next(), Some("0" | "1")) {
+            return String::new();
+        }
+        match fields.next().map(|addr| usize::from_str_radix(addr, 16)) {
+            Some(Ok(addr)) if addr % 4 == 0 && addr < MEM_SIZE * 4 => {
+                if insert {
+                    self.breakpoints.insert(addr / 4);
+                } else {
+                    self.breakpoints.remove(&(addr / 4));
+                }
+                ok()
+            }
+            _ => error(),
+        }
+    }
+
+    /// Resume the program, from the code byte address in `args` if there is one,
+    /// for a single step or until it stops or `interrupted` returns true.
+    fn resume(
+        &mut self,
+        args: &str,
+        step: bool,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> String {
+        if let Some(exited) = &self.exited {
+            return exited.clone();
+        }
+        if !args.is_empty() {
+            match usize::from_str_radix(args, 16) {
+                Ok(addr) if addr % 4 == 0 && addr < MEM_SIZE * 4 => {
+                    self.tam.set_register(Register::CP, addr / 4)
+                }
+                _ => return error(),
+            }
+        }
+
+        let mut steps = 0;
+        let reply = loop {
+            match self.tam.step() {
+                Ok(Status::Running) => (),
+                Ok(Status::Halted) => {
+                    self.exited = Some(String::from("W00"));
+                    break String::from("W00");
+                }
+                Err(e) => {
+                    let signal = signal(&e);
+                    self.console.push(format!("{e}\n"));
+                    self.exited = Some(format!("X{signal:02x}"));
+                    break format!("S{signal:02x}");
+                }
+            }
+            if step || self.breakpoints.contains(&self.tam.register(Register::CP)) {
+                break String::from("S05");
+            }
+            steps += 1;
+            if steps % POLL_INTERVAL == 0 && interrupted() {
+                break String::from("S02");
+            }
+        };
+        if let Err(e) = self.tam.flush_output() {
+            self.console
+                .push(format!("could not write program output: {e}\n"));
+        }
+        reply
+    }
+}
+
+/// The signal reported to the debugger for a fault.
+fn signal(e: &TAMError) -> u8 {
+    match e {
+        TAMError::DivideByZero(_) => 8,
+        TAMError::InvalidOpcode(_, _) | TAMError::CodeOutOfBounds(_) => 4,
+        TAMError::IOError(_, _) | TAMError::BadInput(_, _) => 6,
+        TAMError::LimitExceeded(_, _) => 24,
+        _ => 11,
+    }
+}
+
+fn ok() -> String {
+    String::from("OK")
+}
+
+fn error() -> String {
+    String::from("E01")
+}
+
+/// Reply to a `qXfer` read of `doc` at the offset and length in `args`.
+fn xfer(doc: &str, args: &str) -> String {
+    let Some((offset, len)) = parse_pair(args) else {
+        return error();
+    };
+    let Some(end) = offset.checked_add(len) else {
+        return error();
+    };
+    let bytes = doc.as_bytes();
+    let start = offset.min(bytes.len());
+    let end = end.min(bytes.len());
+    let mut reply = String::from(if end < bytes.len() { "m" } else { "l" });
+    for byte in &bytes[start..end] {
+        match byte {
+            b'#' | b'$' | b'}' | b'*' => {
+                reply.push('}');
+                reply.push((byte ^ 0x20) as char);
+            }
+            _ => reply.push(*byte as char),
+        }
+    }
+    reply
+}
+
+/// Parse a pair of hex numbers separated by a comma.
+fn parse_pair(s: &str) -> Option<(usize, usize)> {
+    let (a, b) = s.split_once(',')?;
+    Some((
+        usize::from_str_radix(a, 16).ok()?,
+        usize::from_str_radix(b, 16).ok()?,
+    ))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+fn write_packet(out: &mut impl Write, body: &str) -> std::io::Result<()> {
+    write!(out, "${body}#{:02x}", checksum(body.as_bytes()))?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use common::instruction::{Instruction, Register};
+    use rstest::*;
+
+    use super::*;
+    use crate::io::BufferIo;
+
+    fn instr(op: u8, r: u8, n: u8, d: i16) -> Instruction {
+        Instruction { op, r, n, d }
+    }
+
+    /// A program that stores 7 in a global, then divides by zero.
+    #[fixture]
+    fn stub() -> GdbStub {
+        let prog = [
+            instr(10, 0, 0, 1),
+            instr(3, 0, 0, 7),
+            instr(4, 4, 1, 0),
+            instr(3, 0, 0, 0),
+            instr(6, 2, 0, 11),
+            instr(15, 0, 0, 0),
+        ];
+        let mut tam = TAM::new(false);
+        tam.set_io(Box::new(BufferIo::new("")));
+        tam.load_instructions(&prog).unwrap();
+        GdbStub::new(tam)
+    }
+
+    fn request(stub: &mut GdbStub, packet: &str) -> String {
+        stub.handle(packet).unwrap()
+    }
+
+    #[rstest]
+    fn registers(mut stub: GdbStub) {
+        let regs = request(&mut stub, "g");
+        assert_eq!(128, regs.len());
+        assert_eq!("00000018", &regs[8..16]);
+        assert_eq!("00100000", &regs[32..40]);
+        assert_eq!("OK", request(&mut stub, "P0f=0000000c"));
+        assert_eq!("0000000c", request(&mut stub, "pf"));
+        assert_eq!(3, stub.tam().register(Register::CP));
+        assert_eq!("E01", request(&mut stub, "P0f=0000000d"));
+        assert_eq!("E01", request(&mut stub, "P0f=00100000"));
+        assert_eq!("OK", request(&mut stub, "P5=00100004"));
+        assert_eq!(2, stub.tam().register(Register::ST));
+        assert_eq!("E01", request(&mut stub, "p10"));
+        assert_eq!("OK", request(&mut stub, &format!("G{regs}")));
+        assert_eq!(0, stub.tam().register(Register::CP));
+    }
+
+    #[rstest]
+    fn memory(mut stub: GdbStub) {
+        assert_eq!("30000007", request(&mut stub, "m4,4"));
+        assert_eq!("OK", request(&mut stub, "M100000,2:fffe"));
+        assert_eq!(-2, stub.tam().data()[0]);
+        assert_eq!("fffe0000", request(&mut stub, "m100000,4"));
+        assert_eq!("E01", request(&mut stub, "m40000,4"));
+        assert_eq!("E01", request(&mut stub, "mffffffffffffffff,2"));
+        assert_eq!(
+            "E01",
+            request(
+                &mut stub,
+                "qXfer:features:read:target.xml:1,ffffffffffffffff"
+            )
+        );
+    }
+
+    #[rstest]
+    fn breakpoint_and_step(mut stub: GdbStub) {
+        assert_eq!("OK", request(&mut stub, "Z0,c,4"));
+        assert_eq!("S05", request(&mut stub, "c"));
+        assert_eq!(3, stub.tam().register(Register::CP));
+        assert_eq!(7, stub.tam().data()[0]);
+        assert_eq!("S05", request(&mut stub, "s"));
+        assert_eq!(4, stub.tam().register(Register::CP));
+        assert_eq!("OK", request(&mut stub, "z0,c,4"));
+        assert_eq!("E01", request(&mut stub, "Z0,d,4"));
+        assert_eq!("", request(&mut stub, "Z2,100000,2"));
+    }
+
+    #[rstest]
+    fn fault_ends_program(mut stub: GdbStub) {
+        assert_eq!("S08", request(&mut stub, "c"));
+        assert_eq!(vec!["divide by zero attempted at loc 0004\n"], stub.console);
+        assert_eq!("X08", request(&mut stub, "c"));
+        assert_eq!("X08", request(&mut stub, "?"));
+        assert_eq!("S05", request(&mut stub, "vRun;"));
+        assert_eq!("W00", request(&mut stub, "c14"));
+    }
+
+    #[rstest]
+    fn target_description(mut stub: GdbStub) {
+        let names: Vec<&str> = TARGET_XML
+            .split("<reg name=\"")
+            .skip(1)
+            .map(|reg| reg.split('"').next().unwrap())
+            .collect();
+        let expected: Vec<&str> = Register::ALL.iter().map(|r| r.name()).collect();
+        assert_eq!(expected, names);
+
+        let start = request(&mut stub, "qXfer:features:read:target.xml:0,a");
+        assert_eq!("m<?xml vers", start);
+        let end = request(&mut stub, "qXfer:features:read:target.xml:a,4000");
+        assert!(end.starts_with('l') && end.ends_with("</target>\n"));
+        let map = request(&mut stub, "qXfer:memory-map:read::0,4000");
+        assert!(map.contains(&format!("start=\"{DATA_BASE:#x}\"")));
+    }
+
+    #[rstest]
+    fn serve_packets(mut stub: GdbStub) {
+        let mut out = Vec::new();
+        let input = b"+$?#3f$m0,1#00$QStartNoAckMode#b0+$qAttached#8f$D#44";
+        stub.serve(&mut &input[..], &mut out).unwrap();
+        assert_eq!(
+            "+$S05#b8-+$OK#9a$1#31$OK#9a",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[rstest]
+    fn interrupt_while_running() {
+        let mut tam = TAM::new(false);
+        tam.load_instructions(&[instr(12, 0, 0, 0)]).unwrap();
+        let mut stub = GdbStub::new(tam);
+        let mut out = Vec::new();
+        stub.serve(&mut &b"+$c#63\x03"[..], &mut out).unwrap();
+        assert_eq!("+$S02#b5", String::from_utf8(out).unwrap());
+    }
+}
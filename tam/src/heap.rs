@@ -0,0 +1,483 @@
+//! Allocator behind the `new` and `dispose` primitives.
+//!
+//! The heap grows down from HB towards the stack. Each block is preceded by a
+//! one-word header in the data store holding the size of the block, and the
+//! address returned by `new` is that of the first word after the header. Freed
+//! blocks are kept in a free list ordered by address and are coalesced with any
+//! free neighbours. A free block at the top of the heap is returned to the space
+//! between the stack and the heap by raising HT.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Error, Formatter},
+    mem::size_of,
+    str::FromStr,
+};
+
+/// How the allocator chooses where to place a new block.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Always allocate at the top of the heap. Freed blocks are only reused once
+    /// they reach the top of the heap.
+    Bump,
+    /// Allocate in the free block with the lowest address that is large enough.
+    #[default]
+    FirstFit,
+    /// Allocate in the smallest free block that is large enough.
+    BestFit,
+}
+
+impl Display for Strategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::Bump => write!(f, "bump"),
+            Self::FirstFit => write!(f, "first-fit"),
+            Self::BestFit => write!(f, "best-fit"),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bump" => Ok(Self::Bump),
+            "first-fit" => Ok(Self::FirstFit),
+            "best-fit" => Ok(Self::BestFit),
+            _ => Err(format!(
+                "unknown heap strategy `{s}`, expected bump, first-fit or best-fit"
+            )),
+        }
+    }
+}
+
+/// A block allocated on the heap.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Block {
+    /// Address of the first word of the block.
+    pub addr: usize,
+    /// Number of words in the block, not including its header.
+    pub size: usize,
+    /// Code address of the `new` call that allocated the block.
+    pub alloc_site: usize,
+    /// Code address of the `dispose` call that freed the block, if it has been.
+    pub free_site: Option<usize>,
+}
+
+impl Block {
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.addr && addr < self.addr + self.size
+    }
+}
+
+/// A misuse of the heap detected by the sanitizer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeapViolation {
+    /// Indicate an access to the given address in a block that has been freed.
+    UseAfterFree(usize, Block),
+    /// Indicate an access to a heap address that is not in any allocated block.
+    Unallocated(usize),
+    /// Indicate a `dispose` of a block that has already been freed.
+    DoubleFree(Block),
+    /// Indicate a `dispose` of an address that is not the start of a block.
+    InvalidFree(usize),
+    /// Indicate a `dispose` of a block with the wrong number of words.
+    WrongSize(Block, usize),
+    /// Indicate blocks that were still allocated when the program halted.
+    Leak(Vec<Block>),
+}
+
+impl HeapViolation {
+    /// Get the blocks the violation concerns.
+    pub fn blocks(&self) -> &[Block] {
+        match self {
+            Self::UseAfterFree(_, block)
+            | Self::DoubleFree(block)
+            | Self::WrongSize(block, _) => std::slice::from_ref(block),
+            Self::Leak(blocks) => blocks,
+            Self::Unallocated(_) | Self::InvalidFree(_) => &[],
+        }
+    }
+}
+
+impl Display for HeapViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::UseAfterFree(addr, block) => write!(
+                f,
+                "use of {addr:04x} after it was freed at loc {:04x}",
+                block.free_site.unwrap_or_default()
+            ),
+            Self::Unallocated(addr) => {
+                write!(f, "use of {addr:04x}, which is not in an allocated block")
+            }
+            Self::DoubleFree(block) => write!(
+                f,
+                "block at {:04x} disposed of twice, first at loc {:04x}",
+                block.addr,
+                block.free_site.unwrap_or_default()
+            ),
+            Self::InvalidFree(addr) => {
+                write!(f, "dispose of {addr:04x}, which is not an allocated block")
+            }
+            Self::WrongSize(block, n) => write!(
+                f,
+                "dispose of {n} words from a block of {} words at {:04x}",
+                block.size, block.addr
+            ),
+            Self::Leak(blocks) => write!(
+                f,
+                "{} words in {} blocks still allocated at halt",
+                blocks.iter().map(|b| b.size).sum::<usize>(),
+                blocks.len()
+            ),
+        }
+    }
+}
+
+/// Bookkeeping for the blocks on the heap.
+///
+/// The heap does not own the data store, so the machine writes block headers and
+/// moves HT as the allocator directs.
+#[derive(Debug, Clone, Default)]
+pub struct Heap {
+    strategy: Strategy,
+    /// Live blocks, by the address returned by `new`.
+    allocated: BTreeMap<usize, Block>,
+    /// Blocks that have been freed and whose memory has not been reused, by
+    /// address.
+    freed: BTreeMap<usize, Block>,
+    /// Free blocks, from the address of their header to their length in words,
+    /// including the header.
+    free: BTreeMap<usize, usize>,
+    allocations: usize,
+    frees: usize,
+    live_words: usize,
+    peak_words: usize,
+}
+
+/// Where a block was placed by [`Heap::allocate`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Placement {
+    /// Address of the block header.
+    pub header: usize,
+    /// Address of the first word of the block.
+    pub addr: usize,
+    /// New value of HT.
+    pub ht: usize,
+}
+
+impl Heap {
+    /// Construct an empty heap that places blocks with `strategy`.
+    pub fn new(strategy: Strategy) -> Heap {
+        Heap {
+            strategy,
+            ..Heap::default()
+        }
+    }
+
+    /// Get the strategy used to place blocks.
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Forget every block, for a heap that is empty again.
+    pub fn reset(&mut self) {
+        *self = Heap::new(self.strategy);
+    }
+
+    /// Iterate over the live blocks in address order.
+    pub fn blocks(&self) -> impl Iterator<Item = &Block> + '_ {
+        self.allocated.values()
+    }
+
+    /// Get the approximate number of bytes the allocator occupies.
+    pub(crate) fn size(&self) -> usize {
+        let node = size_of::<usize>() + size_of::<Block>();
+        size_of::<Heap>()
+            + (self.allocated.len() + self.freed.len() + self.free.len()) * node
+    }
+
+    /// Find the live block that contains `addr`, if there is one.
+    pub fn live_block(&self, addr: usize) -> Option<&Block> {
+        let (_, block) = self.allocated.range(..=addr).next_back()?;
+        block.contains(addr).then_some(block)
+    }
+
+    /// Find the freed block that most recently contained `addr`, if its memory has
+    /// not been reused.
+    pub fn freed_block(&self, addr: usize) -> Option<&Block> {
+        let (_, block) = self.freed.range(..=addr).next_back()?;
+        block.contains(addr).then_some(block)
+    }
+
+    /// Get the number of words the heap would grow by to allocate a block of `n`
+    /// words, which is none if a free block has room for it.
+    pub fn growth(&self, n: usize) -> usize {
+        let len = n + 1;
+        if self.find_free(len).is_some() {
+            0
+        } else {
+            len
+        }
+    }
+
+    /// Find room for a block of `n` words for the `new` call at code address `site`.
+    ///
+    /// `ht` is the current heap top, and a block taken from the top of the heap must
+    /// leave HT above `floor`. Returns `None` if there is no room.
+    pub fn allocate(
+        &mut self,
+        n: usize,
+        site: usize,
+        ht: usize,
+        floor: usize,
+    ) -> Option<Placement> {
+        let len = n + 1;
+        let (header, ht) = match self.find_free(len) {
+            Some(header) => {
+                let block = self.free.remove(&header).unwrap_or(len);
+                if block > len {
+                    self.free.insert(header + len, block - len);
+                }
+                (header, ht)
+            }
+            None => {
+                let new_ht = ht.checked_sub(len).filter(|t| *t > floor)?;
+                (new_ht + 1, new_ht)
+            }
+        };
+
+        // Forget freed blocks whose memory is being reused
+        let reused: Vec<usize> = self
+            .freed
+            .range(..header + len)
+            .filter(|(_, b)| b.addr + b.size >= header)
+            .map(|(a, _)| *a)
+            .collect();
+        for a in reused {
+            self.freed.remove(&a);
+        }
+
+        let addr = header + 1;
+        let block = Block {
+            addr,
+            size: n,
+            alloc_site: site,
+            free_site: None,
+        };
+        self.allocated.insert(addr, block);
+        self.allocations += 1;
+        self.live_words += len;
+        self.peak_words = self.peak_words.max(self.live_words);
+        Some(Placement { header, addr, ht })
+    }
+
+    /// Free the live block starting at `addr` for the `dispose` call at code address
+    /// `site`.
+    ///
+    /// Returns the block and the new value of HT, or `None` if `addr` is not the
+    /// start of a live block.
+    pub fn free(
+        &mut self,
+        addr: usize,
+        site: usize,
+        ht: usize,
+    ) -> Option<(Block, usize)> {
+        let mut block = self.allocated.remove(&addr)?;
+        block.free_site = Some(site);
+        self.freed.insert(addr, block);
+        self.frees += 1;
+
+        let n = block.size;
+        self.live_words -= n + 1;
+
+        let mut header = addr - 1;
+        let mut len = n + 1;
+        if let Some((&prev, &prev_len)) = self.free.range(..header).next_back() {
+            if prev + prev_len == header {
+                self.free.remove(&prev);
+                header = prev;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.free.remove(&(header + len)) {
+            len += next_len;
+        }
+
+        if header == ht + 1 {
+            Some((block, ht + len))
+        } else {
+            self.free.insert(header, len);
+            Some((block, ht))
+        }
+    }
+
+    fn find_free(&self, len: usize) -> Option<usize> {
+        let mut fits = self.free.iter().filter(|(_, l)| **l >= len);
+        match self.strategy {
+            Strategy::Bump => None,
+            Strategy::FirstFit => fits.next().map(|(h, _)| *h),
+            Strategy::BestFit => fits.min_by_key(|(h, l)| (**l, **h)).map(|(h, _)| *h),
+        }
+    }
+
+    /// Summarise the state of the heap, given the current heap top and base.
+    pub fn usage(&self, ht: usize, hb: usize) -> HeapUsage {
+        HeapUsage {
+            strategy: self.strategy,
+            extent: hb - ht,
+            live_blocks: self.allocated.len(),
+            live_words: self.allocated.values().map(|b| b.size).sum(),
+            free_blocks: self.free.len(),
+            free_words: self.free.values().sum(),
+            largest_free: self.free.values().max().copied().unwrap_or(0),
+            allocations: self.allocations,
+            frees: self.frees,
+            peak_words: self.peak_words,
+        }
+    }
+}
+
+/// Statistics on the use of the heap.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HeapUsage {
+    pub strategy: Strategy,
+    /// Number of words between HT and HB, including headers and free blocks.
+    pub extent: usize,
+    pub live_blocks: usize,
+    /// Number of words in live blocks, not including headers.
+    pub live_words: usize,
+    pub free_blocks: usize,
+    /// Number of words in free blocks, including their headers.
+    pub free_words: usize,
+    pub largest_free: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    /// Largest number of words in live blocks at once, including headers.
+    pub peak_words: usize,
+}
+
+impl Display for HeapUsage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        writeln!(f, "heap usage ({}):", self.strategy)?;
+        writeln!(f, "  extent:      {} words", self.extent)?;
+        writeln!(
+            f,
+            "  live:        {} words in {} blocks",
+            self.live_words, self.live_blocks
+        )?;
+        writeln!(
+            f,
+            "  free:        {} words in {} blocks, largest {}",
+            self.free_words, self.free_blocks, self.largest_free
+        )?;
+        writeln!(f, "  peak:        {} words", self.peak_words)?;
+        writeln!(
+            f,
+            "  allocations: {}, frees: {}",
+            self.allocations, self.frees
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::*;
+
+    use super::*;
+
+    const HB: usize = 100;
+
+    #[rstest]
+    fn allocate_from_top() {
+        let mut heap = Heap::new(Strategy::FirstFit);
+        let p = heap.allocate(2, 0, HB, 0).unwrap();
+        assert_eq!(
+            Placement {
+                header: 98,
+                addr: 99,
+                ht: 97
+            },
+            p
+        );
+        let p = heap.allocate(3, 0, p.ht, 0).unwrap();
+        assert_eq!(
+            Placement {
+                header: 94,
+                addr: 95,
+                ht: 93
+            },
+            p
+        );
+    }
+
+    #[rstest]
+    fn allocate_respects_floor() {
+        let mut heap = Heap::new(Strategy::FirstFit);
+        assert_eq!(None, heap.allocate(5, 0, HB, 95));
+        assert!(heap.allocate(5, 0, HB, 93).is_some());
+    }
+
+    #[rstest]
+    fn free_top_block_lowers_heap() {
+        let mut heap = Heap::new(Strategy::FirstFit);
+        let a = heap.allocate(2, 0, HB, 0).unwrap();
+        let b = heap.allocate(3, 0, a.ht, 0).unwrap();
+
+        let size_and_ht = |res: Option<(Block, usize)>| res.map(|(b, ht)| (b.size, ht));
+        assert_eq!(Some((3, 97)), size_and_ht(heap.free(b.addr, 0, b.ht)));
+        assert_eq!(Some((2, HB)), size_and_ht(heap.free(a.addr, 0, 97)));
+        assert_eq!(None, heap.free(a.addr, 0, HB));
+    }
+
+    #[rstest]
+    fn free_coalesces_neighbours() {
+        let mut heap = Heap::new(Strategy::FirstFit);
+        let a = heap.allocate(2, 0, HB, 0).unwrap();
+        let b = heap.allocate(2, 0, a.ht, 0).unwrap();
+        let c = heap.allocate(2, 0, b.ht, 0).unwrap();
+
+        heap.free(a.addr, 0, c.ht);
+        heap.free(b.addr, 0, c.ht);
+
+        let usage = heap.usage(c.ht, HB);
+        assert_eq!((1, 6), (usage.free_blocks, usage.free_words));
+    }
+
+    #[rstest]
+    #[case::bump(Strategy::Bump, 92)]
+    #[case::first_fit(Strategy::FirstFit, 95)]
+    #[case::best_fit(Strategy::BestFit, 100)]
+    fn strategy_places_block(#[case] strategy: Strategy, #[case] expected: usize) {
+        let mut heap = Heap::new(strategy);
+        let a = heap.allocate(1, 0, HB, 0).unwrap();
+        let b = heap.allocate(0, 0, a.ht, 0).unwrap();
+        let c = heap.allocate(3, 0, b.ht, 0).unwrap();
+        let d = heap.allocate(0, 0, c.ht, 0).unwrap();
+        heap.free(a.addr, 0, d.ht);
+        heap.free(c.addr, 0, d.ht);
+
+        let p = heap.allocate(1, 0, d.ht, 0).unwrap();
+        assert_eq!(expected, p.addr);
+    }
+
+    #[rstest]
+    fn track_freed_blocks_until_reused() {
+        let mut heap = Heap::new(Strategy::FirstFit);
+        let a = heap.allocate(2, 4, HB, 0).unwrap();
+        let b = heap.allocate(1, 6, a.ht, 0).unwrap();
+        assert_eq!(Some(4), heap.live_block(a.addr + 1).map(|b| b.alloc_site));
+        assert_eq!(None, heap.live_block(a.header));
+
+        heap.free(a.addr, 9, b.ht);
+        let freed = heap.freed_block(a.addr + 1).unwrap();
+        assert_eq!((4, Some(9)), (freed.alloc_site, freed.free_site));
+        assert_eq!(None, heap.live_block(a.addr));
+
+        heap.allocate(2, 12, b.ht, 0).unwrap();
+        assert_eq!(None, heap.freed_block(a.addr));
+        assert_eq!(Some(12), heap.live_block(a.addr).map(|b| b.alloc_site));
+    }
+}
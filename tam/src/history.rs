@@ -0,0 +1,343 @@
+//! Execution history for running a program backwards.
+//!
+//! While recording, the machine keeps a [`History`] of the steps it has executed.
+//! Each step is logged as the register values before it and the old value of each
+//! data-store word it overwrote, and every so often the whole machine state is
+//! saved as a checkpoint. A step is undone by restoring the words and registers
+//! it logged, and a long way back is reached by restoring the nearest checkpoint
+//! after it and undoing the steps in between.
+//!
+//! The oldest steps are forgotten once the history outgrows its budget, so the
+//! program can only be run back to the oldest step that is still logged.
+
+use std::{
+    collections::VecDeque,
+    fmt::{Display, Error, Formatter},
+    mem::size_of,
+};
+
+use crate::{heap::Heap, machine::CP, shadow::Shadow};
+
+/// How much history the machine keeps.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HistoryConfig {
+    /// Approximate number of bytes the history may occupy.
+    pub budget: usize,
+    /// Number of steps between checkpoints.
+    pub checkpoint_interval: u64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            budget: 64 << 20,
+            checkpoint_interval: 10_000,
+        }
+    }
+}
+
+/// The last write to a data-store word that is still in the history.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LastWrite {
+    /// Number of the step that wrote the word.
+    pub step: u64,
+    /// Code address of the instruction executed by the step.
+    pub loc: usize,
+    /// Value of the word before the step.
+    pub old: i16,
+    /// Value of the word after the step.
+    pub new: i16,
+}
+
+impl Display for LastWrite {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(
+            f,
+            "step {} at loc {:04x}: {} -> {}",
+            self.step, self.loc, self.old, self.new
+        )
+    }
+}
+
+/// The state of the machine before a step, apart from the words it overwrote.
+#[derive(Debug, Clone)]
+pub(crate) struct Delta {
+    /// Number of the step, counting the first after a reset as step 1.
+    pub(crate) step: u64,
+    pub(crate) registers: [usize; 16],
+    pub(crate) depth: usize,
+    pub(crate) output_len: usize,
+    /// Old values of the data-store words the step wrote, in the order it wrote
+    /// them.
+    pub(crate) writes: Vec<(usize, i16)>,
+    /// Old shadow bits of the words whose initialization the step changed.
+    pub(crate) defined: Vec<(usize, bool)>,
+    /// The allocator before the step, if the step allocated or freed a block.
+    pub(crate) heap: Option<Box<Heap>>,
+}
+
+impl Delta {
+    fn size(&self) -> usize {
+        size_of::<Delta>()
+            + self.writes.capacity() * size_of::<(usize, i16)>()
+            + self.defined.capacity() * size_of::<(usize, bool)>()
+            + self.heap.as_ref().map_or(0, |heap| heap.size())
+    }
+}
+
+/// The whole state of the machine after a number of steps.
+#[derive(Debug, Clone)]
+pub(crate) struct Checkpoint {
+    /// Number of steps executed before the checkpoint was taken.
+    pub(crate) steps: u64,
+    pub(crate) registers: [usize; 16],
+    pub(crate) depth: usize,
+    pub(crate) output_len: usize,
+    pub(crate) data: Vec<i16>,
+    pub(crate) shadow: Shadow,
+    pub(crate) heap: Heap,
+}
+
+impl Checkpoint {
+    fn size(&self) -> usize {
+        size_of::<Checkpoint>()
+            + self.data.len() * size_of::<i16>()
+            + self.shadow.size()
+            + self.heap.size()
+    }
+}
+
+/// Steps and checkpoints recorded since the machine was last reset.
+#[derive(Debug, Clone)]
+pub struct History {
+    config: HistoryConfig,
+    deltas: VecDeque<Delta>,
+    checkpoints: VecDeque<Checkpoint>,
+    /// The step being executed.
+    current: Option<Delta>,
+    bytes: usize,
+}
+
+impl History {
+    /// Construct an empty history.
+    pub fn new(config: HistoryConfig) -> History {
+        History {
+            config,
+            deltas: VecDeque::new(),
+            checkpoints: VecDeque::new(),
+            current: None,
+            bytes: 0,
+        }
+    }
+
+    /// Get the limits on the history.
+    pub fn config(&self) -> HistoryConfig {
+        self.config
+    }
+
+    /// Get the number of steps that can be undone.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// Check if there are no steps that can be undone.
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Get the approximate number of bytes the history occupies.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Get the number of steps executed at the oldest point the program can be run
+    /// back to, if any step can be undone.
+    pub fn earliest(&self) -> Option<u64> {
+        self.deltas.front().map(|delta| delta.step - 1)
+    }
+
+    /// Check if a checkpoint is due after `steps` steps.
+    pub(crate) fn checkpoint_due(&self, steps: u64) -> bool {
+        steps.is_multiple_of(self.config.checkpoint_interval.max(1))
+            && self.checkpoints.back().is_none_or(|cp| cp.steps != steps)
+    }
+
+    /// Save a checkpoint, unless it alone would exceed the budget.
+    pub(crate) fn checkpoint(&mut self, checkpoint: Checkpoint) {
+        let size = checkpoint.size();
+        if size <= self.config.budget {
+            self.checkpoints.push_back(checkpoint);
+            self.bytes += size;
+        }
+    }
+
+    /// Start logging the step numbered `step`, given the state before it.
+    pub(crate) fn begin(
+        &mut self,
+        step: u64,
+        registers: &[usize; 16],
+        depth: usize,
+        output_len: usize,
+    ) {
+        self.current = Some(Delta {
+            step,
+            registers: *registers,
+            depth,
+            output_len,
+            writes: Vec::new(),
+            defined: Vec::new(),
+            heap: None,
+        });
+    }
+
+    /// Log the old value of a word the current step is about to write.
+    pub(crate) fn write(&mut self, addr: usize, old: i16) {
+        if let Some(delta) = &mut self.current {
+            delta.writes.push((addr, old));
+        }
+    }
+
+    /// Log the old shadow bit of a word whose initialization the current step is
+    /// about to change.
+    pub(crate) fn define(&mut self, addr: usize, old: bool) {
+        if let Some(delta) = &mut self.current {
+            delta.defined.push((addr, old));
+        }
+    }
+
+    /// Save the allocator before the current step first changes it.
+    pub(crate) fn save_heap(&mut self, heap: &Heap) {
+        if let Some(delta) = &mut self.current {
+            if delta.heap.is_none() {
+                delta.heap = Some(Box::new(heap.clone()));
+            }
+        }
+    }
+
+    /// Finish logging the current step, forgetting the oldest steps if the history
+    /// is over budget.
+    pub(crate) fn end(&mut self) {
+        let Some(delta) = self.current.take() else {
+            return;
+        };
+        self.bytes += delta.size();
+        self.deltas.push_back(delta);
+
+        while self.bytes > self.config.budget {
+            let oldest_step = self.deltas.front().map_or(u64::MAX, |delta| delta.step);
+            match self.checkpoints.front() {
+                Some(cp) if cp.steps < oldest_step || self.deltas.len() == 1 => {
+                    self.bytes -= cp.size();
+                    self.checkpoints.pop_front();
+                }
+                _ if self.deltas.len() > 1 => {
+                    let delta = self.deltas.pop_front().unwrap();
+                    self.bytes -= delta.size();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Remove the newest step so that it can be undone.
+    pub(crate) fn pop(&mut self) -> Option<Delta> {
+        let delta = self.deltas.pop_back()?;
+        self.bytes -= delta.size();
+        Some(delta)
+    }
+
+    /// Get the oldest checkpoint taken at or after `steps` steps and before `now`.
+    pub(crate) fn checkpoint_between(
+        &self,
+        steps: u64,
+        now: u64,
+    ) -> Option<&Checkpoint> {
+        self.checkpoints
+            .iter()
+            .find(|cp| cp.steps >= steps && cp.steps < now)
+    }
+
+    /// Forget the steps and checkpoints after `steps` steps.
+    pub(crate) fn truncate(&mut self, steps: u64) {
+        while self.deltas.back().is_some_and(|delta| delta.step > steps) {
+            self.pop();
+        }
+        while let Some(cp) = self.checkpoints.back() {
+            if cp.steps <= steps {
+                break;
+            }
+            self.bytes -= cp.size();
+            self.checkpoints.pop_back();
+        }
+    }
+
+    /// Find the newest logged step that wrote to `addr`, whose value is now
+    /// `current`.
+    pub(crate) fn last_write(&self, addr: usize, current: i16) -> Option<LastWrite> {
+        let delta = self
+            .deltas
+            .iter()
+            .rev()
+            .find(|delta| delta.writes.iter().any(|(a, _)| *a == addr))?;
+        let old = delta.writes.iter().find(|(a, _)| *a == addr).unwrap().1;
+        Some(LastWrite {
+            step: delta.step,
+            loc: delta.registers[CP],
+            old,
+            new: current,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(budget: usize) -> History {
+        History::new(HistoryConfig {
+            budget,
+            checkpoint_interval: 4,
+        })
+    }
+
+    fn log(history: &mut History, step: u64, writes: &[(usize, i16)]) {
+        history.begin(step, &[0; 16], 0, 0);
+        for (addr, old) in writes {
+            history.write(*addr, *old);
+        }
+        history.end();
+    }
+
+    #[test]
+    fn last_write_is_newest() {
+        let mut history = history(1 << 20);
+        log(&mut history, 1, &[(3, 0)]);
+        log(&mut history, 2, &[(4, 0)]);
+        log(&mut history, 3, &[(3, 5), (3, 6)]);
+        let write = history.last_write(3, 7).unwrap();
+        assert_eq!((3, 5, 7), (write.step, write.old, write.new));
+        assert_eq!(None, history.last_write(9, 0));
+    }
+
+    #[test]
+    fn budget_forgets_oldest_steps() {
+        let mut history = history(3 * size_of::<Delta>());
+        for step in 1..=5 {
+            log(&mut history, step, &[]);
+        }
+        assert!(history.len() < 5);
+        assert!(history.bytes() <= 3 * size_of::<Delta>());
+        assert_eq!(Some(5 - history.len() as u64), history.earliest());
+    }
+
+    #[test]
+    fn truncate_forgets_later_steps() {
+        let mut history = history(1 << 20);
+        for step in 1..=5 {
+            log(&mut history, step, &[]);
+        }
+        history.truncate(2);
+        assert_eq!(2, history.len());
+        assert_eq!(2, history.pop().unwrap().step);
+    }
+}
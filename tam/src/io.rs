@@ -0,0 +1,182 @@
+//! Input and output backends used by the I/O primitives.
+
+use std::{
+    cell::RefCell,
+    io::{BufRead, Cursor, Read, Result, Write},
+    rc::Rc,
+};
+
+/// A source of input and sink for output for a running program.
+pub trait TamIo {
+    /// Look at the next byte of input without consuming it.
+    ///
+    /// Returns `None` at the end of the input.
+    fn peek_byte(&mut self) -> Result<Option<u8>>;
+
+    /// Consume the next byte of input.
+    ///
+    /// Returns `None` at the end of the input.
+    fn read_byte(&mut self) -> Result<Option<u8>>;
+
+    /// Write a string to the output.
+    fn write_str(&mut self, s: &str) -> Result<()>;
+
+    /// Flush any buffered output.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Consume input up to and including the next newline, returning everything
+    /// before it.
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = Vec::new();
+        while let Some(b) = self.read_byte()? {
+            if b == b'\n' {
+                break;
+            }
+            line.push(b);
+        }
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+}
+
+/// I/O backend over any buffered reader and writer.
+pub struct StreamIo<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> StreamIo<R, W> {
+    pub fn new(input: R, output: W) -> StreamIo<R, W> {
+        StreamIo { input, output }
+    }
+}
+
+impl<R: BufRead, W: Write> TamIo for StreamIo<R, W> {
+    fn peek_byte(&mut self) -> Result<Option<u8>> {
+        self.output.flush()?;
+        Ok(self.input.fill_buf()?.first().copied())
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let b = self.peek_byte()?;
+        if b.is_some() {
+            self.input.consume(1);
+        }
+        Ok(b)
+    }
+
+    fn write_str(&mut self, s: &str) -> Result<()> {
+        self.output.write_all(s.as_bytes())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.output.flush()
+    }
+}
+
+/// I/O backend that reads from stdin and writes to stdout.
+///
+/// Stdin is only locked for the duration of each read, so other readers such as
+/// the debugger can share it with the running program.
+#[derive(Default)]
+pub struct StdIo;
+
+impl TamIo for StdIo {
+    fn peek_byte(&mut self) -> Result<Option<u8>> {
+        std::io::stdout().flush()?;
+        Ok(std::io::stdin().lock().fill_buf()?.first().copied())
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let b = self.peek_byte()?;
+        if b.is_some() {
+            std::io::stdin().lock().consume(1);
+        }
+        Ok(b)
+    }
+
+    fn write_str(&mut self, s: &str) -> Result<()> {
+        std::io::stdout().write_all(s.as_bytes())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+/// In-memory I/O backend with scripted input and captured output.
+///
+/// Clones share the same buffers, so a clone can be handed to a [`TAM`](crate::TAM)
+/// and the original used to inspect the output afterwards.
+#[derive(Clone, Default)]
+pub struct BufferIo {
+    input: Rc<RefCell<Cursor<Vec<u8>>>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl BufferIo {
+    /// Construct a backend that will supply `input` to the program.
+    pub fn new(input: impl Into<Vec<u8>>) -> BufferIo {
+        BufferIo {
+            input: Rc::new(RefCell::new(Cursor::new(input.into()))),
+            output: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Get everything the program has written so far.
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output.borrow()).into_owned()
+    }
+}
+
+impl TamIo for BufferIo {
+    fn peek_byte(&mut self) -> Result<Option<u8>> {
+        Ok(self.input.borrow_mut().fill_buf()?.first().copied())
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut b = [0u8; 1];
+        match self.input.borrow_mut().read(&mut b)? {
+            0 => Ok(None),
+            _ => Ok(Some(b[0])),
+        }
+    }
+
+    fn write_str(&mut self, s: &str) -> Result<()> {
+        self.output.borrow_mut().extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_io_peek_does_not_consume() {
+        let mut io = BufferIo::new("ab");
+        assert_eq!(Some(b'a'), io.peek_byte().unwrap());
+        assert_eq!(Some(b'a'), io.read_byte().unwrap());
+        assert_eq!(Some(b'b'), io.read_byte().unwrap());
+        assert_eq!(None, io.peek_byte().unwrap());
+    }
+
+    #[test]
+    fn buffer_io_read_line() {
+        let mut io = BufferIo::new("12\n34");
+        assert_eq!("12", io.read_line().unwrap());
+        assert_eq!("34", io.read_line().unwrap());
+        assert_eq!("", io.read_line().unwrap());
+    }
+
+    #[test]
+    fn buffer_io_clones_share_output() {
+        let io = BufferIo::new("");
+        let mut io2 = io.clone();
+        io2.write_str("hello").unwrap();
+        assert_eq!("hello", io.output());
+    }
+}
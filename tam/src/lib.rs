@@ -0,0 +1,26 @@
+//! An implementation of the Triangle Abstract Machine that can be embedded in other
+//! tools.
+//!
+//! Programs are loaded into a [`TAM`] from raw bytecode or a sequence of
+//! [`Instruction`](common::instruction::Instruction)s, and can then be run to
+//! completion or executed one instruction at a time.
+
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod errors;
+pub mod gdb;
+pub mod heap;
+pub mod history;
+pub mod io;
+pub mod limits;
+pub mod machine;
+pub mod profile;
+pub mod report;
+pub mod shadow;
+pub mod trace;
+
+pub use crate::{
+    errors::{TAMError, TAMResult},
+    machine::{Status, TAM},
+};
@@ -0,0 +1,36 @@
+//! Limits on the resources a program may use.
+
+use std::fmt::{Display, Error, Formatter};
+
+/// Resource limits for a [`TAM`](crate::TAM). A limit of `None` is unbounded.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of instructions executed after the machine is reset.
+    pub max_steps: Option<u64>,
+    /// Maximum number of words on the stack, counted from SB.
+    pub max_stack: Option<usize>,
+    /// Maximum number of words the heap may occupy, including block headers.
+    pub max_heap: Option<usize>,
+    /// Maximum number of bytes of output after the machine is reset.
+    pub max_output: Option<usize>,
+}
+
+/// A limit that a program has exceeded, with the value it was set to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Limit {
+    Steps(u64),
+    Stack(usize),
+    Heap(usize),
+    Output(usize),
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::Steps(n) => write!(f, "step limit of {}", n),
+            Self::Stack(n) => write!(f, "stack limit of {} words", n),
+            Self::Heap(n) => write!(f, "heap limit of {} words", n),
+            Self::Output(n) => write!(f, "output limit of {} bytes", n),
+        }
+    }
+}
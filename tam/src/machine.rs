@@ -1,42 +1,125 @@
-use std::io::{Cursor, Read};
-use std::str::FromStr;
+use std::{collections::HashMap, io::Cursor};
 
 use byteorder::{ReadBytesExt, BE};
-use common::instruction::Instruction;
-
-use crate::errors::{TAMError, TAMResult};
-
-const MEM_SIZE: usize = 65535;
+use common::{
+    container::Container,
+    debug::DebugInfo,
+    instruction::{Instruction, Opcode, Primitive, Register},
+};
+
+use crate::{
+    coverage::Coverage,
+    errors::{TAMError, TAMResult},
+    heap::{Heap, HeapUsage, HeapViolation, Strategy},
+    history::{Checkpoint, Delta, History, HistoryConfig, LastWrite},
+    io::{StdIo, TamIo},
+    limits::{Limit, Limits},
+    profile::Profile,
+    shadow::{Shadow, UninitMode, UninitRead},
+    trace::{TraceFilter, Tracer},
+};
+
+/// Number of words in each of the code and data stores.
+pub const MEM_SIZE: usize = 65535;
+
+pub const CB: usize = Register::CB as usize;
+pub const CT: usize = Register::CT as usize;
+pub const PB: usize = Register::PB as usize;
+pub const PT: usize = Register::PT as usize;
+pub const SB: usize = Register::SB as usize;
+pub const ST: usize = Register::ST as usize;
+pub const HB: usize = Register::HB as usize;
+pub const HT: usize = Register::HT as usize;
+pub const LB: usize = Register::LB as usize;
+pub const L1: usize = Register::L1 as usize;
+pub const L2: usize = Register::L2 as usize;
+pub const L3: usize = Register::L3 as usize;
+pub const L4: usize = Register::L4 as usize;
+pub const L5: usize = Register::L5 as usize;
+pub const L6: usize = Register::L6 as usize;
+pub const CP: usize = Register::CP as usize;
+
+/// Outcome of executing a single instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// The machine can continue executing.
+    Running,
+    /// The machine has executed a `halt` instruction.
+    Halted,
+}
 
-const CT: usize = 1;
-const PB: usize = 2;
-const PT: usize = 3;
-const SB: usize = 4;
-const ST: usize = 5;
-const HB: usize = 6;
-const HT: usize = 7;
-const LB: usize = 8;
-const CP: usize = 15;
+/// An active routine frame on the stack.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Address of the first word of the frame, which holds its static link.
+    pub base: usize,
+    /// Base of the frame of the caller, taken from the dynamic link.
+    pub dynamic_link: usize,
+    /// Code address control returns to when the routine returns.
+    pub return_addr: usize,
+}
 
 /// TAM emulator
+#[allow(clippy::upper_case_acronyms)]
 pub struct TAM {
-    code: [u32; MEM_SIZE],
-    data: [i16; MEM_SIZE],
+    code: Vec<u32>,
+    data: Vec<i16>,
     registers: [usize; 16],
-    trace: bool,
+    entry: usize,
+    static_data: Vec<i16>,
+    labels: HashMap<String, usize>,
+    debug_info: Option<DebugInfo>,
+    io: Box<dyn TamIo>,
+    depth: usize,
+    halted: bool,
+    tracer: Option<Tracer>,
+    strict: bool,
+    limits: Limits,
+    heap: Heap,
+    sanitize: bool,
+    uninit: UninitMode,
+    shadow: Shadow,
+    uninit_reads: Vec<UninitRead>,
+    profile: Option<Profile>,
+    coverage: Option<Coverage>,
+    history: Option<History>,
+    steps: u64,
+    output_len: usize,
 }
 
 impl TAM {
     /// Construct a new TAM emulator.
     ///
     /// # Arguments
-    /// - `trace`: specify if a trace should be printed during execution
+    /// - `trace`: specify if a trace of every instruction should be printed to
+    ///   stdout during execution, as described for [`Tracer`]
     pub fn new(trace: bool) -> TAM {
         let mut tam = TAM {
-            code: [0; MEM_SIZE],
-            data: [0; MEM_SIZE],
+            code: vec![0; MEM_SIZE],
+            data: vec![0; MEM_SIZE],
             registers: [0; 16],
-            trace,
+            entry: 0,
+            static_data: Vec::new(),
+            labels: HashMap::new(),
+            debug_info: None,
+            io: Box::new(StdIo),
+            depth: 0,
+            halted: false,
+            tracer: trace.then(|| {
+                Tracer::new(Box::new(std::io::stdout()), TraceFilter::default())
+            }),
+            strict: false,
+            limits: Limits::default(),
+            heap: Heap::default(),
+            sanitize: false,
+            uninit: UninitMode::Off,
+            shadow: Shadow::new(MEM_SIZE),
+            uninit_reads: Vec::new(),
+            profile: None,
+            coverage: None,
+            history: None,
+            steps: 0,
+            output_len: 0,
         };
 
         tam.registers[PB] = MEM_SIZE - 29;
@@ -46,324 +129,1001 @@ impl TAM {
         tam
     }
 
-    /// Load a program from a file.
+    /// Replace the backend used by the I/O primitives.
+    ///
+    /// By default a program reads from stdin and writes to stdout.
+    pub fn set_io(&mut self, io: Box<dyn TamIo>) {
+        self.io = io;
+    }
+
+    /// Set the tracer that records each instruction executed, or stop tracing.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Enable or disable strict checking.
+    ///
+    /// In strict mode, running past the end of the loaded program is a
+    /// [`TAMError::CodeOutOfBounds`] error rather than executing the empty code store,
+    /// `getint` raises [`TAMError::BadInput`] for input that is not an integer in
+    /// range rather than reading it as 0 or wrapping it, and a routine popping words
+    /// from its own frame links raises [`TAMError::StackUnderflow`].
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Set the limits on the resources a program may use.
+    ///
+    /// A program that exceeds a limit stops with [`TAMError::LimitExceeded`].
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Get the limits on the resources a program may use.
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Set the strategy the allocator uses to place blocks on the heap.
+    ///
+    /// The heap is emptied, so this should be called before running a program.
+    pub fn set_heap_strategy(&mut self, strategy: Strategy) {
+        self.heap = Heap::new(strategy);
+    }
+
+    /// Enable or disable the heap sanitizer.
+    ///
+    /// The sanitizer raises [`TAMError::HeapViolation`] when a program reads or
+    /// writes a heap word outside any live block, disposes of a block twice or with
+    /// the wrong size, disposes of an address that is not a block, or halts with
+    /// blocks still allocated.
+    pub fn set_sanitize(&mut self, sanitize: bool) {
+        self.sanitize = sanitize;
+    }
+
+    /// Set what happens when `load` or `loadi` reads an uninitialized word.
+    ///
+    /// Words reserved with `push` or allocated with `new` are uninitialized until
+    /// they are stored to or read into by `get` or `getint`. In
+    /// [`UninitMode::Report`] each such read is recorded in
+    /// [`uninit_reads`](Self::uninit_reads); in [`UninitMode::Trap`] it raises
+    /// [`TAMError::UninitializedRead`].
+    pub fn set_uninit_mode(&mut self, mode: UninitMode) {
+        self.uninit = mode;
+    }
+
+    /// Get the reads of uninitialized words since the machine was last reset, in
+    /// the order the instructions that made them first did so.
+    pub fn uninit_reads(&self) -> &[UninitRead] {
+        &self.uninit_reads
+    }
+
+    /// Enable or disable profiling.
+    ///
+    /// While profiling, the machine counts the instructions it executes at each
+    /// address and in each procedure, and the opcodes and primitives they use. The
+    /// profile is emptied when the machine is reset.
+    pub fn set_profile(&mut self, profile: bool) {
+        self.profile = profile.then(|| Profile::new(self.registers[CP]));
+    }
+
+    /// Get the profile of the program since the machine was last reset, if
+    /// profiling is enabled.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Enable or disable recording code coverage.
+    ///
+    /// The coverage is emptied when the machine is reset.
+    pub fn set_coverage(&mut self, coverage: bool) {
+        self.coverage = coverage.then(Coverage::default);
+    }
+
+    /// Get the code coverage of the program since the machine was last reset, if
+    /// it is being recorded.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Enable or disable recording the history of the program, so that it can be run
+    /// backwards.
+    ///
+    /// While recording, each step logs the registers before it and the data-store
+    /// words it overwrote, and the whole state of the machine is saved every
+    /// `checkpoint_interval` steps. The oldest steps are forgotten once the history
+    /// exceeds its budget. The history is emptied when the machine is reset.
+    pub fn set_history(&mut self, config: Option<HistoryConfig>) {
+        self.history = config.map(History::new);
+    }
+
+    /// Get the history of the program since the machine was last reset, if it is
+    /// being recorded.
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Undo the last instruction executed, returning `false` if it is not in the
+    /// history.
+    ///
+    /// The registers, data store and heap are restored to their values before the
+    /// instruction, and the step count goes back by one. Input the program has read
+    /// and output it has written are not taken back, nor are its profile, coverage
+    /// or trace.
+    pub fn reverse_step(&mut self) -> bool {
+        match self.history.as_mut().and_then(History::pop) {
+            Some(delta) => {
+                self.undo(delta);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Run the program backwards to the point where it had executed `steps`
+    /// instructions, returning `false` if that point is not in the history.
+    ///
+    /// A long way back, the nearest checkpoint after the point is restored and the
+    /// steps between them are undone, as by [`reverse_step`](Self::reverse_step).
+    pub fn rewind_to(&mut self, steps: u64) -> bool {
+        let Some(history) = &mut self.history else {
+            return false;
+        };
+        match history.earliest() {
+            Some(earliest) if steps >= earliest && steps < self.steps => (),
+            _ => return false,
+        }
+        if let Some(checkpoint) = history.checkpoint_between(steps, self.steps).cloned()
+        {
+            history.truncate(checkpoint.steps);
+            self.restore(checkpoint);
+        }
+        while self.steps > steps {
+            self.reverse_step();
+        }
+        true
+    }
+
+    /// Find the last instruction in the history that wrote to the data word at
+    /// `addr`.
+    pub fn last_write(&self, addr: usize) -> Option<LastWrite> {
+        self.history
+            .as_ref()?
+            .last_write(addr, *self.data.get(addr)?)
+    }
+
+    /// Restore the state of the machine before a step from the history.
+    fn undo(&mut self, delta: Delta) {
+        for (addr, old) in delta.writes.into_iter().rev() {
+            self.data[addr] = old;
+        }
+        for (addr, old) in delta.defined.into_iter().rev() {
+            self.shadow.set(addr..addr + 1, old);
+        }
+        if let Some(heap) = delta.heap {
+            self.heap = *heap;
+        }
+        self.registers = delta.registers;
+        self.depth = delta.depth;
+        self.output_len = delta.output_len;
+        self.steps = delta.step - 1;
+        self.halted = false;
+    }
+
+    /// Restore the state of the machine from a checkpoint in the history.
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.data = checkpoint.data;
+        self.shadow = checkpoint.shadow;
+        self.heap = checkpoint.heap;
+        self.registers = checkpoint.registers;
+        self.depth = checkpoint.depth;
+        self.output_len = checkpoint.output_len;
+        self.steps = checkpoint.steps;
+        self.halted = false;
+    }
+
+    /// Get the allocator that manages the heap.
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Get statistics on the use of the heap since the machine was last reset.
+    pub fn heap_usage(&self) -> HeapUsage {
+        self.heap.usage(self.registers[HT], self.registers[HB])
+    }
+
+    /// Get the number of instructions executed since the machine was last reset.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Flush any output the program has written.
+    pub fn flush_output(&mut self) -> std::io::Result<()> {
+        self.io.flush()
+    }
+
+    /// Load a program from a bytecode container.
+    ///
+    /// The program's initialized data is written to the data store at SB each time
+    /// the machine is reset. This method clears the code store before loading.
+    pub fn load_container(&mut self, container: &Container) -> std::io::Result<()> {
+        if container.entry as usize > container.code.len()
+            || container.data.len() >= self.registers[HB] - self.registers[SB]
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "program entry point or data does not fit in memory",
+            ));
+        }
+
+        self.load_instructions(&container.code)?;
+        self.entry = container.entry as usize;
+        self.static_data = container.data.clone();
+        self.labels = container.code_labels();
+        self.debug_info = container.debug_info();
+        Ok(())
+    }
+
+    /// Get the labels of the loaded program's code addresses.
+    pub fn labels(&self) -> &HashMap<String, usize> {
+        &self.labels
+    }
+
+    /// Get the source positions of the loaded program's instructions, if its
+    /// container has any.
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Load a program from a file of raw instructions.
     ///
     /// This method clears the code store before loading.
     pub fn load_program(&mut self, filename: &str) -> std::io::Result<()> {
         let bytes = std::fs::read(filename)?;
-        let mut bytes = Cursor::new(bytes);
+        self.load_bytes(&bytes)
+    }
 
-        self.code.fill(0);
-        self.registers[CT] = 0;
+    /// Load a program from a sequence of big-endian encoded instructions.
+    ///
+    /// Any trailing bytes that do not make up a whole instruction are ignored. This
+    /// method clears the code store before loading.
+    pub fn load_bytes(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let mut bytes = Cursor::new(bytes);
+        let mut code = Vec::new();
         while let Ok(instr) = bytes.read_u32::<BE>() {
-            self.code[self.registers[CT]] = instr;
-            self.registers[CT] += 1;
+            code.push(instr);
+        }
+        self.load_words(&code)
+    }
+
+    /// Load a program from a sequence of instructions.
+    ///
+    /// This method clears the code store before loading.
+    pub fn load_instructions(&mut self, instrs: &[Instruction]) -> std::io::Result<()> {
+        let code: Vec<u32> = instrs.iter().map(|i| u32::from(*i)).collect();
+        self.load_words(&code)
+    }
+
+    fn load_words(&mut self, code: &[u32]) -> std::io::Result<()> {
+        if code.len() > self.registers[PB] {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "program of {} words does not fit in the code store",
+                    code.len()
+                ),
+            ));
         }
+
+        self.code.fill(0);
+        self.code[..code.len()].copy_from_slice(code);
+        self.registers[CT] = code.len();
+        self.entry = 0;
+        self.static_data.clear();
+        self.labels.clear();
+        self.debug_info = None;
         Ok(())
     }
 
+    /// Reset the machine so the loaded program can be run from the start.
+    ///
+    /// This method clears the data store, writes the program's initialized data at
+    /// SB, and resets the stack and heap registers.
+    pub fn reset(&mut self) {
+        let sb = self.registers[SB];
+        self.data.fill(0);
+        self.data[sb..sb + self.static_data.len()].copy_from_slice(&self.static_data);
+        self.registers[ST] = sb + self.static_data.len();
+        self.registers[LB] = sb;
+        self.registers[HT] = self.registers[HB];
+        self.registers[CP] = self.registers[CB] + self.entry;
+        self.depth = 0;
+        self.halted = false;
+        self.heap.reset();
+        self.shadow.clear();
+        self.shadow.set(sb..self.registers[ST], true);
+        self.uninit_reads.clear();
+        if self.profile.is_some() {
+            self.profile = Some(Profile::new(self.registers[CP]));
+        }
+        if self.coverage.is_some() {
+            self.coverage = Some(Coverage::default());
+        }
+        if let Some(history) = &mut self.history {
+            *history = History::new(history.config());
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.reset(self.registers[CP]);
+        }
+        self.steps = 0;
+        self.output_len = 0;
+    }
+
     /// Run the loaded program.
     ///
-    /// This method clears the data store before running.
+    /// This method resets the machine before running.
     pub fn run(&mut self) -> TAMResult<()> {
-        self.data.fill(0);
-        self.registers[CP] = 0;
-        loop {
-            let instr = self.fetch_decode();
-            if self.trace {
-                println!("{:04x}: {:?}", self.registers[CP] - 1, instr);
-                println!("{:?}", self.data[..self.registers[ST]].to_vec());
-                println!(
-                    "SB[{:x}] LB[{:x}] ST[{:x}]",
-                    self.registers[SB], self.registers[LB], self.registers[ST]
-                );
+        self.reset();
+        while self.step()? == Status::Running {}
+        Ok(())
+    }
+
+    /// Execute at most `n` instructions, stopping early if the program halts.
+    pub fn run_for(&mut self, n: usize) -> TAMResult<Status> {
+        for _ in 0..n {
+            if self.step()? == Status::Halted {
+                return Ok(Status::Halted);
             }
+        }
+        Ok(if self.halted {
+            Status::Halted
+        } else {
+            Status::Running
+        })
+    }
+
+    /// Execute the next instruction.
+    ///
+    /// Once the machine has halted, further calls do nothing until it is reset.
+    pub fn step(&mut self) -> TAMResult<Status> {
+        if self.halted {
+            return Ok(Status::Halted);
+        }
 
-            if instr.op == 15 {
-                return Ok(());
+        if let Some(max) = self.limits.max_steps {
+            if self.steps >= max {
+                let loc = self.registers[CP];
+                self.flush_trace()?;
+                return Err(TAMError::LimitExceeded(loc, Limit::Steps(max)));
+            }
+        }
+        if let Some(history) = &mut self.history {
+            if history.checkpoint_due(self.steps) {
+                history.checkpoint(Checkpoint {
+                    steps: self.steps,
+                    registers: self.registers,
+                    depth: self.depth,
+                    output_len: self.output_len,
+                    data: self.data.clone(),
+                    shadow: self.shadow.clone(),
+                    heap: self.heap.clone(),
+                });
             }
-            self.execute(instr)?;
+            let step = self.steps + 1;
+            history.begin(step, &self.registers, self.depth, self.output_len);
+        }
+        self.steps += 1;
+
+        let status = self.execute_next();
+        if let Some(history) = &mut self.history {
+            history.end();
+        }
+        if status.is_err() {
+            self.flush_trace()?;
         }
+        status
+    }
+
+    /// Flush the trace, so that it is complete when the program stops with a
+    /// fault and the process exits without dropping the machine.
+    fn flush_trace(&mut self) -> TAMResult<()> {
+        let loc = self.instr_addr();
+        match &mut self.tracer {
+            Some(tracer) => tracer.flush().map_err(|e| TAMError::IOError(loc, e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Fetch and execute the next instruction, recording it with the profiler,
+    /// coverage and tracer.
+    fn execute_next(&mut self) -> TAMResult<Status> {
+        let instr = self.fetch_decode()?;
+        let loc = self.instr_addr();
+        if let Some(profile) = &mut self.profile {
+            profile.record(loc, instr.op);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(loc);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            let step = self.steps;
+            tracer.begin(step, loc, instr, &self.registers);
+        }
+
+        let status = if instr.op == Opcode::Halt as u8 {
+            self.exec_halt()
+        } else {
+            self.execute(instr).map(|_| Status::Running)
+        };
+        if let Some(tracer) = &mut self.tracer {
+            let error = status.as_ref().err().map(|e| e.to_string());
+            tracer
+                .end(
+                    &self.registers,
+                    error,
+                    &self.labels,
+                    self.debug_info.as_ref(),
+                )
+                .and_then(|_| if self.halted { tracer.flush() } else { Ok(()) })
+                .map_err(|e| TAMError::IOError(self.instr_addr(), e))?;
+        }
+        status
+    }
+
+    /// Halt the machine, checking for leaks if the sanitizer is enabled.
+    fn exec_halt(&mut self) -> TAMResult<Status> {
+        self.halted = true;
+        self.io
+            .flush()
+            .map_err(|e| TAMError::IOError(self.instr_addr(), e))?;
+        if self.sanitize && self.heap.blocks().next().is_some() {
+            let leaks = self.heap.blocks().copied().collect();
+            return Err(TAMError::HeapViolation(
+                self.instr_addr(),
+                HeapViolation::Leak(leaks),
+            ));
+        }
+        Ok(Status::Halted)
+    }
+
+    /// Check if the machine has executed a `halt` instruction since it was last
+    /// reset.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Get the number of routine frames currently on the stack.
+    pub fn call_depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Get the active routine frames, innermost first, by following the dynamic
+    /// links from LB.
+    pub fn frames(&self) -> Vec<Frame> {
+        let link = |addr: usize| self.data.get(addr).map_or(0, |v| *v as u16 as usize);
+
+        let mut frames = Vec::with_capacity(self.depth);
+        let mut lb = self.registers[LB];
+        for _ in 0..self.depth {
+            let frame = Frame {
+                base: lb,
+                dynamic_link: link(lb + 1),
+                return_addr: link(lb + 2),
+            };
+            frames.push(frame);
+            lb = frame.dynamic_link;
+        }
+        frames
+    }
+
+    /// Get the value of register `r`.
+    ///
+    /// The display registers L1-L6 are computed from the static links in the
+    /// current chain of frames.
+    pub fn register(&self, r: Register) -> usize {
+        self.reg_value(r as usize)
+    }
+
+    /// Set the value of register `r`.
+    pub fn set_register(&mut self, r: Register, val: usize) {
+        self.registers[r as usize] = val;
+    }
+
+    /// Get the contents of the code store.
+    pub fn code(&self) -> &[u32] {
+        &self.code
+    }
+
+    /// Get mutable access to the contents of the code store.
+    pub fn code_mut(&mut self) -> &mut [u32] {
+        &mut self.code
+    }
+
+    /// Get the contents of the data store.
+    pub fn data(&self) -> &[i16] {
+        &self.data
+    }
+
+    /// Get mutable access to the contents of the data store.
+    pub fn data_mut(&mut self) -> &mut [i16] {
+        &mut self.data
     }
 
-    fn fetch_decode(&mut self) -> Instruction {
-        let instr = self.code[self.registers[CP]];
+    /// Fetch the instruction at CP and advance CP past it.
+    pub fn fetch_decode(&mut self) -> TAMResult<Instruction> {
+        let cp = self.registers[CP];
+        let end = if self.strict {
+            self.registers[CT]
+        } else {
+            self.registers[PB]
+        };
+        if cp >= end {
+            return Err(TAMError::CodeOutOfBounds(cp));
+        }
+
         self.registers[CP] += 1;
-        Instruction::from(instr)
+        Ok(Instruction::from(self.code[cp]))
     }
 
-    fn execute(&mut self, instr: Instruction) -> TAMResult<()> {
-        match instr.op {
-            0 => self.exec_load(instr),
-            1 => self.exec_loada(instr),
-            2 => self.exec_loadi(instr),
-            3 => self.exec_loadl(instr),
-            4 => self.exec_store(instr),
-            5 => self.exec_storei(instr),
-            6 => self.exec_call(instr),
-            7 => self.exec_calli(instr),
-            8 => self.exec_return(instr),
-            10 => self.exec_push(instr),
-            11 => self.exec_pop(instr),
-            12 => self.exec_jump(instr),
-            13 => self.exec_jumpi(instr),
-            14 => self.exec_jumpif(instr),
-            _ => Ok(()),
+    /// Execute a single decoded instruction.
+    ///
+    /// `halt` is handled by [`TAM::step`] and is ignored here.
+    pub fn execute(&mut self, instr: Instruction) -> TAMResult<()> {
+        let opcode = Opcode::try_from(instr.op)
+            .map_err(|_| TAMError::InvalidOpcode(self.instr_addr(), instr.op))?;
+        match opcode {
+            Opcode::Load => self.exec_load(instr),
+            Opcode::LoadA => self.exec_loada(instr),
+            Opcode::LoadI => self.exec_loadi(instr),
+            Opcode::LoadL => self.exec_loadl(instr),
+            Opcode::Store => self.exec_store(instr),
+            Opcode::StoreI => self.exec_storei(instr),
+            Opcode::Call => self.exec_call(instr),
+            Opcode::CallI => self.exec_calli(instr),
+            Opcode::Return => self.exec_return(instr),
+            Opcode::Push => self.exec_push(instr),
+            Opcode::Pop => self.exec_pop(instr),
+            Opcode::Jump => self.exec_jump(instr),
+            Opcode::JumpI => self.exec_jumpi(instr),
+            Opcode::JumpIf => self.exec_jumpif(instr),
+            Opcode::Halt => Ok(()),
         }
     }
 
     fn push_data(&mut self, dat: i16) {
-        self.data[self.registers[ST]] = dat;
+        self.write_data(self.registers[ST], dat);
         self.registers[ST] += 1;
     }
 
+    /// Write a word to the data store, marking it as initialized.
+    fn write_data(&mut self, addr: usize, dat: i16) {
+        if let Some(history) = &mut self.history {
+            history.write(addr, self.data[addr]);
+        }
+        self.data[addr] = dat;
+        self.define(addr..addr + 1, true);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.write(addr, dat);
+        }
+    }
+
     fn pop_data(&mut self) -> i16 {
         self.registers[ST] -= 1;
         self.data[self.registers[ST]]
     }
 
+    /// Pop a word and interpret it as an unsigned address.
+    fn pop_addr(&mut self) -> usize {
+        self.pop_data() as u16 as usize
+    }
+
+    /// Pop an `n`-word object, returning its words in address order.
+    fn pop_object(&mut self, n: usize) -> Vec<i16> {
+        let start = self.registers[ST] - n;
+        let obj = self.data[start..self.registers[ST]].to_vec();
+        self.registers[ST] = start;
+        obj
+    }
+
+    fn push_object(&mut self, obj: &[i16]) {
+        for dat in obj {
+            self.push_data(*dat);
+        }
+    }
+
     fn check_addr(&self, addr: usize) -> TAMResult<()> {
-        if addr < self.registers[ST] || addr > self.registers[HT] {
+        if addr < self.registers[ST]
+            || (addr > self.registers[HT] && addr <= self.registers[HB])
+        {
             Ok(())
         } else {
-            Err(TAMError::SegmentationFault(self.registers[CP] - 1, addr))
+            Err(TAMError::SegmentationFault(self.instr_addr(), addr))
+        }
+    }
+
+    /// Check that an instruction or primitive may read or write `addr`.
+    ///
+    /// When the sanitizer is enabled, heap addresses must be in a live block. Freed
+    /// blocks are reported even once they have been returned to the space between
+    /// the stack and the heap.
+    fn check_access(&self, addr: usize) -> TAMResult<()> {
+        if self.sanitize && addr >= self.registers[ST] && addr <= self.registers[HB] {
+            let violation = match self.heap.freed_block(addr) {
+                Some(block) => Some(HeapViolation::UseAfterFree(addr, *block)),
+                None if addr > self.registers[HT]
+                    && self.heap.live_block(addr).is_none() =>
+                {
+                    Some(HeapViolation::Unallocated(addr))
+                }
+                None => None,
+            };
+            if let Some(violation) = violation {
+                return Err(TAMError::HeapViolation(self.instr_addr(), violation));
+            }
+        }
+        self.check_addr(addr)
+    }
+
+    /// Mark the words in `range` as defined or undefined, if they are being tracked.
+    fn define(&mut self, range: std::ops::Range<usize>, defined: bool) {
+        if self.uninit != UninitMode::Off {
+            if let Some(history) = &mut self.history {
+                for addr in range.start..range.end.min(MEM_SIZE) {
+                    history.define(addr, self.shadow.is_defined(addr));
+                }
+            }
+            self.shadow.set(range, defined);
+        }
+    }
+
+    /// Check whether `addr` is initialized before an instruction reads it.
+    fn check_init(&mut self, addr: usize) -> TAMResult<()> {
+        if self.uninit == UninitMode::Off || self.shadow.is_defined(addr) {
+            return Ok(());
         }
+        let loc = self.instr_addr();
+        if self.uninit == UninitMode::Trap {
+            return Err(TAMError::UninitializedRead(loc, addr));
+        }
+        match self.uninit_reads.iter_mut().find(|read| read.loc == loc) {
+            Some(read) => read.count += 1,
+            None => self.uninit_reads.push(UninitRead {
+                loc,
+                addr,
+                count: 1,
+            }),
+        }
+        Ok(())
     }
 
-    fn check_stack(&self) -> TAMResult<()> {
-        if self.registers[ST] < self.registers[HT] {
+    fn check_code_addr(&self, addr: usize) -> TAMResult<()> {
+        if addr < self.registers[CT] {
             Ok(())
         } else {
-            Err(TAMError::StackOverflow(self.registers[CP] - 1))
+            Err(TAMError::SegmentationFault(self.instr_addr(), addr))
         }
     }
 
+    /// Check that `n` words can be popped without going below SB or, in strict mode,
+    /// into the links of the current frame.
+    fn check_pop(&self, n: usize) -> TAMResult<()> {
+        let floor = if self.strict && self.depth > 0 {
+            self.registers[LB] + 3
+        } else {
+            self.registers[SB]
+        };
+        if self.registers[ST] >= floor + n {
+            Ok(())
+        } else {
+            Err(TAMError::StackUnderflow(self.instr_addr()))
+        }
+    }
+
+    /// Check that `n` more words can be pushed without reaching the heap or
+    /// exceeding the stack limit.
+    fn check_push(&self, n: usize) -> TAMResult<()> {
+        if let Some(max) = self.limits.max_stack {
+            if (self.registers[ST] + n).saturating_sub(self.registers[SB]) > max {
+                let loc = self.instr_addr();
+                return Err(TAMError::LimitExceeded(loc, Limit::Stack(max)));
+            }
+        }
+        if self.registers[ST] + n < self.registers[HT] {
+            Ok(())
+        } else {
+            Err(TAMError::StackOverflow(self.instr_addr()))
+        }
+    }
+
+    fn reg_value(&self, r: usize) -> usize {
+        match r {
+            L1..=L6 => {
+                // Ln is found by following n static links from the current frame. A
+                // broken chain resolves to an address that fails bounds checks.
+                let mut addr = self.registers[LB];
+                for _ in LB..r {
+                    addr = self
+                        .data
+                        .get(addr)
+                        .map_or(usize::MAX, |link| *link as u16 as usize);
+                }
+                addr
+            }
+            _ => self.registers.get(r).copied().unwrap_or(usize::MAX),
+        }
+    }
+
+    /// Get the code address of the instruction being executed, which is 0 if an
+    /// instruction is executed directly without being fetched.
+    fn instr_addr(&self) -> usize {
+        self.registers[CP].saturating_sub(1)
+    }
+
     fn get_addr(&self, instr: Instruction) -> usize {
-        self.registers[instr.r as usize].wrapping_add_signed(instr.d as isize)
+        self.reg_value(instr.r as usize)
+            .wrapping_add_signed(instr.d as isize)
+    }
+
+    fn is_primitive(&self, addr: usize) -> bool {
+        addr > self.registers[PB] && addr <= self.registers[PT]
     }
 
     fn exec_load(&mut self, instr: Instruction) -> TAMResult<()> {
-        let mut addr = self.get_addr(instr);
-        for _ in 0..instr.n {
-            self.check_addr(addr)?;
+        let addr = self.get_addr(instr);
+        self.check_push(instr.n as usize)?;
+        for addr in addr..addr.saturating_add(instr.n as usize) {
+            self.check_access(addr)?;
+            self.check_init(addr)?;
             let dat = self.data[addr];
             self.push_data(dat);
-            addr += 1;
         }
-        self.check_stack()
+        Ok(())
     }
 
     fn exec_loada(&mut self, instr: Instruction) -> TAMResult<()> {
         let addr = self.get_addr(instr);
         self.check_addr(addr)?;
+        self.check_push(1)?;
         self.push_data(addr as i16);
-        self.check_stack()
+        Ok(())
     }
 
     fn exec_loadi(&mut self, instr: Instruction) -> TAMResult<()> {
-        let mut addr = self.pop_data() as usize;
-        for _ in 0..instr.n {
-            self.check_addr(addr)?;
+        self.check_pop(1)?;
+        let addr = self.pop_addr();
+        self.check_push(instr.n as usize)?;
+        for addr in addr..addr + instr.n as usize {
+            self.check_access(addr)?;
+            self.check_init(addr)?;
             let dat = self.data[addr];
             self.push_data(dat);
-            addr += 1;
         }
-        self.check_stack()
+        Ok(())
     }
 
     fn exec_loadl(&mut self, instr: Instruction) -> TAMResult<()> {
+        self.check_push(1)?;
         self.push_data(instr.d);
-        self.check_stack()
+        Ok(())
     }
 
     fn exec_store(&mut self, instr: Instruction) -> TAMResult<()> {
-        let mut addr = self.get_addr(instr);
-        for _ in 0..instr.n {
-            self.check_addr(addr)?;
-            let dat = self.pop_data();
-            self.data[addr] = dat;
-            addr += 1;
-        }
-        self.check_stack()
+        let addr = self.get_addr(instr);
+        self.store_object(addr, instr.n as usize)
     }
 
     fn exec_storei(&mut self, instr: Instruction) -> TAMResult<()> {
-        let mut addr = self.pop_data() as usize;
-        for _ in 0..instr.n {
-            self.check_addr(addr)?;
-            let dat = self.pop_data();
-            self.data[addr] = dat;
-            addr += 1;
-        }
-        self.check_stack()
+        self.check_pop(1)?;
+        let addr = self.pop_addr();
+        self.store_object(addr, instr.n as usize)
     }
 
-    fn exec_call(&mut self, instr: Instruction) -> TAMResult<()> {
-        if instr.r as usize == PB && instr.d > 0 && instr.d < 28 {
-            self.exec_call_primitive(instr.d)
-        } else {
-            self.exec_call_nonprimitive(instr)
-        }
-    }
-
-    fn exec_call_primitive(&mut self, off: i16) -> TAMResult<()> {
-        match off {
-            1 => self.call_id(),
-            2 => self.call_not(),
-            3 => self.call_and(),
-            4 => self.call_or(),
-            5 => self.call_inc(),
-            6 => self.call_dec(),
-            7 => self.call_neg(),
-            8 => self.call_add(),
-            9 => self.call_sub(),
-            10 => self.call_mul(),
-            11 => self.call_div()?,
-            12 => self.call_mod()?,
-            13 => self.call_lt(),
-            14 => self.call_le(),
-            15 => self.call_ge(),
-            16 => self.call_gt(),
-            17 => self.call_eq(),
-            18 => self.call_ne(),
-            19 => todo!("implement eol primitive"),
-            20 => todo!("implement eof primitive"),
-            21 => self.call_get()?,
-            22 => self.call_put(),
-            23 => self.call_geteol(),
-            24 => self.call_puteol(),
-            25 => self.call_getint(),
-            26 => self.call_putint(),
-            27 => self.call_new(),
-            _ => (),
+    /// Pop an `n`-word object and write it to the data store starting at `addr`.
+    fn store_object(&mut self, addr: usize, n: usize) -> TAMResult<()> {
+        self.check_pop(n)?;
+        let obj = self.pop_object(n);
+        for (addr, dat) in (addr..addr.saturating_add(n)).zip(obj) {
+            self.check_access(addr)?;
+            self.write_data(addr, dat);
         }
         Ok(())
     }
 
-    fn exec_call_nonprimitive(&mut self, instr: Instruction) -> TAMResult<()> {
+    fn exec_call(&mut self, instr: Instruction) -> TAMResult<()> {
         let addr = self.get_addr(instr);
-        if addr >= self.registers[CT] {
-            return Err(TAMError::SegmentationFault(self.registers[CP] - 1, addr));
+        if instr.r as usize == PB || self.is_primitive(addr) {
+            self.exec_call_primitive(addr.wrapping_sub(self.registers[PB]))
+        } else {
+            let static_link = self.reg_value(instr.n as usize);
+            self.call_routine(addr, static_link as i16)
         }
+    }
 
-        let static_link = self.registers[instr.n as usize];
-        let dynamic_link = self.registers[LB];
-        let ret_addr = self.registers[CP];
-
-        self.push_data(static_link as i16);
-        self.push_data(dynamic_link as i16);
-        self.push_data(ret_addr as i16);
-        self.check_stack()?;
+    fn exec_call_primitive(&mut self, off: usize) -> TAMResult<()> {
+        let prim = i16::try_from(off)
+            .ok()
+            .and_then(|d| Primitive::try_from(d).ok());
+        let Some(prim) = prim else {
+            return Err(TAMError::SegmentationFault(
+                self.instr_addr(),
+                self.registers[PB].wrapping_add(off),
+            ));
+        };
 
-        self.registers[LB] = self.registers[ST] - 3;
-        self.registers[CP] = addr;
+        // eq and ne check their operands once they have popped the size of them
+        let (args, results) = match prim {
+            Primitive::Eq | Primitive::Ne => (1, 1),
+            _ => prim.arity(),
+        };
+        self.check_pop(args)?;
+        self.check_push(results.saturating_sub(args))?;
+        if let Some(profile) = &mut self.profile {
+            profile.record_primitive(prim);
+        }
 
-        if self.trace {
-            println!("          slnk: {:08x}", self.data[self.registers[LB]]);
-            println!("          dlnk: {:08x}", self.data[self.registers[LB] + 1]);
-            println!("          radr: {:08x}", self.data[self.registers[LB] + 2]);
+        match prim {
+            Primitive::Id => self.call_id(),
+            Primitive::Not => self.call_not(),
+            Primitive::And => self.call_and(),
+            Primitive::Or => self.call_or(),
+            Primitive::Inc => self.call_inc(),
+            Primitive::Dec => self.call_dec(),
+            Primitive::Neg => self.call_neg(),
+            Primitive::Add => self.call_add(),
+            Primitive::Sub => self.call_sub(),
+            Primitive::Mul => self.call_mul(),
+            Primitive::Div => self.call_div()?,
+            Primitive::Mod => self.call_mod()?,
+            Primitive::Lt => self.call_lt(),
+            Primitive::Le => self.call_le(),
+            Primitive::Ge => self.call_ge(),
+            Primitive::Gt => self.call_gt(),
+            Primitive::Eq => self.call_eq()?,
+            Primitive::Ne => self.call_ne()?,
+            Primitive::Eol => self.call_eol()?,
+            Primitive::Eof => self.call_eof()?,
+            Primitive::Get => self.call_get()?,
+            Primitive::Put => self.call_put()?,
+            Primitive::GetEol => self.call_geteol()?,
+            Primitive::PutEol => self.call_puteol()?,
+            Primitive::GetInt => self.call_getint()?,
+            Primitive::PutInt => self.call_putint()?,
+            Primitive::New => self.call_new()?,
+            Primitive::Dispose => self.call_dispose()?,
         }
         Ok(())
     }
 
-    fn exec_calli(&mut self, _: Instruction) -> TAMResult<()> {
-        let addr = self.pop_data() as usize;
-        if addr >= self.registers[CT] {
-            return Err(TAMError::SegmentationFault(self.registers[CP] - 1, addr));
-        }
+    /// Push a new frame and transfer control to the routine at `addr`.
+    fn call_routine(&mut self, addr: usize, static_link: i16) -> TAMResult<()> {
+        self.check_code_addr(addr)?;
+        self.check_push(3)?;
 
-        let static_link = self.pop_data();
         let dynamic_link = self.registers[LB];
         let ret_addr = self.registers[CP];
 
-        self.push_data(static_link as i16);
+        self.push_data(static_link);
         self.push_data(dynamic_link as i16);
         self.push_data(ret_addr as i16);
-        self.check_stack()?;
 
         self.registers[LB] = self.registers[ST] - 3;
         self.registers[CP] = addr;
-
-        if self.trace {
-            println!("          slnk: {:08x}", self.data[self.registers[LB]]);
-            println!("          dlnk: {:08x}", self.data[self.registers[LB] + 1]);
-            println!("          radr: {:08x}", self.data[self.registers[LB] + 2]);
+        self.depth += 1;
+        if let Some(profile) = &mut self.profile {
+            profile.enter(addr);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.enter(addr);
         }
         Ok(())
     }
 
-    fn exec_return(&mut self, instr: Instruction) -> TAMResult<()> {
-        let ret_addr = self.data[self.registers[LB] + 2] as usize;
-        if ret_addr >= self.registers[CT] {
-            return Err(TAMError::SegmentationFault(
-                self.registers[CP] - 1,
-                ret_addr,
-            ));
+    fn exec_calli(&mut self, _: Instruction) -> TAMResult<()> {
+        self.check_pop(2)?;
+        let addr = self.pop_addr();
+        let static_link = self.pop_data();
+        if self.is_primitive(addr) {
+            self.exec_call_primitive(addr - self.registers[PB])
+        } else {
+            self.call_routine(addr, static_link)
         }
+    }
 
-        let mut ret_val = Vec::new();
-        for _ in 0..instr.n {
-            ret_val.push(self.pop_data());
-        }
+    fn exec_return(&mut self, instr: Instruction) -> TAMResult<()> {
+        let lb = self.registers[LB];
+        let link = |addr: usize| self.data.get(addr).map(|v| *v as u16 as usize);
+        let (Some(dynamic_link), Some(ret_addr)) = (link(lb + 1), link(lb + 2)) else {
+            return Err(TAMError::SegmentationFault(self.instr_addr(), lb + 2));
+        };
+        self.check_code_addr(ret_addr)?;
 
-        while self.registers[ST] > self.registers[LB] {
-            self.pop_data();
+        let n = instr.n as usize;
+        let d = instr.d as u16 as usize;
+        self.check_pop(n)?;
+        if lb < self.registers[SB] + d {
+            return Err(TAMError::StackUnderflow(self.instr_addr()));
         }
 
-        for _ in 0..instr.d {
-            self.pop_data();
+        // A routine may have overwritten its dynamic link, so it must still point
+        // into the stack, and the result must fit where the frame was.
+        let st = self.registers[ST];
+        if dynamic_link < self.registers[SB] || dynamic_link > st {
+            return Err(TAMError::SegmentationFault(self.instr_addr(), lb + 1));
         }
+        self.registers[ST] = lb - d;
+        let fits = self.check_push(n);
+        self.registers[ST] = st;
+        fits?;
 
-        while let Some(val) = ret_val.pop() {
-            self.push_data(val);
-        }
+        let ret_val = self.pop_object(n);
+        self.registers[ST] = lb - d;
+        self.push_object(&ret_val);
 
         self.registers[CP] = ret_addr;
-        self.registers[LB] = self.data[self.registers[LB] + 1] as usize;
+        self.registers[LB] = dynamic_link;
+        self.depth = self.depth.saturating_sub(1);
+        if let Some(profile) = &mut self.profile {
+            profile.leave();
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.leave();
+        }
         Ok(())
     }
 
     fn exec_push(&mut self, instr: Instruction) -> TAMResult<()> {
-        self.registers[ST] += instr.d as usize;
-        self.check_stack()
+        let n = instr.d as u16 as usize;
+        self.check_push(n)?;
+        let st = self.registers[ST];
+        self.define(st..st + n, false);
+        self.registers[ST] += n;
+        Ok(())
     }
 
-    fn exec_pop(&mut self, _instr: Instruction) -> TAMResult<()> {
-        todo!("pop not yet implemented");
+    fn exec_pop(&mut self, instr: Instruction) -> TAMResult<()> {
+        let n = instr.n as usize;
+        let d = instr.d as u16 as usize;
+        self.check_pop(n + d)?;
+        let ret_val = self.pop_object(n);
+        self.registers[ST] -= d;
+        self.push_object(&ret_val);
+        Ok(())
     }
 
     fn exec_jump(&mut self, instr: Instruction) -> TAMResult<()> {
         let addr = self.get_addr(instr);
-        if addr >= self.registers[CT] {
-            return Err(TAMError::SegmentationFault(self.registers[CP] - 1, addr));
-        }
-
+        self.check_code_addr(addr)?;
         self.registers[CP] = addr;
         Ok(())
     }
 
     fn exec_jumpi(&mut self, _: Instruction) -> TAMResult<()> {
-        let addr = self.pop_data() as usize;
-        if addr >= self.registers[CT] {
-            Err(TAMError::SegmentationFault(self.registers[CP] - 1, addr))
-        } else {
-            self.registers[CP] = addr;
-            Ok(())
-        }
+        self.check_pop(1)?;
+        let addr = self.pop_addr();
+        self.check_code_addr(addr)?;
+        self.registers[CP] = addr;
+        Ok(())
     }
 
     fn exec_jumpif(&mut self, instr: Instruction) -> TAMResult<()> {
+        self.check_pop(1)?;
         let val = self.pop_data();
+        let loc = self.instr_addr();
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record_branch(loc, val == instr.n as i16);
+        }
         if val == instr.n as i16 {
             let addr = self.get_addr(instr);
-            if addr >= self.registers[CT] {
-                return Err(TAMError::SegmentationFault(self.registers[CP] - 1, addr));
-            }
+            self.check_code_addr(addr)?;
             self.registers[CP] = addr;
         }
         Ok(())
     }
 
+    fn push_bool(&mut self, b: bool) {
+        self.push_data(if b { 1 } else { 0 });
+    }
+
     fn call_id(&mut self) {
         let val = self.pop_data();
         self.push_data(val);
@@ -371,160 +1131,312 @@ impl TAM {
 
     fn call_not(&mut self) {
         let val = self.pop_data();
-        self.push_data(if val == 0 { 1 } else { 0 });
+        self.push_bool(val == 0);
     }
 
     fn call_and(&mut self) {
         let t2 = self.pop_data();
         let t1 = self.pop_data();
-        self.push_data(if t1 * t2 == 0 { 0 } else { 1 });
+        self.push_bool(t1 != 0 && t2 != 0);
     }
 
     fn call_or(&mut self) {
         let t2 = self.pop_data();
         let t1 = self.pop_data();
-        self.push_data(if t1 + t2 == 0 { 0 } else { 1 });
+        self.push_bool(t1 != 0 || t2 != 0);
     }
 
     fn call_inc(&mut self) {
         let val = self.pop_data();
-        self.push_data(val.overflowing_add(1).0);
+        self.push_data(val.wrapping_add(1));
     }
 
     fn call_dec(&mut self) {
         let val = self.pop_data();
-        self.push_data(val.overflowing_sub(1).0);
+        self.push_data(val.wrapping_sub(1));
     }
 
     fn call_neg(&mut self) {
-        let val = self.pop_data() as i16;
-        self.push_data(-val);
+        let val = self.pop_data();
+        self.push_data(val.wrapping_neg());
     }
 
     fn call_add(&mut self) {
         let t2 = self.pop_data();
         let t1 = self.pop_data();
-        self.push_data(t1.overflowing_add(t2).0);
+        self.push_data(t1.wrapping_add(t2));
     }
 
     fn call_sub(&mut self) {
         let t2 = self.pop_data();
         let t1 = self.pop_data();
-        self.push_data(t1.overflowing_sub(t2).0);
+        self.push_data(t1.wrapping_sub(t2));
     }
 
     fn call_mul(&mut self) {
         let t2 = self.pop_data();
         let t1 = self.pop_data();
-        self.push_data(t1.overflowing_mul(t2).0);
+        self.push_data(t1.wrapping_mul(t2));
     }
 
     fn call_div(&mut self) -> TAMResult<()> {
         let t2 = self.pop_data();
         if t2 == 0 {
-            return Err(TAMError::DivideByZero(self.registers[CP] - 1));
+            return Err(TAMError::DivideByZero(self.instr_addr()));
         }
 
         let t1 = self.pop_data();
-        self.push_data(t1.overflowing_div(t2).0);
+        self.push_data(t1.wrapping_div(t2));
         Ok(())
     }
 
     fn call_mod(&mut self) -> TAMResult<()> {
         let t2 = self.pop_data();
         if t2 == 0 {
-            return Err(TAMError::DivideByZero(self.registers[CP] - 1));
+            return Err(TAMError::DivideByZero(self.instr_addr()));
         }
 
         let t1 = self.pop_data();
-        self.push_data(t1 % t2);
+        self.push_data(t1.wrapping_rem(t2));
         Ok(())
     }
 
     fn call_lt(&mut self) {
         let t2 = self.pop_data();
         let t1 = self.pop_data();
-        self.push_data(if t1 < t2 { 1 } else { 0 });
+        self.push_bool(t1 < t2);
     }
 
     fn call_le(&mut self) {
         let t2 = self.pop_data();
         let t1 = self.pop_data();
-        self.push_data(if t1 <= t2 { 1 } else { 0 });
+        self.push_bool(t1 <= t2);
     }
 
     fn call_ge(&mut self) {
         let t2 = self.pop_data();
         let t1 = self.pop_data();
-        self.push_data(if t1 >= t2 { 1 } else { 0 });
+        self.push_bool(t1 >= t2);
     }
 
     fn call_gt(&mut self) {
         let t2 = self.pop_data();
         let t1 = self.pop_data();
-        self.push_data(if t1 > t2 { 1 } else { 0 });
+        self.push_bool(t1 > t2);
+    }
+
+    fn call_eq(&mut self) -> TAMResult<()> {
+        let n = self.pop_data() as u16 as usize;
+        self.check_pop(2 * n)?;
+        let t2 = self.pop_object(n);
+        let t1 = self.pop_object(n);
+        self.push_bool(t1 == t2);
+        Ok(())
+    }
+
+    fn call_ne(&mut self) -> TAMResult<()> {
+        let n = self.pop_data() as u16 as usize;
+        self.check_pop(2 * n)?;
+        let t2 = self.pop_object(n);
+        let t1 = self.pop_object(n);
+        self.push_bool(t1 != t2);
+        Ok(())
+    }
+
+    fn io_error(&self, e: std::io::Error) -> TAMError {
+        TAMError::IOError(self.instr_addr(), e)
+    }
+
+    fn write_output(&mut self, s: &str) -> TAMResult<()> {
+        if let Some(max) = self.limits.max_output {
+            if self.output_len + s.len() > max {
+                let loc = self.instr_addr();
+                return Err(TAMError::LimitExceeded(loc, Limit::Output(max)));
+            }
+        }
+        self.output_len += s.len();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.output(s);
+        }
+        self.io.write_str(s).map_err(|e| self.io_error(e))
+    }
+
+    fn peek_input(&mut self) -> TAMResult<Option<u8>> {
+        self.io.peek_byte().map_err(|e| self.io_error(e))
+    }
+
+    fn read_input(&mut self) -> TAMResult<Option<u8>> {
+        let byte = self.io.read_byte().map_err(|e| self.io_error(e))?;
+        if let (Some(tracer), Some(byte)) = (&mut self.tracer, byte) {
+            tracer.input(byte);
+        }
+        Ok(byte)
     }
 
-    fn call_getint(&mut self) {
-        let mut buffer = String::new();
-        std::io::stdin().read_line(&mut buffer).unwrap();
-        let input = i16::from_str(buffer.trim()).unwrap();
-        let addr = self.pop_data() as usize;
-        self.data[addr] = input;
+    fn call_eol(&mut self) -> TAMResult<()> {
+        let next = self.peek_input()?;
+        self.push_bool(next == Some(b'\n'));
+        Ok(())
+    }
+
+    fn call_eof(&mut self) -> TAMResult<()> {
+        let next = self.peek_input()?;
+        self.push_bool(next.is_none());
+        Ok(())
+    }
+
+    /// Read an optionally signed decimal integer, skipping any leading whitespace.
+    ///
+    /// The character following the integer is not consumed. Input with no digits
+    /// reads as 0 and integers out of range wrap around, unless the machine is in
+    /// strict mode.
+    fn read_int(&mut self) -> TAMResult<i16> {
+        while self.peek_input()?.is_some_and(|c| c.is_ascii_whitespace()) {
+            self.read_input()?;
+        }
+
+        let mut sign = 1;
+        while let Some(c @ (b'-' | b'+')) = self.peek_input()? {
+            sign = if c == b'-' { -1 } else { 1 };
+            self.read_input()?;
+        }
+
+        let mut val: i16 = 0;
+        let mut exact: i32 = 0;
+        let mut digits = 0;
+        while let Some(c @ b'0'..=b'9') = self.peek_input()? {
+            val = val.wrapping_mul(10).wrapping_add((c - b'0') as i16);
+            exact = exact.saturating_mul(10).saturating_add((c - b'0') as i32);
+            digits += 1;
+            self.read_input()?;
+        }
+
+        if self.strict {
+            let loc = self.instr_addr();
+            if digits == 0 {
+                let found = match self.peek_input()? {
+                    Some(c) => format!("{:?}", c as char),
+                    None => String::from("end of input"),
+                };
+                return Err(TAMError::BadInput(
+                    loc,
+                    format!("expected an integer, found {found}"),
+                ));
+            }
+            if i16::try_from(exact * sign as i32).is_err() {
+                return Err(TAMError::BadInput(
+                    loc,
+                    String::from("integer is out of range"),
+                ));
+            }
+        }
+        Ok(val.wrapping_mul(sign))
+    }
+
+    fn call_getint(&mut self) -> TAMResult<()> {
+        let addr = self.pop_addr();
+        self.check_access(addr)?;
+        let input = self.read_int()?;
+        self.write_data(addr, input);
+        Ok(())
     }
 
-    fn call_putint(&mut self) {
+    fn call_putint(&mut self) -> TAMResult<()> {
         let val = self.pop_data();
-        print!("{}", val);
+        self.write_output(&val.to_string())
     }
 
-    fn call_puteol(&self) {
-        print!("\n");
+    fn call_puteol(&mut self) -> TAMResult<()> {
+        self.write_output("\n")
     }
 
     fn call_get(&mut self) -> TAMResult<()> {
-        let mut input = [0u8; 1];
-        std::io::stdin().read(&mut input[..]).unwrap();
-        let addr = self.pop_data() as usize;
-        self.check_addr(addr)?;
-        self.data[addr] = input[0] as i16;
+        let addr = self.pop_addr();
+        self.check_access(addr)?;
+        let input = self.read_input()?;
+        self.write_data(addr, input.map_or(-1, |c| c as i16));
         Ok(())
     }
 
-    fn call_put(&mut self) {
+    fn call_put(&mut self) -> TAMResult<()> {
         let c = self.pop_data() as u8;
-        print!("{}", c as char);
+        self.write_output(&(c as char).to_string())
     }
 
-    fn call_geteol(&self) {
-        let mut buf = String::new();
-        std::io::stdin().read_line(&mut buf).unwrap();
+    fn call_geteol(&mut self) -> TAMResult<()> {
+        while let Some(c) = self.read_input()? {
+            if c == b'\n' {
+                break;
+            }
+        }
+        Ok(())
     }
 
-    fn call_new(&mut self) {
-        let n = self.pop_data() as usize;
-        self.registers[HT] -= n;
-        self.push_data((self.registers[HT] + 1) as i16);
+    fn call_new(&mut self) -> TAMResult<()> {
+        let n = self.pop_data() as u16 as usize;
+        let loc = self.instr_addr();
+        // Check the limit first, so a refused block is never recorded as allocated
+        if let Some(max) = self.limits.max_heap {
+            let size = self.registers[HB] - self.registers[HT] + self.heap.growth(n);
+            if size > max {
+                return Err(TAMError::LimitExceeded(loc, Limit::Heap(max)));
+            }
+        }
+        if let Some(history) = &mut self.history {
+            history.save_heap(&self.heap);
+        }
+        let place = self
+            .heap
+            .allocate(n, loc, self.registers[HT], self.registers[ST])
+            .ok_or(TAMError::HeapExhausted(loc, n))?;
+
+        self.write_data(place.header, n as i16);
+        self.define(place.addr..place.addr + n, false);
+        self.registers[HT] = place.ht;
+        self.push_data(place.addr as i16);
+        Ok(())
     }
 
-    fn call_eq(&mut self) {
-        let t2 = self.pop_data();
-        let t1 = self.pop_data();
-        self.push_data(if t1 == t2 { 1 } else { 0 });
-    }
+    fn call_dispose(&mut self) -> TAMResult<()> {
+        let addr = self.pop_addr();
+        let n = self.pop_data() as u16 as usize;
+        let loc = self.instr_addr();
+        if self.sanitize {
+            if let Some(block) = self.heap.live_block(addr) {
+                if block.addr == addr && block.size != n {
+                    let violation = HeapViolation::WrongSize(*block, n);
+                    return Err(TAMError::HeapViolation(loc, violation));
+                }
+            }
+        }
 
-    fn call_ne(&mut self) {
-        let t2 = self.pop_data();
-        let t1 = self.pop_data();
-        self.push_data(if t1 != t2 { 1 } else { 0 });
+        if let Some(history) = &mut self.history {
+            history.save_heap(&self.heap);
+        }
+        match self.heap.free(addr, loc, self.registers[HT]) {
+            Some((_, ht)) => {
+                self.registers[HT] = ht;
+                Ok(())
+            }
+            None if self.sanitize => {
+                let violation = match self.heap.freed_block(addr) {
+                    Some(block) if block.addr == addr => {
+                        HeapViolation::DoubleFree(*block)
+                    }
+                    _ => HeapViolation::InvalidFree(addr),
+                };
+                Err(TAMError::HeapViolation(loc, violation))
+            }
+            None => Err(TAMError::SegmentationFault(loc, addr)),
+        }
     }
 }
-
 #[cfg(test)]
 mod tests {
     use rstest::*;
 
     use super::*;
+    use crate::io::BufferIo;
 
     #[fixture]
     fn tam() -> TAM {
@@ -541,7 +1453,7 @@ mod tests {
         };
         tam.code[0] = u32::from(inst);
 
-        let res = tam.fetch_decode();
+        let res = tam.fetch_decode().unwrap();
 
         assert_eq!(1, tam.registers[CP]);
         assert_eq!(inst, res);
@@ -569,15 +1481,8 @@ mod tests {
 
         match res {
             Ok(_) => panic!("should not have succeeded"),
-            Err(e) => match e {
-                TAMError::StackOverflow(_) => assert!(true),
-                TAMError::SegmentationFault(_, _) => {
-                    panic!("expected stack overflow, got segfault")
-                }
-                TAMError::DivideByZero(_) => {
-                    panic!("expected stack overflow, got divide by 0")
-                }
-            },
+            Err(TAMError::StackOverflow(_)) => (),
+            Err(e) => panic!("expected stack overflow, got {}", e),
         }
     }
 
@@ -614,9 +1519,7 @@ mod tests {
         match res {
             Ok(_) => panic!("should not have succeeded"),
             Err(e) => {
-                if let TAMError::SegmentationFault(_, _) = e {
-                    assert!(true);
-                } else {
+                if !matches!(e, TAMError::SegmentationFault(_, _)) {
                     panic!("expected a segmentation fault");
                 }
             }
@@ -655,9 +1558,7 @@ mod tests {
         match result {
             Ok(_) => panic!("should not have succeeded"),
             Err(e) => {
-                if let TAMError::SegmentationFault(_, _) = e {
-                    assert!(true);
-                } else {
+                if !matches!(e, TAMError::SegmentationFault(_, _)) {
                     panic!("expected a segfault");
                 }
             }
@@ -694,4 +1595,157 @@ mod tests {
         assert!(res.is_ok());
         assert_eq!(2, tam.registers[ST]);
     }
+
+    #[rstest]
+    fn load_bytes(mut tam: TAM) {
+        let bytes = [0x30, 0x00, 0x00, 0x05, 0xf0, 0x00, 0x00, 0x00, 0xff];
+
+        let res = tam.load_bytes(&bytes);
+
+        assert!(res.is_ok());
+        assert_eq!(2, tam.register(Register::CT));
+        assert_eq!(0x30000005, tam.code()[0]);
+        assert_eq!(0xf0000000, tam.code()[1]);
+    }
+
+    #[rstest]
+    fn step_and_halt(mut tam: TAM) {
+        let prog = [
+            Instruction {
+                op: 3,
+                r: 0,
+                n: 0,
+                d: 7,
+            },
+            Instruction {
+                op: 15,
+                r: 0,
+                n: 0,
+                d: 0,
+            },
+        ];
+        tam.load_instructions(&prog).unwrap();
+        tam.reset();
+
+        assert!(matches!(tam.step(), Ok(Status::Running)));
+        assert_eq!(7, tam.data()[0]);
+        assert_eq!(1, tam.register(Register::ST));
+        assert!(matches!(tam.step(), Ok(Status::Halted)));
+        assert!(tam.is_halted());
+        assert!(matches!(tam.step(), Ok(Status::Halted)));
+        assert_eq!(2, tam.register(Register::CP));
+    }
+
+    #[rstest]
+    fn execute_fault_after_reset(mut tam: TAM) {
+        tam.reset();
+        let pop = Instruction {
+            op: 11,
+            r: 0,
+            n: 0,
+            d: 1,
+        };
+
+        let res = tam.execute(pop);
+
+        assert!(matches!(res, Err(TAMError::StackUnderflow(0))));
+        tam.set_register(Register::CP, 3);
+        assert_eq!(3, tam.register(Register::CP));
+    }
+
+    #[rstest]
+    fn run_for_stops_after_n(mut tam: TAM) {
+        let jump = Instruction {
+            op: 12,
+            r: 0,
+            n: 0,
+            d: 0,
+        };
+        tam.load_instructions(&[jump]).unwrap();
+        tam.reset();
+
+        let res = tam.run_for(10);
+
+        assert!(matches!(res, Ok(Status::Running)));
+        assert_eq!(0, tam.register(Register::CP));
+    }
+
+    #[rstest]
+    fn io_uses_backend(mut tam: TAM) {
+        let io = BufferIo::new("-12\n");
+        tam.set_io(Box::new(io.clone()));
+        let prog = [
+            Instruction {
+                op: 10,
+                r: 0,
+                n: 0,
+                d: 1,
+            },
+            Instruction {
+                op: 1,
+                r: 4,
+                n: 0,
+                d: 0,
+            },
+            Instruction {
+                op: 6,
+                r: 2,
+                n: 0,
+                d: 25,
+            },
+            Instruction {
+                op: 0,
+                r: 4,
+                n: 1,
+                d: 0,
+            },
+            Instruction {
+                op: 6,
+                r: 2,
+                n: 0,
+                d: 26,
+            },
+            Instruction {
+                op: 6,
+                r: 2,
+                n: 0,
+                d: 24,
+            },
+            Instruction {
+                op: 15,
+                r: 0,
+                n: 0,
+                d: 0,
+            },
+        ];
+        tam.load_instructions(&prog).unwrap();
+
+        let res = tam.run();
+
+        assert!(res.is_ok());
+        assert_eq!("-12\n", io.output());
+    }
+
+    #[rstest]
+    fn load_container(mut tam: TAM) {
+        let halt = Instruction {
+            op: 15,
+            r: 0,
+            n: 0,
+            d: 0,
+        };
+        let container = Container {
+            entry: 1,
+            code: vec![halt, halt],
+            data: vec![4, 5],
+            ..Container::default()
+        };
+        tam.load_container(&container).unwrap();
+
+        tam.reset();
+
+        assert_eq!(1, tam.register(Register::CP));
+        assert_eq!(2, tam.register(Register::ST));
+        assert_eq!(&[4, 5], &tam.data()[..2]);
+    }
 }
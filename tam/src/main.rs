@@ -1,50 +1,422 @@
-mod errors;
-mod machine;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::TcpListener,
+    ops::Range,
+    process::exit,
+};
 
-use std::fs::File;
-
-use byteorder::{ReadBytesExt, BE};
-use clap::Parser;
-use common::instruction::Instruction;
-
-use crate::machine::TAM;
+use clap::{Parser, Subcommand};
+use common::{
+    container::{Container, SymbolKind},
+    instruction::{Instruction, Opcode, Register},
+};
+use tam::{
+    dap::DapServer,
+    debugger::Debugger,
+    gdb::{GdbStub, ThreadedInput},
+    heap::Strategy,
+    history::HistoryConfig,
+    io::StreamIo,
+    limits::Limits,
+    profile::ProfileReport,
+    report::FaultReport,
+    shadow::UninitMode,
+    trace::{parse_range, TraceFilter, Tracer},
+    TAM,
+};
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Bytecode file to load and run
-    bytecode: String,
+    #[arg(required = true)]
+    bytecode: Option<String>,
 
     /// Print the disassembly of the given code instead of running it
     #[arg(short, long)]
     disassemble: bool,
 
-    /// Print each instruction before executing them
+    /// Write a trace of each instruction executed to stderr as JSON Lines
     #[arg(short, long)]
     trace: bool,
+
+    /// Write the trace to this file instead of stderr
+    #[arg(long, value_name = "FILE")]
+    trace_file: Option<String>,
+
+    /// Only trace the instructions at these code addresses, such as 0x10..0x20
+    #[arg(long, value_name = "RANGE", value_parser = parse_range)]
+    trace_addr: Vec<Range<u64>>,
+
+    /// Only trace the instructions of this procedure, given by label or address
+    #[arg(long, value_name = "PROC")]
+    trace_proc: Vec<String>,
+
+    /// Only trace these steps, counting the first instruction as step 1
+    #[arg(long, value_name = "RANGE", value_parser = parse_range)]
+    trace_steps: Vec<Range<u64>>,
+
+    /// Load a legacy bytecode file of bare instructions with no header
+    #[arg(long)]
+    raw: bool,
+
+    /// Treat running past the end of the code and malformed input as errors
+    #[arg(long)]
+    strict: bool,
+
+    /// Stop the program after it executes this many instructions
+    #[arg(long, value_name = "N")]
+    max_steps: Option<u64>,
+
+    /// Stop the program if it uses more than this many words of stack
+    #[arg(long, value_name = "WORDS")]
+    max_stack: Option<usize>,
+
+    /// Stop the program if it allocates more than this many words of heap
+    #[arg(long, value_name = "WORDS")]
+    max_heap: Option<usize>,
+
+    /// Stop the program if it writes more than this many bytes of output
+    #[arg(long, value_name = "BYTES")]
+    max_output: Option<usize>,
+
+    /// Place heap blocks with this strategy: bump, first-fit or best-fit
+    #[arg(long, value_name = "STRATEGY", default_value_t = Strategy::FirstFit)]
+    heap_strategy: Strategy,
+
+    /// Report uses of freed or unallocated heap memory, bad disposes and leaks
+    #[arg(long)]
+    sanitize_heap: bool,
+
+    /// Check for reads of uninitialized memory: off, report or trap
+    #[arg(long, value_name = "MODE", default_value_t = UninitMode::Off)]
+    uninit: UninitMode,
+
+    /// Print a profile of the instructions executed when the program stops
+    #[arg(long)]
+    profile: bool,
+
+    /// Write the profile's call stacks to this file in collapsed format, for use
+    /// with flame graph tools
+    #[arg(long, value_name = "FILE")]
+    profile_stacks: Option<String>,
+
+    /// Write the lines and branches of the source the program executed to this
+    /// file in lcov format
+    #[arg(long, value_name = "FILE")]
+    coverage: Option<String>,
+
+    /// Serve the GDB remote protocol on this TCP address, or on stdin and stdout if
+    /// it is `-`, and let a debugger run the program
+    #[arg(long, value_name = "ADDR")]
+    gdb: Option<String>,
+
+    /// Read program input from this file instead of stdin
+    #[arg(short, long)]
+    input: Option<String>,
+
+    /// Write program output to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a program under the interactive debugger
+    Debug {
+        /// Bytecode file to debug
+        bytecode: String,
+
+        /// Read program input from this file instead of stdin
+        #[arg(short, long)]
+        input: Option<String>,
+
+        /// Load a legacy bytecode file of bare instructions with no header
+        #[arg(long)]
+        raw: bool,
+
+        /// Keep at most about this many bytes of history for running backwards
+        #[arg(
+            long,
+            value_name = "BYTES",
+            default_value_t = HistoryConfig::default().budget
+        )]
+        history_budget: usize,
+    },
+
+    /// Serve the Debug Adapter Protocol on stdin and stdout, so that an editor can
+    /// debug programs
+    Dap,
 }
 
 fn main() -> std::io::Result<()> {
     let args = Args::parse();
+    if let Some(Command::Debug {
+        bytecode,
+        input,
+        raw,
+        history_budget,
+    }) = &args.command
+    {
+        return debug(bytecode, input.as_deref(), *raw, *history_budget);
+    }
+    if let Some(Command::Dap) = &args.command {
+        let mut input = ThreadedInput::new(std::io::stdin());
+        return DapServer::new(std::io::stdout()).serve(&mut input);
+    }
+
+    let container =
+        read_container(args.bytecode.as_deref().unwrap_or_default(), args.raw)?;
     if args.disassemble {
-        return disassemble(&args.bytecode);
+        disassemble(&container);
+        return Ok(());
+    }
+
+    let debug_info = container.debug_info();
+    if args.coverage.is_some() && debug_info.is_none() {
+        eprintln!(
+            "error: {} has no source line information for coverage",
+            args.bytecode.as_deref().unwrap_or_default()
+        );
+        eprintln!("note: reassemble it with tasc, without --raw");
+        exit(1);
     }
 
-    let mut tam = TAM::new(args.trace);
-    tam.load_program(&args.bytecode)?;
-    if let Err(e) = tam.run() {
-        println!("{}", e);
+    let mut tam = TAM::new(false);
+    tam.load_container(&container)?;
+    tam.set_strict(args.strict);
+    tam.set_heap_strategy(args.heap_strategy);
+    tam.set_sanitize(args.sanitize_heap);
+    tam.set_uninit_mode(args.uninit);
+    tam.set_profile(args.profile || args.profile_stacks.is_some());
+    tam.set_coverage(args.coverage.is_some());
+    let trace = args.trace
+        || args.trace_file.is_some()
+        || !args.trace_addr.is_empty()
+        || !args.trace_proc.is_empty()
+        || !args.trace_steps.is_empty();
+    if trace {
+        let out: Box<dyn Write> = match &args.trace_file {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(std::io::stderr()),
+        };
+        let filter = trace_filter(&args, &container);
+        tam.set_tracer(Some(Tracer::new(out, filter)));
+    }
+    tam.set_limits(Limits {
+        max_steps: args.max_steps,
+        max_stack: args.max_stack,
+        max_heap: args.max_heap,
+        max_output: args.max_output,
+    });
+
+    // A debugger attached through a pipe has stdin and stdout to itself.
+    let pipe = args.gdb.as_deref() == Some("-");
+    if args.input.is_some() || args.output.is_some() || pipe {
+        let input: Box<dyn BufRead> = match &args.input {
+            Some(path) => Box::new(BufReader::new(File::open(path)?)),
+            None if pipe => Box::new(std::io::empty()),
+            None => Box::new(std::io::stdin().lock()),
+        };
+        let output: Box<dyn Write> = match &args.output {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None if pipe => Box::new(std::io::stderr()),
+            None => Box::new(std::io::stdout()),
+        };
+        tam.set_io(Box::new(StreamIo::new(input, output)));
+    }
+    if let Some(addr) = &args.gdb {
+        return serve_gdb(tam, addr);
+    }
+
+    let result = tam.run();
+    tam.flush_output()?;
+    for read in tam.uninit_reads() {
+        eprintln!("warning: {read}");
+    }
+    let symbols = container.code_labels();
+    if let Some(profile) = tam.profile() {
+        if args.profile {
+            eprint!("{}", ProfileReport::new(profile, tam.code(), &symbols));
+        }
+        if let Some(path) = &args.profile_stacks {
+            let mut out = BufWriter::new(File::create(path)?);
+            profile.write_collapsed(&mut out, &symbols)?;
+        }
+    }
+    if let (Some(coverage), Some(path), Some(debug)) =
+        (tam.coverage(), &args.coverage, &debug_info)
+    {
+        let mut out = BufWriter::new(File::create(path)?);
+        coverage.write_lcov(&mut out, &container.code, debug)?;
+    }
+    if let Err(e) = result {
+        let program = args.bytecode.as_deref().unwrap_or_default();
+        eprint!(
+            "{}",
+            FaultReport::new(&tam, &e, &symbols).with_program(program)
+        );
+        exit(e.exit_code());
+    }
+    // Programs that never use the heap have nothing to report.
+    let usage = tam.heap_usage();
+    if usage.allocations > 0 {
+        eprint!("{usage}");
     }
     Ok(())
 }
 
-fn disassemble(filename: &str) -> std::io::Result<()> {
-    let mut f = File::open(filename)?;
-    let mut addr = 0;
+/// Let a debugger run the program over the GDB remote protocol, on a TCP address
+/// or on stdin and stdout if `addr` is `-`.
+fn serve_gdb(tam: TAM, addr: &str) -> std::io::Result<()> {
+    let mut stub = GdbStub::new(tam);
+    if addr == "-" {
+        let mut input = ThreadedInput::new(std::io::stdin());
+        return stub.serve(&mut input, &mut std::io::stdout());
+    }
+
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("waiting for a debugger on {}", listener.local_addr()?);
+    let (mut stream, peer) = listener.accept()?;
+    eprintln!("debugger attached from {peer}");
+    stub.serve(&mut BufReader::new(stream.try_clone()?), &mut stream)
+}
 
-    while let Ok(inst) = f.read_u32::<BE>() {
-        let inst1 = Instruction::from(inst);
-        println!("{addr:04x}: {inst1}");
-        addr += 1;
+/// Build the filter for the trace from the arguments, exiting with an error
+/// message if a procedure is not a label or an address.
+fn trace_filter(args: &Args, container: &Container) -> TraceFilter {
+    let labels = container.code_labels();
+    let procs = args.trace_proc.iter().map(|name| {
+        let addr = match name.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok(),
+            None => name.parse().ok(),
+        };
+        labels.get(name).copied().or(addr).unwrap_or_else(|| {
+            eprintln!("error: `{name}` is not a label or an address");
+            exit(1);
+        })
+    });
+    TraceFilter {
+        addrs: (args.trace_addr.iter())
+            .map(|r| r.start as usize..r.end.min(usize::MAX as u64) as usize)
+            .collect(),
+        procs: procs.collect(),
+        steps: args.trace_steps.clone(),
+    }
+}
+
+/// Read a bytecode file, exiting with an error message if it is not valid.
+fn read_container(filename: &str, raw: bool) -> std::io::Result<Container> {
+    let bytes = std::fs::read(filename)?;
+    if raw {
+        return Ok(Container::from_raw(&bytes));
+    }
+
+    match Container::from_bytes(&bytes) {
+        Ok(container) => Ok(container),
+        Err(e) => {
+            eprintln!("error: {filename}: {e}");
+            if !Container::is_container(&bytes) {
+                eprintln!("note: use --raw to load a legacy bytecode file");
+            }
+            exit(1);
+        }
+    }
+}
+
+fn debug(
+    bytecode: &str,
+    input: Option<&str>,
+    raw: bool,
+    history_budget: usize,
+) -> std::io::Result<()> {
+    let container = read_container(bytecode, raw)?;
+    let mut tam = TAM::new(false);
+    tam.load_container(&container)?;
+    tam.set_history(Some(HistoryConfig {
+        budget: history_budget,
+        ..HistoryConfig::default()
+    }));
+    if let Some(path) = input {
+        let input = BufReader::new(File::open(path)?);
+        tam.set_io(Box::new(StreamIo::new(input, std::io::stdout())));
+    }
+
+    let mut debugger = Debugger::new(tam);
+    debugger.set_symbols(container.code_labels());
+    debugger.repl(
+        |buf| std::io::stdin().read_line(buf),
+        &mut std::io::stdout(),
+    )
+}
+
+fn disassemble(container: &Container) {
+    let mut labels: Vec<(usize, String)> = container
+        .code_labels()
+        .into_iter()
+        .map(|(name, addr)| (addr, name))
+        .collect();
+    labels.sort();
+    let data_labels: Vec<(usize, String)> = container
+        .symbols
+        .iter()
+        .filter(|sym| sym.kind == SymbolKind::Data)
+        .map(|sym| (sym.addr as usize, sym.name.clone()))
+        .collect();
+    let debug = container.debug_info();
+
+    if container.entry != 0 {
+        println!("entry: {:04x}", container.entry);
+    }
+    for (addr, inst) in container.code.iter().enumerate() {
+        for (_, name) in labels.iter().filter(|(a, _)| *a == addr) {
+            println!("{name}:");
+        }
+        let text = format_instruction(inst, &labels, &data_labels);
+        match debug.as_ref().and_then(|d| d.describe(addr)) {
+            Some(pos) => println!("{addr:04x}: {text:<24} # {pos} #"),
+            None => println!("{addr:04x}: {text}"),
+        }
+    }
+
+    if !container.data.is_empty() {
+        println!("# data at sb #");
+    }
+    for (addr, word) in container.data.iter().enumerate() {
+        for (_, name) in data_labels.iter().filter(|(a, _)| *a == addr) {
+            println!("{name}:");
+        }
+        println!("sb+{addr:04x}: .word   {word}");
+    }
+}
+
+/// Format an instruction, naming the target of a jump or call by its label, and an
+/// address in the static data by the label of its data.
+fn format_instruction(
+    inst: &Instruction,
+    labels: &[(usize, String)],
+    data_labels: &[(usize, String)],
+) -> String {
+    let text = inst.to_string();
+    if inst.r == Register::SB as u8 && inst.d >= 0 {
+        if let Some((_, name)) = data_labels.iter().find(|(a, _)| *a == inst.d as usize)
+        {
+            return text.replace(&format!("[sb+{}]", inst.d), &format!("[{name}]"));
+        }
+    }
+    let branch = matches!(
+        Opcode::try_from(inst.op),
+        Ok(Opcode::Jump | Opcode::JumpIf | Opcode::Call)
+    );
+    if !branch || inst.r != Register::CB as u8 || inst.d < 0 {
+        return text;
+    }
+    match labels.iter().find(|(addr, _)| *addr == inst.d as usize) {
+        Some((_, name)) => text.replace(&format!("[cb+{}]", inst.d), name),
+        None => text,
     }
-    Ok(())
 }
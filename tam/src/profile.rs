@@ -0,0 +1,397 @@
+//! Execution profiles of a running program.
+//!
+//! A [`Profile`] counts the instructions executed at each code address, the mix of
+//! opcodes and primitives, and builds a call tree from `call` and `return` pairs
+//! from which per-procedure and collapsed-stack counts are derived.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter, Result},
+    io::Write,
+};
+
+use common::instruction::{Instruction, Opcode, Primitive};
+
+use crate::report::describe_addr;
+
+/// A node of the call tree: one procedure reached by one particular path of calls.
+#[derive(Debug, Clone)]
+struct Node {
+    entry: usize,
+    parent: Option<usize>,
+    children: HashMap<usize, usize>,
+    calls: u64,
+    count: u64,
+    /// Whether `entry` is also the entry of one of the node's ancestors.
+    recursive: bool,
+}
+
+impl Node {
+    fn new(entry: usize, parent: Option<usize>, recursive: bool) -> Node {
+        Node {
+            entry,
+            parent,
+            children: HashMap::new(),
+            calls: 0,
+            count: 0,
+            recursive,
+        }
+    }
+}
+
+/// Instruction counts for one procedure, identified by its entry address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProcStats {
+    /// Code address of the first instruction of the procedure.
+    pub entry: usize,
+    /// Number of times the procedure was called.
+    pub calls: u64,
+    /// Instructions executed by the procedure and everything it called.
+    pub inclusive: u64,
+    /// Instructions executed by the procedure itself.
+    pub exclusive: u64,
+}
+
+/// Counts of what a program executed since the machine was last reset.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    counts: Vec<u64>,
+    opcodes: [u64; 16],
+    primitives: [u64; Primitive::ALL.len() + 1],
+    nodes: Vec<Node>,
+    current: usize,
+    /// Number of times each procedure appears on the path to the current node.
+    active: HashMap<usize, usize>,
+}
+
+impl Profile {
+    /// Construct an empty profile of a program that starts at `entry`.
+    pub fn new(entry: usize) -> Profile {
+        Profile {
+            counts: Vec::new(),
+            opcodes: [0; 16],
+            primitives: [0; Primitive::ALL.len() + 1],
+            nodes: vec![Node::new(entry, None, false)],
+            current: 0,
+            active: HashMap::from([(entry, 1)]),
+        }
+    }
+
+    /// Count an instruction with opcode `op` executed at `addr`.
+    pub(crate) fn record(&mut self, addr: usize, op: u8) {
+        if addr >= self.counts.len() {
+            self.counts.resize(addr + 1, 0);
+        }
+        self.counts[addr] += 1;
+        self.opcodes[op as usize & 0xf] += 1;
+        self.nodes[self.current].count += 1;
+    }
+
+    /// Count a call of a primitive routine.
+    pub(crate) fn record_primitive(&mut self, prim: Primitive) {
+        self.primitives[prim as usize] += 1;
+    }
+
+    /// Record a call of the routine at `entry` from the current procedure.
+    pub(crate) fn enter(&mut self, entry: usize) {
+        let child = match self.nodes[self.current].children.get(&entry) {
+            Some(child) => *child,
+            None => {
+                let recursive = self.active.get(&entry).is_some_and(|n| *n > 0);
+                self.nodes
+                    .push(Node::new(entry, Some(self.current), recursive));
+                let child = self.nodes.len() - 1;
+                self.nodes[self.current].children.insert(entry, child);
+                child
+            }
+        };
+        self.nodes[child].calls += 1;
+        *self.active.entry(entry).or_default() += 1;
+        self.current = child;
+    }
+
+    /// Record a return from the current procedure to its caller.
+    pub(crate) fn leave(&mut self) {
+        let node = &self.nodes[self.current];
+        if let Some(parent) = node.parent {
+            if let Some(n) = self.active.get_mut(&node.entry) {
+                *n -= 1;
+            }
+            self.current = parent;
+        }
+    }
+
+    /// Get the total number of instructions executed.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Get the number of times the instruction at `addr` was executed.
+    pub fn count(&self, addr: usize) -> u64 {
+        self.counts.get(addr).copied().unwrap_or(0)
+    }
+
+    /// Get each code address that was executed with its count, most executed
+    /// first.
+    pub fn addresses(&self) -> Vec<(usize, u64)> {
+        let mut addrs: Vec<(usize, u64)> = (self.counts.iter().copied().enumerate())
+            .filter(|(_, n)| *n > 0)
+            .collect();
+        addrs.sort_by_key(|(addr, n)| (std::cmp::Reverse(*n), *addr));
+        addrs
+    }
+
+    /// Get the number of instructions executed with opcode `op`.
+    pub fn opcode_count(&self, op: Opcode) -> u64 {
+        self.opcodes[op as usize]
+    }
+
+    /// Get the number of calls of the primitive routine `prim`.
+    pub fn primitive_count(&self, prim: Primitive) -> u64 {
+        self.primitives[prim as usize]
+    }
+
+    /// Get the counts for each procedure, ordered by inclusive count, highest
+    /// first.
+    ///
+    /// The program's entry point is counted as a procedure called once. A
+    /// recursive procedure's inclusive count includes its recursive calls only once.
+    pub fn procedures(&self) -> Vec<ProcStats> {
+        // children always come after their parents
+        let mut totals: Vec<u64> = self.nodes.iter().map(|node| node.count).collect();
+        for (i, node) in self.nodes.iter().enumerate().rev() {
+            if let Some(parent) = node.parent {
+                totals[parent] += totals[i];
+            }
+        }
+
+        let mut procs: HashMap<usize, ProcStats> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let stats = procs.entry(node.entry).or_insert(ProcStats {
+                entry: node.entry,
+                calls: 0,
+                inclusive: 0,
+                exclusive: 0,
+            });
+            stats.calls += if node.parent.is_none() { 1 } else { node.calls };
+            stats.exclusive += node.count;
+            if !node.recursive {
+                stats.inclusive += totals[i];
+            }
+        }
+
+        let mut procs: Vec<ProcStats> = procs.into_values().collect();
+        procs.sort_by_key(|p| (std::cmp::Reverse(p.inclusive), p.entry));
+        procs
+    }
+
+    /// Get the number of instructions executed with each stack of active
+    /// procedures, outermost first.
+    pub fn stacks(&self) -> Vec<(Vec<usize>, u64)> {
+        let mut stacks: Vec<(Vec<usize>, u64)> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.count > 0)
+            .map(|(i, node)| (self.path(i), node.count))
+            .collect();
+        stacks.sort();
+        stacks
+    }
+
+    /// Write the stacks in the collapsed format read by flame graph tools: the
+    /// procedures on each stack separated by `;`, then a space and the count.
+    pub fn write_collapsed(
+        &self,
+        out: &mut impl Write,
+        symbols: &HashMap<String, usize>,
+    ) -> std::io::Result<()> {
+        for (stack, count) in self.stacks() {
+            let names: Vec<String> =
+                stack.iter().map(|addr| proc_name(symbols, *addr)).collect();
+            writeln!(out, "{} {count}", names.join(";"))?;
+        }
+        Ok(())
+    }
+
+    fn path(&self, mut node: usize) -> Vec<usize> {
+        let mut path = vec![self.nodes[node].entry];
+        while let Some(parent) = self.nodes[node].parent {
+            path.push(self.nodes[parent].entry);
+            node = parent;
+        }
+        path.reverse();
+        path
+    }
+}
+
+/// Name a procedure after the label at its entry, or its address if there is none.
+fn proc_name(symbols: &HashMap<String, usize>, entry: usize) -> String {
+    symbols
+        .iter()
+        .filter(|(_, addr)| **addr == entry)
+        .map(|(name, _)| name)
+        .min()
+        .cloned()
+        .unwrap_or_else(|| format!("{entry:04x}"))
+}
+
+/// A text report of a profile, with each section sorted from most to least
+/// executed.
+pub struct ProfileReport<'a> {
+    profile: &'a Profile,
+    code: &'a [u32],
+    symbols: &'a HashMap<String, usize>,
+}
+
+impl<'a> ProfileReport<'a> {
+    /// Construct a report of `profile`, taken of a run of `code`.
+    pub fn new(
+        profile: &'a Profile,
+        code: &'a [u32],
+        symbols: &'a HashMap<String, usize>,
+    ) -> ProfileReport<'a> {
+        ProfileReport {
+            profile,
+            code,
+            symbols,
+        }
+    }
+}
+
+impl Display for ProfileReport<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let total = self.profile.total();
+        let percent = |n: u64| 100.0 * n as f64 / total.max(1) as f64;
+        writeln!(f, "profile: {total} instructions executed")?;
+
+        writeln!(f, "procedures:")?;
+        writeln!(f, "   inclusive   exclusive       calls  procedure")?;
+        for p in self.profile.procedures() {
+            writeln!(
+                f,
+                "  {:>10}  {:>10}  {:>10}  {}",
+                p.inclusive,
+                p.exclusive,
+                p.calls,
+                proc_name(self.symbols, p.entry)
+            )?;
+        }
+
+        writeln!(f, "opcodes:")?;
+        let mut opcodes: Vec<(Opcode, u64)> = (Opcode::ALL.iter())
+            .map(|op| (*op, self.profile.opcode_count(*op)))
+            .filter(|(_, n)| *n > 0)
+            .collect();
+        opcodes.sort_by_key(|(op, n)| (std::cmp::Reverse(*n), *op as u8));
+        for (op, n) in opcodes {
+            writeln!(f, "  {:<8}{:>10}  {:5.1}%", op.name(), n, percent(n))?;
+        }
+
+        writeln!(f, "primitives:")?;
+        let mut prims: Vec<(Primitive, u64)> = (Primitive::ALL.iter())
+            .map(|prim| (*prim, self.profile.primitive_count(*prim)))
+            .filter(|(_, n)| *n > 0)
+            .collect();
+        prims.sort_by_key(|(prim, n)| (std::cmp::Reverse(*n), *prim as u8));
+        if prims.is_empty() {
+            writeln!(f, "  <none>")?;
+        }
+        for (prim, n) in prims {
+            writeln!(f, "  {:<8}{:>10}", prim.name(), n)?;
+        }
+
+        writeln!(f, "addresses:")?;
+        for (addr, n) in self.profile.addresses() {
+            let instr = Instruction::from(self.code.get(addr).copied().unwrap_or(0));
+            writeln!(
+                f,
+                "  {:>10}  {:5.1}%  {}: {}",
+                n,
+                percent(n),
+                describe_addr(self.symbols, addr),
+                instr
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::*;
+
+    use super::*;
+
+    /// Profile `main` at 0 calling `f` at 10 twice, where `f` calls itself once.
+    fn recursive_profile() -> Profile {
+        let mut profile = Profile::new(0);
+        profile.record(0, Opcode::Call as u8);
+        profile.enter(10);
+        profile.record(10, Opcode::Call as u8);
+        profile.enter(10);
+        profile.record(10, Opcode::Return as u8);
+        profile.leave();
+        profile.record(11, Opcode::Return as u8);
+        profile.leave();
+        profile.record(1, Opcode::Call as u8);
+        profile.enter(10);
+        profile.record(10, Opcode::Return as u8);
+        profile.leave();
+        profile.record(2, Opcode::Halt as u8);
+        profile
+    }
+
+    #[rstest]
+    fn counts_addresses_and_opcodes() {
+        let profile = recursive_profile();
+        assert_eq!(7, profile.total());
+        assert_eq!(3, profile.count(10));
+        assert_eq!(0, profile.count(5));
+        assert_eq!((10, 3), profile.addresses()[0]);
+        assert_eq!(3, profile.opcode_count(Opcode::Call));
+        assert_eq!(3, profile.opcode_count(Opcode::Return));
+    }
+
+    #[rstest]
+    fn recursion_counted_once_inclusively() {
+        let procs = recursive_profile().procedures();
+        assert_eq!(
+            vec![
+                ProcStats {
+                    entry: 0,
+                    calls: 1,
+                    inclusive: 7,
+                    exclusive: 3
+                },
+                ProcStats {
+                    entry: 10,
+                    calls: 3,
+                    inclusive: 4,
+                    exclusive: 4
+                },
+            ],
+            procs
+        );
+    }
+
+    #[rstest]
+    fn collapsed_stacks() {
+        let profile = recursive_profile();
+        let symbols = HashMap::from([("main".to_string(), 0), ("f".to_string(), 10)]);
+        let mut out = Vec::new();
+        profile.write_collapsed(&mut out, &symbols).unwrap();
+        assert_eq!(
+            "main 3\nmain;f 3\nmain;f;f 1\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[rstest]
+    fn leave_at_top_level_is_ignored() {
+        let mut profile = Profile::new(0);
+        profile.leave();
+        profile.record(0, Opcode::Halt as u8);
+        assert_eq!(vec![(vec![0], 1)], profile.stacks());
+    }
+}
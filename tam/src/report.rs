@@ -0,0 +1,316 @@
+//! Reports of the state of the machine when a program faults.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter, Result},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use common::{debug::DebugInfo, instruction::Register};
+
+use crate::{errors::TAMError, machine::TAM};
+
+/// Number of words from the top of the stack included in a report.
+const STACK_WORDS: usize = 8;
+
+/// Number of bytes of a source file read to show a source line.
+const MAX_SOURCE: u64 = 1 << 24;
+
+/// Describe a code address, relative to the nearest preceding label in `symbols`
+/// if there is one.
+pub fn describe_addr(symbols: &HashMap<String, usize>, addr: usize) -> String {
+    match label_addr(symbols, addr) {
+        Some(label) => format!("{addr:04x} <{label}>"),
+        None => format!("{addr:04x}"),
+    }
+}
+
+/// Name a code address after the nearest preceding label in `symbols`, with its
+/// offset from the label if it is not zero.
+pub fn label_addr(symbols: &HashMap<String, usize>, addr: usize) -> Option<String> {
+    let (name, a) = symbols
+        .iter()
+        .filter(|(_, a)| **a <= addr)
+        .max_by_key(|(name, a)| (**a, std::cmp::Reverse(*name)))?;
+    if *a == addr {
+        Some(name.clone())
+    } else {
+        Some(format!("{name}+{}", addr - a))
+    }
+}
+
+/// Find the source file named in the debug information of the bytecode file
+/// `program`, relative to the program unless it is only found as given.
+pub fn resolve_source(program: &str, file: &str) -> PathBuf {
+    let path = PathBuf::from(file);
+    let Some(relative) = Path::new(program).parent().map(|dir| dir.join(&path)) else {
+        return path;
+    };
+    if relative.exists() || !path.exists() {
+        relative
+    } else {
+        path
+    }
+}
+
+/// Read a source file named by a program's debug information. The name comes from
+/// the bytecode, so only the start of a regular file is read, and any failure gives
+/// `None`.
+fn read_source(path: &Path) -> Option<String> {
+    if !std::fs::metadata(path).ok()?.is_file() {
+        return None;
+    }
+    let mut text = String::new();
+    let file = std::fs::File::open(path).ok()?;
+    file.take(MAX_SOURCE).read_to_string(&mut text).ok()?;
+    Some(text)
+}
+
+/// Describe the source position of the instruction at `addr` in the bytecode file
+/// `program`, followed by the source line with the position marked if the source
+/// file can be read.
+pub fn describe_source(
+    debug: &DebugInfo,
+    addr: usize,
+    program: &str,
+) -> Option<String> {
+    let loc = debug.loc(addr)?;
+    let gutter = " ".repeat(loc.line.to_string().len());
+    let mut out = format!("{gutter}--> {}:{loc}\n", debug.file);
+    let source = read_source(&resolve_source(program, &debug.file));
+    if let Some(text) = source
+        .as_deref()
+        .and_then(|s| s.lines().nth(loc.line.checked_sub(1)? as usize))
+    {
+        let indent: String = (text.chars().take(loc.col.saturating_sub(1) as usize))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out += &format!(
+            "{gutter} |\n{} | {}\n{gutter} | {indent}^\n",
+            loc.line,
+            text.trim_end()
+        );
+    }
+    Some(out)
+}
+
+/// Format the register file of `tam`, four registers to a line.
+pub fn format_registers(tam: &TAM) -> String {
+    let mut out = String::new();
+    for reg in Register::ALL {
+        let sep = if reg as usize % 4 == 3 { "\n" } else { "  " };
+        out += &format!("{:>2}={:04x}{sep}", reg.name(), tam.register(reg));
+    }
+    out
+}
+
+/// A description of a runtime error and the state of the machine when it happened.
+///
+/// The report lists the active frames, the words at the top of the stack and the
+/// register file. Code addresses are named after the labels in the symbol table,
+/// and given their source positions if the program has debug information.
+pub struct FaultReport<'a> {
+    tam: &'a TAM,
+    error: &'a TAMError,
+    symbols: &'a HashMap<String, usize>,
+    program: &'a str,
+}
+
+impl<'a> FaultReport<'a> {
+    /// Construct a report of `error`, which `tam` has just raised.
+    pub fn new(
+        tam: &'a TAM,
+        error: &'a TAMError,
+        symbols: &'a HashMap<String, usize>,
+    ) -> FaultReport<'a> {
+        FaultReport {
+            tam,
+            error,
+            symbols,
+            program: "",
+        }
+    }
+
+    /// Look for the source file relative to the bytecode file `program`.
+    pub fn with_program(mut self, program: &'a str) -> FaultReport<'a> {
+        self.program = program;
+        self
+    }
+}
+
+impl Display for FaultReport<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        writeln!(f, "error: {}", self.error)?;
+        let debug = self.tam.debug_info();
+        if let Some(source) =
+            debug.and_then(|d| describe_source(d, self.error.loc(), self.program))
+        {
+            write!(f, "{source}")?;
+        }
+        let at = |addr: usize| {
+            (debug.and_then(|d| d.describe(addr)))
+                .map_or(String::new(), |pos| format!(" at {pos}"))
+        };
+        if let TAMError::HeapViolation(_, violation) = self.error {
+            for block in violation.blocks() {
+                write!(
+                    f,
+                    "  block {:04x} of {} words allocated at {}",
+                    block.addr,
+                    block.size,
+                    describe_addr(self.symbols, block.alloc_site)
+                )?;
+                match block.free_site {
+                    Some(site) => {
+                        writeln!(f, ", freed at {}", describe_addr(self.symbols, site))?
+                    }
+                    None => writeln!(f)?,
+                }
+            }
+        }
+
+        writeln!(f, "backtrace:")?;
+        let mut pc = self.error.loc();
+        let frames = self.tam.frames();
+        for (i, frame) in frames.iter().enumerate() {
+            writeln!(
+                f,
+                "  #{i} {} lb={:04x}{}",
+                describe_addr(self.symbols, pc),
+                frame.base,
+                at(pc)
+            )?;
+            writeln!(
+                f,
+                "     returns to {}",
+                describe_addr(self.symbols, frame.return_addr)
+            )?;
+            pc = frame.return_addr.saturating_sub(1);
+        }
+        writeln!(
+            f,
+            "  #{} {} (top level){}",
+            frames.len(),
+            describe_addr(self.symbols, pc),
+            at(pc)
+        )?;
+
+        let st = self.tam.register(Register::ST).min(self.tam.data().len());
+        writeln!(f, "stack (st={st:04x}):")?;
+        if st == 0 {
+            writeln!(f, "  <empty>")?;
+        }
+        for addr in (st.saturating_sub(STACK_WORDS)..st).rev() {
+            writeln!(f, "  {addr:04x}: {}", self.tam.data()[addr])?;
+        }
+
+        writeln!(f, "registers:")?;
+        for line in format_registers(self.tam).lines() {
+            writeln!(f, "  {line}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{container::Container, debug::SourceLoc, instruction::Instruction};
+
+    use super::*;
+    use crate::io::BufferIo;
+
+    fn instr(op: u8, r: u8, n: u8, d: i16) -> Instruction {
+        Instruction { op, r, n, d }
+    }
+
+    #[test]
+    fn describe_relative_to_label() {
+        let symbols = HashMap::from([("main".to_string(), 0), ("fac".to_string(), 4)]);
+        assert_eq!("0004 <fac>", describe_addr(&symbols, 4));
+        assert_eq!("0006 <fac+2>", describe_addr(&symbols, 6));
+        assert_eq!("0006", describe_addr(&HashMap::new(), 6));
+    }
+
+    #[test]
+    fn report_fault_in_routine() {
+        let mut tam = TAM::new(false);
+        tam.set_io(Box::new(BufferIo::new("")));
+        tam.load_instructions(&[
+            instr(3, 0, 0, 7),  // loadl 7
+            instr(6, 0, 4, 3),  // call  sb, [cb+3]
+            instr(15, 0, 0, 0), // halt
+            instr(3, 0, 0, 0),  // div0: loadl 0
+            instr(6, 2, 0, 11), // call  div
+            instr(8, 0, 1, 0),  // return 1, 0
+        ])
+        .unwrap();
+        let symbols = HashMap::from([("div0".to_string(), 3)]);
+
+        let err = tam.run().unwrap_err();
+        let report = FaultReport::new(&tam, &err, &symbols).to_string();
+
+        assert!(report.starts_with("error: divide by zero attempted at loc 0004\n"));
+        assert!(report.contains(
+            "backtrace:\n  #0 0004 <div0+1> lb=0001\n     returns to 0002\n  \
+             #1 0001 (top level)\n"
+        ));
+        assert!(report.contains("stack (st=0004):\n  0003: 2\n  0002: 0\n"));
+        assert!(report.contains("registers:\n  cb=0000  ct=0006"));
+    }
+
+    #[test]
+    fn report_source_position() {
+        let path = std::env::temp_dir().join("tam_report_source_position.tasm");
+        std::fs::write(&path, "loadl 1\nloadl 0\n\tcall div\nhalt\n").unwrap();
+        let file = path.to_string_lossy().into_owned();
+        let container = Container {
+            code: vec![
+                instr(3, 0, 0, 1),  // loadl 1
+                instr(3, 0, 0, 0),  // loadl 0
+                instr(6, 2, 0, 11), // call  div
+                instr(15, 0, 0, 0), // halt
+            ],
+            debug: DebugInfo {
+                file: file.clone(),
+                locs: [(1, 1), (2, 1), (3, 2), (4, 1)]
+                    .map(|(line, col)| SourceLoc { line, col })
+                    .to_vec(),
+            }
+            .to_bytes(),
+            ..Container::default()
+        };
+        let mut tam = TAM::new(false);
+        tam.set_io(Box::new(BufferIo::new("")));
+        tam.load_container(&container).unwrap();
+
+        let err = tam.run().unwrap_err();
+        let report = FaultReport::new(&tam, &err, tam.labels()).to_string();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(report.starts_with(&format!(
+            "error: divide by zero attempted at loc 0002\n \
+             --> {file}:3:2\n  |\n3 | \tcall div\n  | \t^\n"
+        )));
+        assert!(report.contains(&format!("  #0 0002 (top level) at {file}:3:2\n")));
+    }
+
+    #[test]
+    fn report_source_relative_to_program() {
+        let dir = std::env::temp_dir().join("tam_report_source_relative");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("div.tasm"), "loadl 1\nloadl 0\ncall div\n").unwrap();
+        let program = dir.join("div.tam").to_string_lossy().into_owned();
+        let debug = |col| DebugInfo {
+            file: String::from("div.tasm"),
+            locs: vec![SourceLoc { line: 3, col }],
+        };
+
+        let source = describe_source(&debug(1), 0, &program).unwrap();
+        // A column of 0 is not valid, and is marked as the first.
+        let no_col = describe_source(&debug(0), 0, &program).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(" --> div.tasm:3:1\n  |\n3 | call div\n  | ^\n", source);
+        assert_eq!(" --> div.tasm:3:0\n  |\n3 | call div\n  | ^\n", no_col);
+    }
+}
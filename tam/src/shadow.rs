@@ -0,0 +1,108 @@
+//! Shadow memory recording which words of the data store have been initialized.
+//!
+//! Words reserved with `push` and blocks allocated with `new` start out
+//! undefined. They become defined when they are written by `store`, `storei`,
+//! `get` or `getint`, or pushed with a value. Reading an undefined word with
+//! `load` or `loadi` is reported or trapped, depending on the [`UninitMode`].
+
+use std::{
+    fmt::{Display, Error, Formatter},
+    mem::size_of,
+    ops::Range,
+    str::FromStr,
+};
+
+/// What the machine does when a program reads an uninitialized word.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum UninitMode {
+    /// Do not track which words are initialized.
+    #[default]
+    Off,
+    /// Record each read of an uninitialized word and carry on.
+    Report,
+    /// Stop with [`TAMError::UninitializedRead`](crate::TAMError::UninitializedRead).
+    Trap,
+}
+
+impl Display for UninitMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::Off => write!(f, "off"),
+            Self::Report => write!(f, "report"),
+            Self::Trap => write!(f, "trap"),
+        }
+    }
+}
+
+impl FromStr for UninitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "report" => Ok(Self::Report),
+            "trap" => Ok(Self::Trap),
+            _ => Err(format!("unknown mode `{s}`, expected off, report or trap")),
+        }
+    }
+}
+
+/// Reads of uninitialized words by the instruction at one code address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UninitRead {
+    /// Code address of the instruction.
+    pub loc: usize,
+    /// The first uninitialized address it read.
+    pub addr: usize,
+    /// Number of uninitialized words it has read.
+    pub count: usize,
+}
+
+impl Display for UninitRead {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(
+            f,
+            "read of uninitialized word {:04x} at loc {:04x}",
+            self.addr, self.loc
+        )?;
+        if self.count > 1 {
+            write!(f, " ({} times)", self.count)?;
+        }
+        Ok(())
+    }
+}
+
+/// One bit per word of the data store, set when the word is defined.
+#[derive(Debug, Clone)]
+pub(crate) struct Shadow {
+    defined: Vec<bool>,
+}
+
+impl Shadow {
+    pub(crate) fn new(size: usize) -> Shadow {
+        Shadow {
+            defined: vec![false; size],
+        }
+    }
+
+    /// Mark every word as undefined.
+    pub(crate) fn clear(&mut self) {
+        self.defined.fill(false);
+    }
+
+    /// Mark the words at the addresses in `range` as defined or undefined.
+    pub(crate) fn set(&mut self, range: Range<usize>, defined: bool) {
+        let end = range.end.min(self.defined.len());
+        let start = range.start.min(end);
+        self.defined[start..end].fill(defined);
+    }
+
+    pub(crate) fn is_defined(&self, addr: usize) -> bool {
+        self.defined.get(addr).copied().unwrap_or(true)
+    }
+
+    /// Get the approximate number of bytes the shadow occupies.
+    pub(crate) fn size(&self) -> usize {
+        size_of::<Shadow>() + self.defined.len()
+    }
+}
@@ -0,0 +1,247 @@
+//! Machine-readable traces of a running program.
+//!
+//! A [`Tracer`] writes one JSON object per line for each instruction executed,
+//! holding the step number, the address and text of the instruction, the
+//! registers it changed, the words it wrote to the data store and any input it
+//! read or output it wrote. A [`TraceFilter`] restricts the trace to certain
+//! addresses, procedures or steps.
+
+use std::{collections::HashMap, io::Write, ops::Range};
+
+use common::{
+    debug::DebugInfo,
+    instruction::{Instruction, Register},
+};
+use serde_json::{json, Map, Value};
+
+use crate::{debugger::parse_num, report::label_addr};
+
+/// Which steps a [`Tracer`] records. Each kind of criterion that is not empty must
+/// match a step for it to be recorded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Code addresses of the instructions to record.
+    pub addrs: Vec<Range<usize>>,
+    /// Entry addresses of the procedures whose own instructions are recorded.
+    pub procs: Vec<usize>,
+    /// Step numbers to record, counting the first instruction executed after the
+    /// machine is reset as step 1.
+    pub steps: Vec<Range<u64>>,
+}
+
+impl TraceFilter {
+    /// Check if the step numbered `step`, executing the instruction at `addr` in
+    /// the procedure starting at `proc`, should be recorded.
+    pub fn matches(&self, step: u64, addr: usize, proc: usize) -> bool {
+        (self.addrs.is_empty() || self.addrs.iter().any(|r| r.contains(&addr)))
+            && (self.procs.is_empty() || self.procs.contains(&proc))
+            && (self.steps.is_empty() || self.steps.iter().any(|r| r.contains(&step)))
+    }
+}
+
+/// Parse a half-open range written `start..end`, `start..`, `..end`, or a single
+/// number. Numbers are decimal or `0x`-prefixed hex.
+pub fn parse_range(s: &str) -> Result<Range<u64>, String> {
+    let num = |n: &str| {
+        parse_num(n)
+            .map(|n| n as u64)
+            .ok_or_else(|| format!("`{n}` is not a number"))
+    };
+    match s.split_once("..") {
+        Some((start, end)) => {
+            let start = if start.is_empty() { 0 } else { num(start)? };
+            let end = if end.is_empty() { u64::MAX } else { num(end)? };
+            Ok(start..end)
+        }
+        None => {
+            let n = num(s)?;
+            let end = n
+                .checked_add(1)
+                .ok_or_else(|| format!("`{s}` is too large"))?;
+            Ok(n..end)
+        }
+    }
+}
+
+/// The effects of the step being recorded.
+#[derive(Debug, Clone)]
+struct Record {
+    step: u64,
+    addr: usize,
+    instr: Instruction,
+    registers: [usize; 16],
+    writes: Vec<(usize, i16)>,
+    input: Vec<u8>,
+    output: String,
+}
+
+/// Writes a trace of each step as JSON Lines.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    filter: TraceFilter,
+    /// Entry addresses of the active procedures, innermost last.
+    procs: Vec<usize>,
+    record: Option<Record>,
+}
+
+impl Tracer {
+    /// Construct a tracer that writes the steps matching `filter` to `out`.
+    pub fn new(out: Box<dyn Write>, filter: TraceFilter) -> Tracer {
+        Tracer {
+            out,
+            filter,
+            procs: Vec::new(),
+            record: None,
+        }
+    }
+
+    /// Start tracing a program from its entry point at `entry`.
+    pub(crate) fn reset(&mut self, entry: usize) {
+        self.procs = vec![entry];
+        self.record = None;
+    }
+
+    /// Start recording the step numbered `step`, which executes `instr` at `addr`
+    /// with the given register values, if it matches the filter.
+    pub(crate) fn begin(
+        &mut self,
+        step: u64,
+        addr: usize,
+        instr: Instruction,
+        registers: &[usize; 16],
+    ) {
+        let proc = self.procs.last().copied().unwrap_or(0);
+        self.record = self.filter.matches(step, addr, proc).then(|| Record {
+            step,
+            addr,
+            instr,
+            registers: *registers,
+            writes: Vec::new(),
+            input: Vec::new(),
+            output: String::new(),
+        });
+    }
+
+    /// Record a word written to the data store.
+    pub(crate) fn write(&mut self, addr: usize, val: i16) {
+        if let Some(record) = &mut self.record {
+            record.writes.push((addr, val));
+        }
+    }
+
+    /// Record a byte read from the program's input.
+    pub(crate) fn input(&mut self, byte: u8) {
+        if let Some(record) = &mut self.record {
+            record.input.push(byte);
+        }
+    }
+
+    /// Record text written to the program's output.
+    pub(crate) fn output(&mut self, s: &str) {
+        if let Some(record) = &mut self.record {
+            record.output += s;
+        }
+    }
+
+    /// Record a call of the routine at `entry`.
+    pub(crate) fn enter(&mut self, entry: usize) {
+        self.procs.push(entry);
+    }
+
+    /// Record a return from the current routine.
+    pub(crate) fn leave(&mut self) {
+        if self.procs.len() > 1 {
+            self.procs.pop();
+        }
+    }
+
+    /// Finish the step being recorded, given the register values after it and the
+    /// error it raised, if any, and write it out.
+    pub(crate) fn end(
+        &mut self,
+        registers: &[usize; 16],
+        error: Option<String>,
+        labels: &HashMap<String, usize>,
+        debug: Option<&DebugInfo>,
+    ) -> std::io::Result<()> {
+        let Some(record) = self.record.take() else {
+            return Ok(());
+        };
+
+        let mut obj = Map::new();
+        obj.insert("step".into(), json!(record.step));
+        obj.insert("addr".into(), json!(record.addr));
+        if let Some(label) = label_addr(labels, record.addr) {
+            obj.insert("label".into(), json!(label));
+        }
+        if let Some(pos) = debug.and_then(|d| d.describe(record.addr)) {
+            obj.insert("source".into(), json!(pos));
+        }
+        obj.insert("instr".into(), json!(record.instr.to_string()));
+
+        let regs: Map<String, Value> = Register::ALL
+            .iter()
+            .filter(|r| registers[**r as usize] != record.registers[**r as usize])
+            .map(|r| (r.name().to_string(), json!(registers[*r as usize])))
+            .collect();
+        if !regs.is_empty() {
+            obj.insert("regs".into(), Value::Object(regs));
+        }
+        if !record.writes.is_empty() {
+            obj.insert("writes".into(), json!(record.writes));
+        }
+        if !record.input.is_empty() {
+            let input = String::from_utf8_lossy(&record.input);
+            obj.insert("input".into(), json!(input));
+        }
+        if !record.output.is_empty() {
+            obj.insert("output".into(), json!(record.output));
+        }
+        if let Some(error) = error {
+            obj.insert("error".into(), json!(error));
+        }
+        writeln!(self.out, "{}", Value::Object(obj))
+    }
+
+    /// Flush any buffered trace output.
+    pub(crate) fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::*;
+
+    use super::*;
+
+    #[rstest]
+    #[case("4", 4..5)]
+    #[case("0x10..0x20", 16..32)]
+    #[case("5..", 5..u64::MAX)]
+    #[case("..8", 0..8)]
+    fn parse_ranges(#[case] s: &str, #[case] expected: Range<u64>) {
+        assert_eq!(Ok(expected), parse_range(s));
+    }
+
+    #[rstest]
+    #[case::not_a_number("a..4")]
+    #[case::no_end("18446744073709551615")]
+    fn parse_bad_range(#[case] s: &str) {
+        assert!(parse_range(s).is_err());
+    }
+
+    #[rstest]
+    fn filter_needs_every_kind_to_match() {
+        let filter = TraceFilter {
+            addrs: vec![0..4, 8..10],
+            procs: vec![],
+            steps: vec![1..50, 60..100],
+        };
+        assert!(filter.matches(1, 9, 0));
+        assert!(!filter.matches(1, 5, 0));
+        assert!(!filter.matches(55, 9, 0));
+        assert!(!filter.matches(100, 9, 0));
+        assert!(TraceFilter::default().matches(7, 7, 7));
+    }
+}
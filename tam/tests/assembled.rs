@@ -0,0 +1,66 @@
+//! Tests of programs assembled from source with tasc.
+
+mod support;
+
+use common::{container::Container, instruction::Register};
+use rstest::*;
+use support::*;
+use tam::{io::BufferIo, TAM};
+
+#[rstest]
+fn recursive_factorial_from_source() {
+    let source = "
+                push    1
+                loada   [sb+0]
+                call    getint
+                load    1, [sb+0]
+                call    sb, fac
+                call    putint
+                call    puteol
+                halt
+        fac:    load    1, [lb-1]
+                jumpif  0, base
+                load    1, [lb-1]
+                load    1, [lb-1]
+                call    dec
+                call    sb, fac
+                call    mul
+                return  1, 1
+        base:   loadl   1
+                return  1, 1
+    ";
+    let program = tasc::assemble(source, &tasc::Options::default()).unwrap();
+    let (mut tam, io) = machine(&program.code, "6\n");
+    let res = tam.run();
+    assert!(res.is_ok(), "{}", res.unwrap_err());
+    assert_eq!("720\n", io.output());
+}
+
+#[rstest]
+fn static_data_from_source() {
+    let source = r#"
+                load    1, [count]
+                call    putint
+                load    1, [msg+1]
+                call    put
+                loadl   5
+                store   1, [count]
+                halt
+        count:  .word   42
+        msg:    .string "hi"
+    "#;
+    let program = tasc::assemble(source, &tasc::Options::default()).unwrap();
+    let mut tam = TAM::new(false);
+    let io = BufferIo::new("");
+    tam.set_io(Box::new(io.clone()));
+    tam.load_container(&Container::from(program)).unwrap();
+
+    let res = tam.run();
+    assert!(res.is_ok(), "{}", res.unwrap_err());
+    assert_eq!("42i", io.output());
+    assert_eq!(&[5, 104, 105, 0], &tam.data()[..4]);
+
+    tam.reset();
+    assert_eq!(&[42, 104, 105, 0], &tam.data()[..4]);
+    assert_eq!(4, tam.register(Register::ST));
+}
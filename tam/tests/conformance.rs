@@ -0,0 +1,498 @@
+//! Conformance tests for every TAM instruction and primitive routine.
+//!
+//! Each case runs a small program to completion and checks the resulting stack,
+//! the program output, or the error raised.
+
+mod support;
+
+use common::instruction::{Instruction, Register};
+use rstest::*;
+use support::*;
+use tam::{
+    heap::Strategy, io::BufferIo, machine::MEM_SIZE, report::FaultReport, TAMError, TAM,
+};
+
+/// Run `prog` with `input` and return the machine and everything it printed.
+fn run(prog: &[Instruction], input: &str) -> (TAM, String, Result<(), TAMError>) {
+    run_mode(prog, input, false)
+}
+
+fn run_strict(
+    prog: &[Instruction],
+    input: &str,
+) -> (TAM, String, Result<(), TAMError>) {
+    run_mode(prog, input, true)
+}
+
+fn run_mode(
+    prog: &[Instruction],
+    input: &str,
+    strict: bool,
+) -> (TAM, String, Result<(), TAMError>) {
+    let (mut tam, io) = machine(prog, input);
+    tam.set_strict(strict);
+    let res = tam.run();
+    (tam, io.output(), res)
+}
+
+#[rstest]
+#[case::load(vec![loadl(3), loadl(4), load(2, SBR, 0), halt()], vec![3, 4, 3, 4])]
+#[case::load_zero_words(vec![loadl(3), load(0, SBR, 0), halt()], vec![3])]
+#[case::loada(vec![push(2), loada(SBR, 1), halt()], vec![0, 0, 1])]
+#[case::loada_negative_offset(vec![push(3), loada(STR, -2), halt()], vec![0, 0, 0, 1])]
+#[case::loadi(vec![loadl(7), loadl(8), loadl(0), loadi(2), halt()], vec![7, 8, 7, 8])]
+#[case::loadl(vec![loadl(-32768), loadl(32767), halt()], vec![-32768, 32767])]
+#[case::store(vec![push(2), loadl(5), loadl(6), store(2, SBR, 0), halt()], vec![5, 6])]
+#[case::storei(vec![push(2), loadl(5), loadl(6), loadl(0), storei(2), halt()], vec![5, 6])]
+#[case::call_return(
+    vec![loadl(20), loadl(22), call(SBR, 5), halt(), halt(),
+         load(1, LB, -2), load(1, LB, -1), prim(ADD), ret(1, 2)],
+    vec![42],
+)]
+#[case::return_multiword_without_args(
+    vec![call(SBR, 2), halt(), loadl(1), loadl(2), loadl(3), ret(3, 0)],
+    vec![1, 2, 3],
+)]
+#[case::return_frame_links(
+    vec![push(1), call(SBR, 3), halt(), load(3, LB, 0), ret(3, 0)],
+    vec![0, 0, 0, 2],
+)]
+#[case::calli(
+    vec![loadl(9), loadl(0), loadl(5), calli(), halt(), load(1, LB, -1), ret(1, 1)],
+    vec![9],
+)]
+#[case::calli_primitive(
+    vec![loadl(4), loadl(0), loadl((MEM_SIZE - 29) as i16 + INC), calli(), halt()],
+    vec![5],
+)]
+#[case::push(vec![push(3), halt()], vec![0, 0, 0])]
+#[case::push_zero(vec![push(0), halt()], vec![])]
+#[case::pop(vec![loadl(1), loadl(2), loadl(3), loadl(4), pop(1, 2), halt()], vec![1, 4])]
+#[case::pop_multiword(vec![loadl(1), loadl(2), loadl(3), pop(2, 1), halt()], vec![2, 3])]
+#[case::pop_nothing(vec![loadl(1), pop(0, 0), halt()], vec![1])]
+#[case::jump(vec![jump(2), loadl(1), loadl(2), halt()], vec![2])]
+#[case::jumpi(vec![loadl(3), jumpi(), loadl(1), loadl(2), halt()], vec![2])]
+#[case::jumpif_taken(vec![loadl(1), jumpif(1, 3), loadl(9), halt()], vec![])]
+#[case::jumpif_not_taken(vec![loadl(0), jumpif(1, 3), loadl(9), halt()], vec![9])]
+#[case::halt(vec![halt(), loadl(1)], vec![])]
+fn instruction(#[case] prog: Vec<Instruction>, #[case] expected: Vec<i16>) {
+    let (tam, _, res) = run(&prog, "");
+    assert!(res.is_ok(), "{}", res.unwrap_err());
+    assert_eq!(expected, stack(&tam));
+}
+
+#[rstest]
+#[case::id(vec![loadl(5), prim(ID)], vec![5])]
+#[case::not_false(vec![loadl(0), prim(NOT)], vec![1])]
+#[case::not_true(vec![loadl(3), prim(NOT)], vec![0])]
+#[case::and(vec![loadl(1), loadl(1), prim(AND)], vec![1])]
+#[case::and_false(vec![loadl(1), loadl(0), prim(AND)], vec![0])]
+#[case::and_no_overflow(vec![loadl(256), loadl(256), prim(AND)], vec![1])]
+#[case::or(vec![loadl(0), loadl(1), prim(OR)], vec![1])]
+#[case::or_false(vec![loadl(0), loadl(0), prim(OR)], vec![0])]
+#[case::or_cancelling(vec![loadl(1), loadl(-1), prim(OR)], vec![1])]
+#[case::inc(vec![loadl(1), prim(INC)], vec![2])]
+#[case::inc_wraps(vec![loadl(i16::MAX), prim(INC)], vec![i16::MIN])]
+#[case::dec(vec![loadl(1), prim(DEC)], vec![0])]
+#[case::dec_wraps(vec![loadl(i16::MIN), prim(DEC)], vec![i16::MAX])]
+#[case::neg(vec![loadl(4), prim(NEG)], vec![-4])]
+#[case::neg_min(vec![loadl(i16::MIN), prim(NEG)], vec![i16::MIN])]
+#[case::add(vec![loadl(4), loadl(-6), prim(ADD)], vec![-2])]
+#[case::add_wraps(vec![loadl(i16::MAX), loadl(1), prim(ADD)], vec![i16::MIN])]
+#[case::sub(vec![loadl(4), loadl(6), prim(SUB)], vec![-2])]
+#[case::mul(vec![loadl(-4), loadl(6), prim(MUL)], vec![-24])]
+#[case::mul_wraps(vec![loadl(256), loadl(256), prim(MUL)], vec![0])]
+#[case::div(vec![loadl(7), loadl(2), prim(DIV)], vec![3])]
+#[case::div_truncates(vec![loadl(-7), loadl(2), prim(DIV)], vec![-3])]
+#[case::div_min(vec![loadl(i16::MIN), loadl(-1), prim(DIV)], vec![i16::MIN])]
+#[case::modulo(vec![loadl(7), loadl(3), prim(MOD)], vec![1])]
+#[case::modulo_negative(vec![loadl(-7), loadl(3), prim(MOD)], vec![-1])]
+#[case::modulo_min(vec![loadl(i16::MIN), loadl(-1), prim(MOD)], vec![0])]
+#[case::lt(vec![loadl(1), loadl(2), prim(LT)], vec![1])]
+#[case::lt_equal(vec![loadl(2), loadl(2), prim(LT)], vec![0])]
+#[case::le(vec![loadl(2), loadl(2), prim(LE)], vec![1])]
+#[case::le_greater(vec![loadl(3), loadl(2), prim(LE)], vec![0])]
+#[case::ge(vec![loadl(2), loadl(2), prim(GE)], vec![1])]
+#[case::ge_less(vec![loadl(-3), loadl(2), prim(GE)], vec![0])]
+#[case::gt(vec![loadl(3), loadl(2), prim(GT)], vec![1])]
+#[case::gt_equal(vec![loadl(2), loadl(2), prim(GT)], vec![0])]
+#[case::eq(vec![loadl(2), loadl(2), loadl(1), prim(EQ)], vec![1])]
+#[case::eq_multiword(vec![loadl(1), loadl(2), loadl(1), loadl(3), loadl(2), prim(EQ)], vec![0])]
+#[case::eq_empty(vec![loadl(0), prim(EQ)], vec![1])]
+#[case::ne(vec![loadl(2), loadl(3), loadl(1), prim(NE)], vec![1])]
+#[case::ne_multiword(vec![loadl(1), loadl(2), loadl(1), loadl(2), loadl(2), prim(NE)], vec![0])]
+fn primitive(#[case] mut prog: Vec<Instruction>, #[case] expected: Vec<i16>) {
+    prog.push(halt());
+    let (tam, _, res) = run(&prog, "");
+    assert!(res.is_ok(), "{}", res.unwrap_err());
+    assert_eq!(expected, stack(&tam));
+}
+
+#[rstest]
+#[case::eol_true(vec![prim(EOL)], "\nx", vec![1], "")]
+#[case::eol_false(vec![prim(EOL)], "x\n", vec![0], "")]
+#[case::eol_at_eof(vec![prim(EOL)], "", vec![0], "")]
+#[case::eof_true(vec![prim(EOF)], "", vec![1], "")]
+#[case::eof_false(vec![prim(EOF)], "x", vec![0], "")]
+#[case::get(vec![push(1), loada(SBR, 0), prim(GET), prim(EOL)], "a\n", vec![97, 1], "")]
+#[case::get_at_eof(vec![push(1), loada(SBR, 0), prim(GET)], "", vec![-1], "")]
+#[case::put(vec![loadl(104), prim(PUT), loadl(105), prim(PUT)], "", vec![], "hi")]
+#[case::geteol(vec![prim(GETEOL), prim(EOF)], "abc\n", vec![1], "")]
+#[case::geteol_leaves_next_line(vec![prim(GETEOL), prim(EOL)], "abc\n\n", vec![1], "")]
+#[case::puteol(vec![prim(PUTEOL)], "", vec![], "\n")]
+#[case::getint(vec![push(1), loada(SBR, 0), prim(GETINT)], "123\n", vec![123], "")]
+#[case::getint_whitespace(vec![push(1), loada(SBR, 0), prim(GETINT)], "  \n -45 ", vec![-45], "")]
+#[case::getint_plus(vec![push(1), loada(SBR, 0), prim(GETINT)], "+7", vec![7], "")]
+#[case::getint_leaves_terminator(
+    vec![push(1), loada(SBR, 0), prim(GETINT), prim(EOL)],
+    "8\n",
+    vec![8, 1],
+    "",
+)]
+#[case::getint_twice(
+    vec![push(2), loada(SBR, 0), prim(GETINT), loada(SBR, 1), prim(GETINT)],
+    "3 4\n",
+    vec![3, 4],
+    "",
+)]
+#[case::putint(vec![loadl(-321), prim(PUTINT)], "", vec![], "-321")]
+#[case::putint_zero(vec![loadl(0), prim(PUTINT)], "", vec![], "0")]
+fn io_primitive(
+    #[case] mut prog: Vec<Instruction>,
+    #[case] input: &str,
+    #[case] expected: Vec<i16>,
+    #[case] output: &str,
+) {
+    prog.push(halt());
+    let (tam, out, res) = run(&prog, input);
+    assert!(res.is_ok(), "{}", res.unwrap_err());
+    assert_eq!(expected, stack(&tam));
+    assert_eq!(output, out);
+}
+
+#[rstest]
+fn new_allocates_below_heap_top() {
+    let prog = [loadl(2), prim(NEW), loadl(3), prim(NEW), halt()];
+    let (tam, _, res) = run(&prog, "");
+    assert!(res.is_ok());
+
+    let hb = tam.register(Register::HB);
+    let addrs: Vec<usize> = stack(&tam).iter().map(|a| *a as u16 as usize).collect();
+    assert_eq!(vec![hb - 1, hb - 5], addrs);
+    assert_eq!(hb - 7, tam.register(Register::HT));
+}
+
+#[rstest]
+fn new_memory_is_usable() {
+    let prog = [
+        loadl(1),
+        prim(NEW),
+        loadl(42),
+        load(1, STR, -2),
+        storei(1),
+        loadi(1),
+        halt(),
+    ];
+    let (tam, _, res) = run(&prog, "");
+    assert!(res.is_ok());
+    assert_eq!(vec![42], stack(&tam));
+}
+
+#[rstest]
+fn new_heap_top_register() {
+    // The block's header is the word above HT.
+    let prog = [loadl(1), prim(NEW), loada(HT, 2), halt()];
+    let (tam, _, res) = run(&prog, "");
+    assert!(res.is_ok());
+    assert_eq!(stack(&tam)[0], stack(&tam)[1]);
+}
+
+#[rstest]
+fn dispose() {
+    let prog = [
+        loadl(2),
+        prim(NEW),
+        loadl(2),
+        load(1, STR, -2),
+        prim(DISPOSE),
+        halt(),
+    ];
+    let (tam, _, res) = run(&prog, "");
+    assert!(res.is_ok());
+    assert_eq!(1, stack(&tam).len());
+}
+
+#[rstest]
+fn dispose_returns_top_block_to_stack() {
+    let prog = [
+        loadl(2),
+        prim(NEW),
+        loadl(2),
+        load(1, STR, -2),
+        prim(DISPOSE),
+        halt(),
+    ];
+    let (tam, _, res) = run(&prog, "");
+    assert!(res.is_ok());
+    assert_eq!(tam.register(Register::HB), tam.register(Register::HT));
+}
+
+#[rstest]
+#[case::bump(Strategy::Bump, vec![0, -3, -9])]
+#[case::first_fit(Strategy::FirstFit, vec![0, -3, -3])]
+#[case::best_fit(Strategy::BestFit, vec![0, -3, -3])]
+fn new_reuses_freed_block(#[case] strategy: Strategy, #[case] expected: Vec<i16>) {
+    // Allocate blocks of 1, 2 and 3 words, free the first two, which coalesce, and
+    // allocate 1 word.
+    let prog = [
+        loadl(1),
+        prim(NEW),
+        loadl(2),
+        prim(NEW),
+        loadl(3),
+        prim(NEW),
+        loadl(1),
+        load(1, SBR, 0),
+        prim(DISPOSE),
+        loadl(2),
+        load(1, SBR, 1),
+        prim(DISPOSE),
+        pop(0, 1),
+        loadl(1),
+        prim(NEW),
+        halt(),
+    ];
+    let mut tam = TAM::new(false);
+    tam.set_heap_strategy(strategy);
+    tam.load_instructions(&prog).unwrap();
+    assert!(tam.run().is_ok());
+
+    let hb = tam.register(Register::HB) as i16;
+    let offsets: Vec<i16> = stack(&tam).iter().map(|a| a - hb).collect();
+    assert_eq!(expected, offsets);
+    assert_eq!(2, tam.heap_usage().frees);
+}
+
+#[rstest]
+fn dispose_twice() {
+    let prog = [
+        loadl(1),
+        prim(NEW),
+        loadl(1),
+        load(1, STR, -2),
+        prim(DISPOSE),
+        loadl(1),
+        load(1, STR, -2),
+        prim(DISPOSE),
+        halt(),
+    ];
+    let (_, _, res) = run(&prog, "");
+    assert!(matches!(res, Err(TAMError::SegmentationFault(7, _))));
+}
+
+#[rstest]
+#[case::div(vec![loadl(1), loadl(0), prim(DIV)])]
+#[case::modulo(vec![loadl(1), loadl(0), prim(MOD)])]
+fn divide_by_zero(#[case] mut prog: Vec<Instruction>) {
+    prog.push(halt());
+    let (_, _, res) = run(&prog, "");
+    assert!(matches!(res, Err(TAMError::DivideByZero(2))));
+}
+
+#[rstest]
+#[case::load(vec![load(1, SBR, 0)])]
+#[case::store(vec![loadl(1), store(1, SBR, 1)])]
+#[case::loadi(vec![loadl(5), loadi(1)])]
+#[case::storei(vec![loadl(1), loadl(5), storei(1)])]
+#[case::jump(vec![jump(100)])]
+#[case::jumpi(vec![loadl(100), jumpi()])]
+#[case::call(vec![call(SBR, 100)])]
+#[case::unallocated_heap(vec![loadl(-2), loadi(1)])]
+#[case::dispose_outside_heap(vec![loadl(1), loadl(0), prim(DISPOSE)])]
+#[case::overwritten_dynamic_link(
+    // the routine overwrites its dynamic link, then main returns into the
+    // frame it names at the top of memory
+    vec![call(SBR, 7), loadl(1), loadl(1), loadl(1), loadl(1), ret(4, 0), halt(),
+         loadl(-4), store(1, LB, 1), ret(0, 0)],
+)]
+fn segmentation_fault(#[case] mut prog: Vec<Instruction>) {
+    prog.push(halt());
+    let (_, _, res) = run(&prog, "");
+    assert!(matches!(res, Err(TAMError::SegmentationFault(_, _))));
+}
+
+#[rstest]
+fn invalid_opcode() {
+    let prog = [
+        loadl(1),
+        Instruction {
+            op: 9,
+            r: 0,
+            n: 0,
+            d: 0,
+        },
+        halt(),
+    ];
+    let (tam, _, res) = run(&prog, "");
+    assert!(matches!(res, Err(TAMError::InvalidOpcode(1, 9))));
+    assert_eq!(vec![1], stack(&tam));
+}
+
+#[rstest]
+#[case::pop(vec![loadl(1), pop(1, 1)])]
+#[case::loadi(vec![loadi(1)])]
+#[case::store(vec![store(1, SBR, 0)])]
+#[case::storei(vec![loadl(0), storei(1)])]
+#[case::calli(vec![loadl(0), calli()])]
+#[case::jumpi(vec![jumpi()])]
+#[case::jumpif(vec![jumpif(0, 0)])]
+#[case::return_args(vec![call(SBR, 2), halt(), ret(0, 1)])]
+#[case::primitive(vec![loadl(1), prim(ADD)])]
+#[case::eq_operands(vec![loadl(1), loadl(2), prim(EQ)])]
+fn stack_underflow(#[case] mut prog: Vec<Instruction>) {
+    prog.push(halt());
+    let (_, _, res) = run(&prog, "");
+    assert!(matches!(res, Err(TAMError::StackUnderflow(_))), "{res:?}");
+}
+
+#[rstest]
+#[case::return_results(vec![call(SBR, 2), halt(), ret(1, 0)])]
+#[case::pop(vec![call(SBR, 2), halt(), pop(0, 1), ret(0, 0)])]
+fn strict_frame_underflow(#[case] prog: Vec<Instruction>) {
+    let (_, _, res) = run(&prog, "");
+    assert!(!matches!(res, Err(TAMError::StackUnderflow(_))));
+
+    let (_, _, res) = run_strict(&prog, "");
+    assert!(matches!(res, Err(TAMError::StackUnderflow(2))), "{res:?}");
+}
+
+#[rstest]
+fn new_collides_with_stack() {
+    let prog = [loadl(-1), prim(NEW), halt()];
+    let (_, _, res) = run(&prog, "");
+    assert!(matches!(res, Err(TAMError::HeapExhausted(1, 65535))));
+}
+
+#[rstest]
+fn run_past_end_of_code() {
+    let prog = [loadl(1)];
+    let (_, _, res) = run(&prog, "");
+    assert!(matches!(res, Err(TAMError::CodeOutOfBounds(n)) if n == MEM_SIZE - 29));
+
+    let (tam, _, res) = run_strict(&prog, "");
+    assert!(matches!(res, Err(TAMError::CodeOutOfBounds(1))));
+    assert_eq!(vec![1], stack(&tam));
+}
+
+#[rstest]
+#[case::no_digits("x\n", 0)]
+#[case::end_of_input("", 0)]
+#[case::out_of_range("40000\n", -25536)]
+fn getint_bad_input(#[case] input: &str, #[case] lenient: i16) {
+    let prog = [push(1), loada(SBR, 0), prim(GETINT), halt()];
+
+    let (tam, _, res) = run(&prog, input);
+    assert!(res.is_ok(), "{}", res.unwrap_err());
+    assert_eq!(vec![lenient], stack(&tam));
+
+    let (_, _, res) = run_strict(&prog, input);
+    assert!(matches!(res, Err(TAMError::BadInput(2, _))));
+}
+
+/// Run pseudo-random bytecode and input in both modes, checking that every fault
+/// is reported as an error rather than a panic.
+#[rstest]
+fn random_bytecode_never_panics() {
+    let mut seed: u64 = 0x2545f4914f6cdd1d;
+    let mut next = move || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 32) as u32
+    };
+
+    for i in 0..400 {
+        let len = 1 + next() as usize % 32;
+        let prog: Vec<Instruction> =
+            (0..len).map(|_| Instruction::from(next())).collect();
+        let input: String = (0..next() % 16)
+            .map(|_| b" -0123456789x\n"[next() as usize % 14] as char)
+            .collect();
+
+        let mut tam = TAM::new(false);
+        tam.set_strict(i % 2 == 0);
+        tam.set_io(Box::new(BufferIo::new(input.as_str())));
+        tam.load_instructions(&prog).unwrap();
+        tam.reset();
+        if let Err(e) = tam.run_for(2000) {
+            FaultReport::new(&tam, &e, &Default::default()).to_string();
+        }
+    }
+}
+
+#[rstest]
+fn recursive_factorial() {
+    let prog = [
+        push(1),
+        loada(SBR, 0),
+        prim(GETINT),
+        load(1, SBR, 0),
+        call(SBR, 9),
+        prim(PUTINT),
+        prim(PUTEOL),
+        halt(),
+        halt(),
+        // fac(n): if n = 0 then 1 else n * fac(n - 1)
+        load(1, LB, -1),
+        jumpif(0, 18),
+        load(1, LB, -1),
+        load(1, LB, -1),
+        prim(DEC),
+        call(SBR, 9),
+        prim(MUL),
+        ret(1, 1),
+        halt(),
+        loadl(1),
+        ret(1, 1),
+    ];
+    let (_, out, res) = run(&prog, "6\n");
+    assert!(res.is_ok(), "{}", res.unwrap_err());
+    assert_eq!("720\n", out);
+}
+
+#[rstest]
+#[case::l1_reads_enclosing_local(
+    // main calls p, p calls its nested procedure q, q reads p's local via L1
+    vec![push(2), call(SBR, 4), halt(), halt(),
+         loadl(11), call(LB, 8), ret(1, 0), halt(),
+         load(1, L1, 3), ret(1, 0)],
+    vec![0, 0, 11],
+)]
+#[case::l2_reads_twice_enclosing_local(
+    // p declares x = 22, q is nested in p, r is nested in q and reads x via L2
+    vec![push(2), call(SBR, 4), halt(), halt(),
+         loadl(22), call(LB, 8), ret(1, 0), halt(),
+         call(LB, 11), ret(1, 0), halt(),
+         load(1, L2, 3), ret(1, 0)],
+    vec![0, 0, 22],
+)]
+#[case::l1_as_static_link(
+    // q and r are both nested in p; q calls its sibling r, passing L1 as the
+    // static link, and r reads p's local via L1
+    vec![call(SBR, 3), halt(), halt(),
+         loadl(33), call(LB, 7), ret(1, 0), halt(),
+         call(L1, 10), ret(1, 0), halt(),
+         load(1, L1, 3), ret(1, 0)],
+    vec![33],
+)]
+#[case::l1_store(
+    vec![call(SBR, 3), halt(), halt(),
+         push(1), call(LB, 7), ret(1, 0), halt(),
+         loadl(44), store(1, L1, 3), load(1, L1, 3), ret(1, 0)],
+    vec![44],
+)]
+fn display_registers(#[case] prog: Vec<Instruction>, #[case] expected: Vec<i16>) {
+    let (tam, _, res) = run(&prog, "");
+    assert!(res.is_ok(), "{}", res.unwrap_err());
+    assert_eq!(expected, stack(&tam));
+}
@@ -0,0 +1,29 @@
+//! Tests of code coverage.
+
+mod support;
+
+use rstest::*;
+use support::*;
+
+#[rstest]
+fn coverage_counts_hits_and_branches() {
+    let prog = [
+        loadl(2),
+        load(1, STR, -1),
+        jumpif(0, 6),
+        prim(DEC),
+        jump(1),
+        halt(),
+        halt(),
+    ];
+    let (mut tam, _) = machine(&prog, "");
+    tam.set_coverage(true);
+    tam.run().unwrap();
+
+    let coverage = tam.coverage().unwrap();
+    assert_eq!(1, coverage.hits(0));
+    assert_eq!(3, coverage.hits(2));
+    assert_eq!(0, coverage.hits(5));
+    assert_eq!([1, 2], coverage.branch(2));
+    assert_eq!([0, 0], coverage.branch(3));
+}
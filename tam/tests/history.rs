@@ -0,0 +1,116 @@
+//! Tests of the execution history used to run programs backwards.
+
+mod support;
+
+use common::instruction::{Instruction, Register};
+use rstest::*;
+use support::*;
+use tam::{history::HistoryConfig, Status, TAM};
+
+/// A program that allocates a block, counts down from 4 into it, and frees it.
+fn countdown() -> Vec<Instruction> {
+    vec![
+        loadl(2),
+        prim(NEW),
+        loadl(4),
+        load(1, SBR, 1),
+        load(1, SBR, 0),
+        storei(1),
+        load(1, SBR, 1),
+        prim(DEC),
+        store(1, SBR, 1),
+        load(1, SBR, 1),
+        jumpif(0, 12),
+        jump(3),
+        loadl(2),
+        load(1, SBR, 0),
+        prim(DISPOSE),
+        halt(),
+    ]
+}
+
+fn run_recorded(prog: &[Instruction], config: HistoryConfig) -> TAM {
+    let (mut tam, _) = machine(prog, "");
+    tam.set_history(Some(config));
+    tam.reset();
+    tam
+}
+
+#[rstest]
+fn history_rewinds_to_every_step() {
+    let config = HistoryConfig {
+        budget: 1 << 24,
+        checkpoint_interval: 5,
+    };
+    let mut tam = run_recorded(&countdown(), config);
+    let snapshot = |tam: &TAM| {
+        let registers: Vec<usize> = Register::ALL.map(|r| tam.register(r)).to_vec();
+        (registers, tam.data().to_vec(), tam.heap_usage())
+    };
+    let mut states = vec![snapshot(&tam)];
+    while tam.step().unwrap() == Status::Running {
+        states.push(snapshot(&tam));
+    }
+    states.push(snapshot(&tam));
+    let end = tam.steps();
+    assert_eq!(end as usize + 1, states.len());
+
+    for steps in [end - 1, 23, 22, 9, 0] {
+        assert!(tam.rewind_to(steps));
+        assert_eq!(steps, tam.steps());
+        assert!(
+            states[steps as usize] == snapshot(&tam),
+            "state after {steps} steps"
+        );
+    }
+    assert!(!tam.is_halted());
+    assert!(!tam.reverse_step());
+
+    while tam.step().unwrap() == Status::Running {}
+    assert!(states[end as usize] == snapshot(&tam));
+}
+
+#[rstest]
+fn history_undoes_fault() {
+    let mut tam =
+        run_recorded(&[loadl(1), loadl(0), prim(DIV)], HistoryConfig::default());
+    while tam.step().is_ok() {}
+    assert_eq!(3, tam.steps());
+    assert!(tam.reverse_step());
+    assert_eq!(2, tam.register(Register::CP));
+    assert_eq!(&[1, 0], &tam.data()[..2]);
+}
+
+#[rstest]
+fn history_last_write() {
+    let mut tam = run_recorded(&countdown(), HistoryConfig::default());
+    assert_eq!(None, tam.last_write(1));
+    while tam.step().unwrap() == Status::Running {}
+
+    let write = tam.last_write(1).unwrap();
+    assert_eq!((8, 1, 0), (write.loc, write.old, write.new));
+    assert!(tam.rewind_to(write.step - 1));
+    assert_eq!(8, tam.register(Register::CP));
+    let previous = tam.last_write(1).unwrap();
+    assert_eq!(
+        (write.step - 9, 2, 1),
+        (previous.step, previous.old, previous.new)
+    );
+}
+
+#[rstest]
+fn history_budget_forgets_oldest_steps() {
+    let config = HistoryConfig {
+        budget: 2048,
+        checkpoint_interval: 5,
+    };
+    let mut tam = run_recorded(&countdown(), config);
+    while tam.step().unwrap() == Status::Running {}
+
+    let history = tam.history().unwrap();
+    let earliest = history.earliest().unwrap();
+    assert!(earliest > 0);
+    assert!(history.bytes() <= 2048);
+    assert!(!tam.rewind_to(earliest - 1));
+    assert!(tam.rewind_to(earliest));
+}
@@ -0,0 +1,90 @@
+//! Tests of the limits on the resources a program may use.
+
+mod support;
+
+use common::instruction::Instruction;
+use rstest::*;
+use support::*;
+use tam::{
+    limits::{Limit, Limits},
+    TAMError,
+};
+
+#[rstest]
+#[case::steps(
+    vec![loadl(1), jump(0)],
+    Limits { max_steps: Some(10), ..Limits::default() },
+    Limit::Steps(10),
+)]
+#[case::stack(
+    vec![loadl(1), loadl(2), loadl(3)],
+    Limits { max_stack: Some(2), ..Limits::default() },
+    Limit::Stack(2),
+)]
+#[case::recursion(
+    vec![call(SBR, 0)],
+    Limits { max_stack: Some(30), ..Limits::default() },
+    Limit::Stack(30),
+)]
+#[case::heap(
+    vec![loadl(4), prim(NEW), loadl(4), prim(NEW)],
+    Limits { max_heap: Some(6), ..Limits::default() },
+    Limit::Heap(6),
+)]
+#[case::output(
+    vec![loadl(123), prim(PUTINT), loadl(45), prim(PUTINT)],
+    Limits { max_output: Some(4), ..Limits::default() },
+    Limit::Output(4),
+)]
+fn limit_exceeded(
+    #[case] mut prog: Vec<Instruction>,
+    #[case] limits: Limits,
+    #[case] expected: Limit,
+) {
+    prog.push(halt());
+    let (mut tam, io) = machine(&prog, "");
+    tam.set_limits(limits);
+
+    let res = tam.run();
+
+    assert!(
+        matches!(res, Err(TAMError::LimitExceeded(_, limit)) if limit == expected),
+        "{res:?}"
+    );
+    assert!(io.output().len() <= limits.max_output.unwrap_or(usize::MAX));
+}
+
+#[rstest]
+fn limits_reset_with_machine() {
+    let (mut tam, _) = machine(&[loadl(7), prim(PUTINT), halt()], "");
+    tam.set_limits(Limits {
+        max_steps: Some(3),
+        max_output: Some(1),
+        ..Limits::default()
+    });
+
+    assert!(tam.run().is_ok());
+    assert_eq!(3, tam.steps());
+    assert!(tam.run().is_ok());
+}
+
+#[rstest]
+fn refused_block_is_not_allocated() {
+    let prog = [loadl(4), prim(NEW), loadl(4), prim(NEW), halt()];
+    let (mut tam, _) = machine(&prog, "");
+    tam.set_limits(Limits {
+        max_heap: Some(6),
+        ..Limits::default()
+    });
+
+    let res = tam.run();
+    assert!(
+        matches!(res, Err(TAMError::LimitExceeded(3, Limit::Heap(6)))),
+        "{res:?}"
+    );
+    let usage = tam.heap_usage();
+    assert_eq!(
+        (1, 1, 5),
+        (usage.allocations, usage.live_blocks, usage.extent)
+    );
+}
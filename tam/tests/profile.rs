@@ -0,0 +1,46 @@
+//! Tests of the execution profiler.
+
+mod support;
+
+use common::instruction::{Opcode, Primitive};
+use rstest::*;
+use support::*;
+use tam::profile::ProcStats;
+
+#[rstest]
+fn profile_counts_procedures() {
+    let prog = [
+        call(SBR, 3),
+        call(SBR, 3),
+        halt(),
+        loadl(1),
+        prim(PUTINT),
+        ret(0, 0),
+    ];
+    let (mut tam, _) = machine(&prog, "");
+    tam.set_profile(true);
+    tam.run().unwrap();
+
+    let profile = tam.profile().unwrap();
+    assert_eq!(9, profile.total());
+    assert_eq!(2, profile.count(4));
+    assert_eq!(2, profile.primitive_count(Primitive::PutInt));
+    assert_eq!(4, profile.opcode_count(Opcode::Call));
+    assert_eq!(
+        vec![
+            ProcStats {
+                entry: 0,
+                calls: 1,
+                inclusive: 9,
+                exclusive: 3
+            },
+            ProcStats {
+                entry: 3,
+                calls: 2,
+                inclusive: 6,
+                exclusive: 6
+            },
+        ],
+        profile.procedures()
+    );
+}
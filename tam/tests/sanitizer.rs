@@ -0,0 +1,139 @@
+//! Tests of the heap sanitizer, which checks every heap access and dispose.
+
+mod support;
+
+use common::instruction::Instruction;
+use rstest::*;
+use support::*;
+use tam::{heap::HeapViolation, TAMError};
+
+fn run_sanitized(prog: &[Instruction]) -> Result<(), TAMError> {
+    let (mut tam, _) = machine(prog, "");
+    tam.set_sanitize(true);
+    tam.run()
+}
+
+#[rstest]
+fn sanitizer_accepts_correct_program() {
+    let prog = [
+        loadl(2),
+        prim(NEW),
+        loadl(5),
+        load(1, STR, -2),
+        storei(1),
+        load(1, STR, -1),
+        loadi(1),
+        pop(0, 1),
+        loadl(2),
+        load(1, STR, -2),
+        prim(DISPOSE),
+        halt(),
+    ];
+    let res = run_sanitized(&prog);
+    assert!(res.is_ok(), "{}", res.unwrap_err());
+}
+
+#[rstest]
+fn sanitizer_use_after_free() {
+    let prog = [
+        loadl(2),
+        prim(NEW),
+        loadl(2),
+        load(1, STR, -2),
+        prim(DISPOSE),
+        load(1, STR, -1),
+        loadi(1),
+        halt(),
+    ];
+    let res = run_sanitized(&prog);
+    let Err(TAMError::HeapViolation(6, HeapViolation::UseAfterFree(_, block))) = res
+    else {
+        panic!("{res:?}");
+    };
+    assert_eq!((1, Some(4)), (block.alloc_site, block.free_site));
+}
+
+#[rstest]
+#[case::header(vec![loadl(1), prim(NEW), loadl(1), load(1, STR, -2), loadl(1), prim(SUB), storei(1)])]
+#[case::between_blocks(vec![loadl(1), prim(NEW), loadl(1), prim(NEW), load(1, STR, -1), prim(INC), loadi(1)])]
+fn sanitizer_unallocated(#[case] mut prog: Vec<Instruction>) {
+    prog.push(halt());
+    let res = run_sanitized(&prog);
+    assert!(
+        matches!(
+            res,
+            Err(TAMError::HeapViolation(_, HeapViolation::Unallocated(_)))
+        ),
+        "{res:?}"
+    );
+}
+
+#[rstest]
+fn sanitizer_double_free() {
+    let prog = [
+        loadl(1),
+        prim(NEW),
+        loadl(1),
+        load(1, STR, -2),
+        prim(DISPOSE),
+        loadl(1),
+        load(1, STR, -2),
+        prim(DISPOSE),
+        halt(),
+    ];
+    let res = run_sanitized(&prog);
+    assert!(
+        matches!(
+            res,
+            Err(TAMError::HeapViolation(7, HeapViolation::DoubleFree(block)))
+                if block.free_site == Some(4)
+        ),
+        "{res:?}"
+    );
+}
+
+#[rstest]
+#[case::not_a_block(vec![loadl(2), prim(NEW), loadl(1), load(1, STR, -2), prim(INC), prim(DISPOSE)])]
+#[case::never_allocated(vec![loadl(1), loadl(-3), prim(DISPOSE)])]
+fn sanitizer_invalid_free(#[case] mut prog: Vec<Instruction>) {
+    prog.push(halt());
+    let res = run_sanitized(&prog);
+    assert!(
+        matches!(
+            res,
+            Err(TAMError::HeapViolation(_, HeapViolation::InvalidFree(_)))
+        ),
+        "{res:?}"
+    );
+}
+
+#[rstest]
+fn sanitizer_wrong_size() {
+    let prog = [
+        loadl(2),
+        prim(NEW),
+        loadl(3),
+        load(1, STR, -2),
+        prim(DISPOSE),
+        halt(),
+    ];
+    let res = run_sanitized(&prog);
+    assert!(
+        matches!(
+            res,
+            Err(TAMError::HeapViolation(4, HeapViolation::WrongSize(_, 3)))
+        ),
+        "{res:?}"
+    );
+}
+
+#[rstest]
+fn sanitizer_leak() {
+    let prog = [loadl(2), prim(NEW), loadl(3), prim(NEW), halt()];
+    let res = run_sanitized(&prog);
+    let Err(TAMError::HeapViolation(4, HeapViolation::Leak(blocks))) = res else {
+        panic!("{res:?}");
+    };
+    let sites: Vec<usize> = blocks.iter().map(|b| b.alloc_site).collect();
+    assert_eq!(vec![3, 1], sites);
+}
@@ -0,0 +1,127 @@
+//! Instruction builders and a fixture shared by the integration tests.
+
+#![allow(dead_code)]
+
+use common::instruction::{Instruction, Opcode, Primitive, Register};
+use tam::{io::BufferIo, TAM};
+
+pub const CB: u8 = Register::CB as u8;
+pub const PB: u8 = Register::PB as u8;
+pub const SBR: u8 = Register::SB as u8;
+pub const STR: u8 = Register::ST as u8;
+pub const HT: u8 = Register::HT as u8;
+pub const LB: u8 = Register::LB as u8;
+pub const L1: u8 = Register::L1 as u8;
+pub const L2: u8 = Register::L2 as u8;
+
+pub fn instr(op: u8, r: u8, n: u8, d: i16) -> Instruction {
+    Instruction { op, r, n, d }
+}
+
+pub fn load(n: u8, r: u8, d: i16) -> Instruction {
+    instr(Opcode::Load as u8, r, n, d)
+}
+
+pub fn loada(r: u8, d: i16) -> Instruction {
+    instr(Opcode::LoadA as u8, r, 0, d)
+}
+
+pub fn loadi(n: u8) -> Instruction {
+    instr(Opcode::LoadI as u8, 0, n, 0)
+}
+
+pub fn loadl(d: i16) -> Instruction {
+    instr(Opcode::LoadL as u8, 0, 0, d)
+}
+
+pub fn store(n: u8, r: u8, d: i16) -> Instruction {
+    instr(Opcode::Store as u8, r, n, d)
+}
+
+pub fn storei(n: u8) -> Instruction {
+    instr(Opcode::StoreI as u8, 0, n, 0)
+}
+
+pub fn call(n: u8, d: i16) -> Instruction {
+    instr(Opcode::Call as u8, CB, n, d)
+}
+
+pub fn prim(d: i16) -> Instruction {
+    instr(Opcode::Call as u8, PB, 0, d)
+}
+
+pub fn calli() -> Instruction {
+    instr(Opcode::CallI as u8, 0, 0, 0)
+}
+
+pub fn ret(n: u8, d: i16) -> Instruction {
+    instr(Opcode::Return as u8, 0, n, d)
+}
+
+pub fn push(d: i16) -> Instruction {
+    instr(Opcode::Push as u8, 0, 0, d)
+}
+
+pub fn pop(n: u8, d: i16) -> Instruction {
+    instr(Opcode::Pop as u8, 0, n, d)
+}
+
+pub fn jump(d: i16) -> Instruction {
+    instr(Opcode::Jump as u8, CB, 0, d)
+}
+
+pub fn jumpi() -> Instruction {
+    instr(Opcode::JumpI as u8, 0, 0, 0)
+}
+
+pub fn jumpif(n: u8, d: i16) -> Instruction {
+    instr(Opcode::JumpIf as u8, CB, n, d)
+}
+
+pub fn halt() -> Instruction {
+    instr(Opcode::Halt as u8, 0, 0, 0)
+}
+
+pub const ID: i16 = Primitive::Id as i16;
+pub const NOT: i16 = Primitive::Not as i16;
+pub const AND: i16 = Primitive::And as i16;
+pub const OR: i16 = Primitive::Or as i16;
+pub const INC: i16 = Primitive::Inc as i16;
+pub const DEC: i16 = Primitive::Dec as i16;
+pub const NEG: i16 = Primitive::Neg as i16;
+pub const ADD: i16 = Primitive::Add as i16;
+pub const SUB: i16 = Primitive::Sub as i16;
+pub const MUL: i16 = Primitive::Mul as i16;
+pub const DIV: i16 = Primitive::Div as i16;
+pub const MOD: i16 = Primitive::Mod as i16;
+pub const LT: i16 = Primitive::Lt as i16;
+pub const LE: i16 = Primitive::Le as i16;
+pub const GE: i16 = Primitive::Ge as i16;
+pub const GT: i16 = Primitive::Gt as i16;
+pub const EQ: i16 = Primitive::Eq as i16;
+pub const NE: i16 = Primitive::Ne as i16;
+pub const EOL: i16 = Primitive::Eol as i16;
+pub const EOF: i16 = Primitive::Eof as i16;
+pub const GET: i16 = Primitive::Get as i16;
+pub const PUT: i16 = Primitive::Put as i16;
+pub const GETEOL: i16 = Primitive::GetEol as i16;
+pub const PUTEOL: i16 = Primitive::PutEol as i16;
+pub const GETINT: i16 = Primitive::GetInt as i16;
+pub const PUTINT: i16 = Primitive::PutInt as i16;
+pub const NEW: i16 = Primitive::New as i16;
+pub const DISPOSE: i16 = Primitive::Dispose as i16;
+
+/// Load `prog` into a new machine that reads `input`, returning the machine to be
+/// configured and run, and its I/O to read back what the program printed.
+pub fn machine(prog: &[Instruction], input: &str) -> (TAM, BufferIo) {
+    let mut tam = TAM::new(false);
+    let io = BufferIo::new(input);
+    tam.set_io(Box::new(io.clone()));
+    tam.load_instructions(prog).unwrap();
+    (tam, io)
+}
+
+/// Get the words on the stack, from SB up.
+pub fn stack(tam: &TAM) -> Vec<i16> {
+    tam.data()[tam.register(Register::SB)..tam.register(Register::ST)].to_vec()
+}
@@ -0,0 +1,110 @@
+//! Tests of execution traces.
+
+mod support;
+
+use std::{
+    cell::RefCell,
+    io::{BufWriter, Write},
+    rc::Rc,
+};
+
+use common::instruction::Instruction;
+use rstest::*;
+use support::*;
+use tam::{
+    limits::Limits,
+    trace::{TraceFilter, Tracer},
+};
+
+/// A trace destination the test can read back after the machine has written to it.
+#[derive(Clone, Default)]
+struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn run_traced(prog: &[Instruction], filter: TraceFilter) -> Vec<serde_json::Value> {
+    let buf = SharedBuf::default();
+    let (mut tam, _) = machine(prog, "");
+    tam.set_tracer(Some(Tracer::new(Box::new(buf.clone()), filter)));
+    let _ = tam.run();
+    let out = String::from_utf8(buf.0.take()).unwrap();
+    out.lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect()
+}
+
+#[rstest]
+fn trace_records_effects() {
+    let prog = [
+        push(1),
+        loadl(65),
+        store(1, SBR, 0),
+        load(1, SBR, 0),
+        prim(PUT),
+        halt(),
+    ];
+    let records = run_traced(&prog, TraceFilter::default());
+    assert_eq!(6, records.len());
+    assert_eq!(
+        serde_json::json!({
+            "step": 3,
+            "addr": 2,
+            "instr": "store   1, [sb+0]",
+            "regs": {"st": 1},
+            "writes": [[0, 65]],
+        }),
+        records[2]
+    );
+    assert_eq!("A", records[4]["output"]);
+}
+
+#[rstest]
+#[case::addrs(TraceFilter { addrs: vec![4..5, 9..10], ..TraceFilter::default() }, vec![3, 6])]
+#[case::steps(TraceFilter { steps: vec![1..3, 100..200], ..TraceFilter::default() }, vec![1, 2])]
+#[case::procs(TraceFilter { procs: vec![3], ..TraceFilter::default() }, vec![2, 3, 5, 6])]
+fn trace_filter(#[case] filter: TraceFilter, #[case] steps: Vec<u64>) {
+    let prog = [call(SBR, 3), call(SBR, 3), halt(), loadl(1), ret(0, 0)];
+    let records = run_traced(&prog, filter);
+    let found: Vec<u64> = records
+        .iter()
+        .map(|r| r["step"].as_u64().unwrap())
+        .collect();
+    assert_eq!(steps, found);
+}
+
+#[rstest]
+fn trace_records_error() {
+    let records = run_traced(&[loadl(1), loadl(0), prim(DIV)], TraceFilter::default());
+    assert_eq!("divide by zero attempted at loc 0002", records[2]["error"]);
+}
+
+#[rstest]
+#[case::fault(vec![loadl(1), loadl(0), prim(DIV)], Limits::default(), 3)]
+#[case::limit(
+    vec![loadl(1), jump(0)],
+    Limits { max_steps: Some(10), ..Limits::default() },
+    10,
+)]
+fn trace_flushed_when_program_stops(
+    #[case] prog: Vec<Instruction>,
+    #[case] limits: Limits,
+    #[case] records: usize,
+) {
+    let buf = SharedBuf::default();
+    let (mut tam, _) = machine(&prog, "");
+    tam.set_limits(limits);
+    let out = BufWriter::new(buf.clone());
+    tam.set_tracer(Some(Tracer::new(Box::new(out), TraceFilter::default())));
+
+    assert!(tam.run().is_err());
+    let out = String::from_utf8(buf.0.take()).unwrap();
+    assert_eq!(records, out.lines().count());
+}
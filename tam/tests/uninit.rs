@@ -0,0 +1,86 @@
+//! Tests of the detection of reads of uninitialized memory.
+
+mod support;
+
+use common::instruction::Instruction;
+use rstest::*;
+use support::*;
+use tam::{
+    machine::MEM_SIZE,
+    shadow::{UninitMode, UninitRead},
+    TAMError, TAM,
+};
+
+fn run_uninit(
+    prog: &[Instruction],
+    input: &str,
+    mode: UninitMode,
+) -> (TAM, Result<(), TAMError>) {
+    let (mut tam, _) = machine(prog, input);
+    tam.set_uninit_mode(mode);
+    let res = tam.run();
+    (tam, res)
+}
+
+#[rstest]
+#[case::store(vec![push(1), loadl(4), store(1, SBR, 0), load(1, SBR, 0)], "")]
+#[case::storei(vec![push(1), loadl(4), loada(SBR, 0), storei(1), load(1, SBR, 0)], "")]
+#[case::getint(vec![push(1), loada(SBR, 0), prim(GETINT), load(1, SBR, 0)], "4\n")]
+#[case::get(vec![push(1), loada(SBR, 0), prim(GET), load(1, SBR, 0)], "a")]
+#[case::pushed_value(vec![loadl(4), load(1, SBR, 0)], "")]
+#[case::heap(vec![loadl(1), prim(NEW), loadl(4), load(1, STR, -2), storei(1), loadi(1)], "")]
+fn uninit_accepts_initialized_reads(
+    #[case] mut prog: Vec<Instruction>,
+    #[case] input: &str,
+) {
+    prog.push(halt());
+    let (tam, res) = run_uninit(&prog, input, UninitMode::Trap);
+    assert!(res.is_ok(), "{res:?}");
+    assert!(tam.uninit_reads().is_empty());
+}
+
+#[rstest]
+#[case::load(vec![push(2), load(2, SBR, 0)], 1, 0)]
+#[case::loadi(vec![push(1), loada(SBR, 0), loadi(1)], 2, 0)]
+#[case::repushed(vec![loadl(4), pop(0, 1), push(1), load(1, SBR, 0)], 3, 0)]
+#[case::heap(vec![loadl(1), prim(NEW), loadi(1)], 2, MEM_SIZE - 1)]
+fn uninit_trap(
+    #[case] mut prog: Vec<Instruction>,
+    #[case] loc: usize,
+    #[case] addr: usize,
+) {
+    prog.push(halt());
+    let (_, res) = run_uninit(&prog, "", UninitMode::Trap);
+    assert!(
+        matches!(res, Err(TAMError::UninitializedRead(l, a)) if l == loc && a == addr),
+        "{res:?}"
+    );
+}
+
+#[rstest]
+fn uninit_report() {
+    let prog = [push(2), load(2, SBR, 0), load(1, SBR, 1), pop(0, 3), halt()];
+    let (tam, res) = run_uninit(&prog, "", UninitMode::Report);
+    assert!(res.is_ok(), "{res:?}");
+    let expected = [
+        UninitRead {
+            loc: 1,
+            addr: 0,
+            count: 2,
+        },
+        UninitRead {
+            loc: 2,
+            addr: 1,
+            count: 1,
+        },
+    ];
+    assert_eq!(&expected, tam.uninit_reads());
+}
+
+#[rstest]
+fn uninit_off_reads_zero() {
+    let prog = [push(1), load(1, SBR, 0), halt()];
+    let (tam, res) = run_uninit(&prog, "", UninitMode::Off);
+    assert!(res.is_ok(), "{res:?}");
+    assert_eq!(vec![0, 0], stack(&tam));
+}
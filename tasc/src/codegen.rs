@@ -1,48 +1,149 @@
-use std::collections::HashMap;
+use common::{
+    container::{Symbol, SymbolKind},
+    debug::{DebugInfo, SourceLoc},
+    instruction::{Instruction, Opcode, Register},
+};
 
-use common::instruction::Instruction;
+use crate::{
+    errors::AsmError,
+    expr::{definitions, Def, Env, Field},
+    InstrData, Item, Program, Words,
+};
 
-use crate::InstrData;
+/// The most words of static data a program may define. `tam` loads the data at SB
+/// in a data store of 65535 words, below the heap at the last word, and leaves at
+/// least one word above it for the stack.
+pub const MAX_DATA: usize = 65533;
 
-pub fn gen_code(data: Vec<InstrData>) -> Vec<Instruction> {
-    let label_indices = get_label_indies(&data);
-    let named_dests = set_named_dests(&label_indices, &data);
-    named_dests.iter().map(|d| d.data).collect()
-}
+/// Generate a program from the parsed statements of `source`, the contents of
+/// the file `filename`.
+pub fn gen_code(
+    items: Vec<Item>,
+    filename: &str,
+    source: &str,
+) -> Result<Program, Vec<AsmError>> {
+    let mut env = Env::new(&items);
+    let line_starts = line_starts(source);
+
+    let mut code = Vec::new();
+    let mut locs = Vec::new();
+    let mut data = Vec::new();
+    let mut too_large = false;
+    for (i, item) in items.iter().enumerate() {
+        match item {
+            Item::Instr(instr) => {
+                code.push(resolve(&mut env, instr));
+                locs.push(source_loc(source, &line_starts, instr.span.start));
+            }
+            Item::Data(def) => {
+                match &def.words {
+                    Words::Values(values) => data.extend(values.iter().map(|v| {
+                        env.operand(v, Field::Word).unwrap_or(0) as u16 as i16
+                    })),
+                    Words::Space(_) => {
+                        data.resize(data.len() + env.size(i).unwrap_or(0) as usize, 0)
+                    }
+                    Words::String(words) => data.extend(words),
+                }
+                // Report only the directive that first goes past the limit.
+                if data.len() > MAX_DATA && !too_large {
+                    env.errors
+                        .push(AsmError::DataTooLarge(def.span.clone(), MAX_DATA));
+                    too_large = true;
+                }
+            }
+            // Constants are worked out even if unused, so that their errors are
+            // reported.
+            Item::Equ(def) => {
+                env.value(&def.name.0, &def.name.1);
+            }
+        }
+    }
 
-fn get_label_indies(data: &Vec<InstrData>) -> HashMap<String, usize> {
-    let mut indices = HashMap::new();
-    for (i, instr) in data.iter().enumerate() {
-        if let Some(lbl) = &instr.label {
-            indices.insert(lbl.clone(), i);
+    let mut symbols = Vec::new();
+    for ((name, span), def) in definitions(&items) {
+        let kind = match def {
+            Def::Code(_) => SymbolKind::Code,
+            Def::Data(_) => SymbolKind::Data,
+            Def::Const(_) => continue,
+        };
+        let addr = env.value(name, span).and_then(|a| u16::try_from(a).ok());
+        if let Some(addr) = addr {
+            symbols.push(Symbol {
+                name: name.clone(),
+                kind,
+                addr,
+            });
         }
     }
+    symbols.sort_by_key(|sym| (sym.kind == SymbolKind::Data, sym.addr));
 
-    indices
+    if !env.errors.is_empty() {
+        return Err(env.errors);
+    }
+
+    Ok(Program {
+        code,
+        data,
+        symbols,
+        debug: DebugInfo {
+            file: filename.to_string(),
+            locs,
+        },
+    })
 }
 
-fn set_named_dests(
-    indices: &HashMap<String, usize>,
-    data: &Vec<InstrData>,
-) -> Vec<InstrData> {
-    let mut new_data = Vec::new();
-    for d in data {
-        if let None = d.named_dest {
-            new_data.push((*d).clone());
-            continue;
+/// Fill in the operands of `instr` from their expressions.
+fn resolve(env: &mut Env, instr: &InstrData) -> Instruction {
+    let mut data = instr.data;
+    if let Some(n) = &instr.n {
+        data.n = env.operand(n, Field::Byte).unwrap_or(0) as u8;
+    }
+    if let Some((d, field)) = &instr.d {
+        data.d = env.operand(d, *field).unwrap_or(0) as u16 as i16;
+    }
+    if let Some((dest, span)) = &instr.named_dest {
+        if let Some(Def::Data(_)) = env.def(dest) {
+            env.errors
+                .push(AsmError::DataLabel(span.clone(), dest.clone()));
         }
-
-        let mut d1 = d.clone();
-        let offset = indices.get(d1.named_dest.as_ref().unwrap());
-        match offset {
-            None => panic!("use of undefined location: {}", d1.named_dest.unwrap()),
-            Some(i) => {
-                d1.data.r = 0;
-                d1.data.d = *i as i16;
-                new_data.push(d1);
+    }
+    if let Some((base, span)) = &instr.named_addr {
+        // Jumps and calls go to code, whichever way the address is written.
+        let to_code = [Opcode::Jump, Opcode::JumpIf, Opcode::Call]
+            .into_iter()
+            .any(|op| data.op == op as u8);
+        data.r = match env.def(base) {
+            Some(Def::Data(_)) if to_code => {
+                env.errors
+                    .push(AsmError::DataLabel(span.clone(), base.clone()));
+                Register::CB
             }
-        }
+            Some(Def::Data(_)) => Register::SB,
+            Some(Def::Const(_)) => {
+                env.errors
+                    .push(AsmError::NotALabel(span.clone(), base.clone()));
+                Register::CB
+            }
+            Some(Def::Code(_)) | None => Register::CB,
+        } as u8;
     }
+    data
+}
+
+/// Get the line and column, in characters, of the byte at `offset` in `source`.
+fn source_loc(source: &str, line_starts: &[usize], offset: usize) -> SourceLoc {
+    let line = line_starts.partition_point(|start| *start <= offset);
+    let start = line_starts[line - 1];
+    SourceLoc {
+        line: line as u32,
+        col: source[start..offset].chars().count() as u32 + 1,
+    }
+}
 
-    new_data
+/// Get the offset of the start of each line of `source`.
+pub fn line_starts(source: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
 }
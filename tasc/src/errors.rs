@@ -0,0 +1,294 @@
+use std::{
+    fmt::{Display, Error, Formatter},
+    ops::Range,
+};
+
+use lalrpop_util::{lexer::Token, ErrorRecovery, ParseError};
+
+/// Represents different errors in an assembly program.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmError {
+    /// Indicate a character that does not begin any token.
+    InvalidToken(usize),
+    /// Indicate a token that is not valid at its position.
+    UnexpectedToken(Range<usize>, String, Vec<String>),
+    /// Indicate the input ended part way through an instruction.
+    UnexpectedEof(usize, Vec<String>),
+    /// Indicate a numeric literal too large for the operand it is used in.
+    NumberOverflow(Range<usize>, i64),
+    /// Indicate a negative value too small for the operand it is used in.
+    NumberUnderflow(Range<usize>, i64),
+    /// Indicate a division by zero in a constant expression.
+    DivisionByZero(Range<usize>),
+    /// Indicate a reference to a label that is never defined.
+    UndefinedLabel(Range<usize>, String),
+    /// Indicate a label that is defined more than once.
+    DuplicateLabel(Range<usize>, String),
+    /// Indicate a constant whose value depends on itself.
+    CircularDefinition(Range<usize>, String),
+    /// Indicate a constant used where a label is needed.
+    NotALabel(Range<usize>, String),
+    /// Indicate a data label used where a code address is needed.
+    DataLabel(Range<usize>, String),
+    /// Indicate static data too large to fit in memory with the stack.
+    DataTooLarge(Range<usize>, usize),
+    /// Indicate an escape sequence in a string that is not recognised.
+    UnknownEscape(Range<usize>, char),
+    /// Indicate a character in a string that does not fit in a byte.
+    WideCharacter(Range<usize>, char),
+}
+
+/// An error recovered from while parsing.
+pub(crate) type Recovery<'input> = ErrorRecovery<usize, Token<'input>, AsmError>;
+
+impl AsmError {
+    /// Get the range of bytes in the source that the error refers to.
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Self::InvalidToken(loc) | Self::UnexpectedEof(loc, _) => *loc..*loc + 1,
+            Self::UnexpectedToken(span, _, _)
+            | Self::NumberOverflow(span, _)
+            | Self::NumberUnderflow(span, _)
+            | Self::DivisionByZero(span)
+            | Self::UndefinedLabel(span, _)
+            | Self::DuplicateLabel(span, _)
+            | Self::CircularDefinition(span, _)
+            | Self::NotALabel(span, _)
+            | Self::DataTooLarge(span, _)
+            | Self::DataLabel(span, _)
+            | Self::UnknownEscape(span, _)
+            | Self::WideCharacter(span, _) => span.clone(),
+        }
+    }
+
+    /// Render the error with the file name, line and column, and the affected
+    /// source line with the span underlined.
+    pub fn render(&self, filename: &str, source: &str) -> String {
+        let span = self.span();
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let line_no = source[..start].matches('\n').count() + 1;
+        let col = source[line_start..start].chars().count() + 1;
+        let text = source[line_start..line_end].trim_end();
+
+        let width = source[start..span.end.clamp(start, line_end)]
+            .chars()
+            .count()
+            .max(1);
+        let gutter = " ".repeat(line_no.to_string().len());
+        let indent: String = source[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        format!(
+            "error: {self}\n{gutter}--> {filename}:{line_no}:{col}\n{gutter} |\n\
+             {line_no} | {text}\n{gutter} | {indent}{}\n",
+            "^".repeat(width)
+        )
+    }
+}
+
+impl<'input> From<ParseError<usize, Token<'input>, AsmError>> for AsmError {
+    fn from(value: ParseError<usize, Token<'input>, AsmError>) -> Self {
+        match value {
+            ParseError::InvalidToken { location } => Self::InvalidToken(location),
+            ParseError::UnrecognizedEof { location, expected } => {
+                Self::UnexpectedEof(location, expected)
+            }
+            ParseError::UnrecognizedToken {
+                token: (l, t, r),
+                expected,
+            } => Self::UnexpectedToken(l..r, t.1.to_string(), expected),
+            ParseError::ExtraToken { token: (l, t, r) } => {
+                Self::UnexpectedToken(l..r, t.1.to_string(), Vec::new())
+            }
+            ParseError::User { error } => error,
+        }
+    }
+}
+
+/// Describe a terminal from the grammar in the terms a user would write it.
+fn describe_terminal(terminal: &str) -> Option<String> {
+    let desc = match terminal {
+        r##"r#"[0-9]+"#"## | r##"r#"0x[0-9A-Fa-f]+"#"## => "a number",
+        r##"r#"[A-Za-z_][A-Za-z0-9_]*"#"## => "a label",
+        r##"r#"#[^#]*#"#"## => "a comment",
+        r###"r#"\"([^\"\\\\\\n]|\\\\.)*\""#"### => "a string",
+        r##"r#"[^\s]"#"## => return None,
+        _ => return Some(format!("`{}`", terminal.trim_matches('"'))),
+    };
+    Some(desc.to_string())
+}
+
+fn fmt_expected(f: &mut Formatter<'_>, expected: &[String]) -> Result<(), Error> {
+    let mut described: Vec<String> = Vec::new();
+    for desc in expected.iter().filter_map(|t| describe_terminal(t)) {
+        if !described.contains(&desc) {
+            described.push(desc);
+        }
+    }
+
+    match &described[..] {
+        [] => Ok(()),
+        [one] => write!(f, ", expected {one}"),
+        _ => write!(f, ", expected one of {}", described.join(", ")),
+    }
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::InvalidToken(_) => write!(f, "invalid token"),
+            Self::UnexpectedToken(_, found, expected) => {
+                write!(f, "unexpected `{found}`")?;
+                fmt_expected(f, expected)
+            }
+            Self::UnexpectedEof(_, expected) => {
+                write!(f, "unexpected end of file")?;
+                fmt_expected(f, expected)
+            }
+            Self::NumberOverflow(_, max) => {
+                write!(f, "number too large, the maximum here is {max}")
+            }
+            Self::NumberUnderflow(_, min) => {
+                write!(f, "number too small, the minimum here is {min}")
+            }
+            Self::DivisionByZero(_) => write!(f, "division by zero"),
+            Self::UndefinedLabel(_, name) => {
+                write!(f, "use of undefined label `{name}`")
+            }
+            Self::DuplicateLabel(_, name) => {
+                write!(f, "label `{name}` is defined more than once")
+            }
+            Self::CircularDefinition(_, name) => {
+                write!(f, "`{name}` is defined in terms of itself")
+            }
+            Self::NotALabel(_, name) => {
+                write!(f, "`{name}` is a constant, not a label")
+            }
+            Self::DataLabel(_, name) => {
+                write!(f, "label `{name}` names data, not code")
+            }
+            Self::DataTooLarge(_, max) => write!(
+                f,
+                "static data does not fit in memory, which has room for {max} words"
+            ),
+            Self::UnknownEscape(_, c) => write!(f, "unknown escape `\\{c}` in string"),
+            Self::WideCharacter(_, c) => {
+                write!(f, "character `{c}` in string does not fit in a byte")
+            }
+        }
+    }
+}
+
+/// Parse the digits of a numeric literal.
+///
+/// Literals too large for a `u32` saturate, so that [`check_range`] reports them.
+pub(crate) fn parse_literal(digits: &str, radix: u32) -> u32 {
+    u32::from_str_radix(digits, radix).unwrap_or(u32::MAX)
+}
+
+/// Get the words of a string literal `s` starting at offset `start`: one for each
+/// character, followed by a zero.
+///
+/// The escapes `\n`, `\t`, `\0`, `\\` and `\"` are recognised. Unknown escapes and
+/// characters that `put` cannot write are recorded as errors and left out.
+pub(crate) fn parse_string(
+    errors: &mut Vec<Recovery>,
+    start: usize,
+    s: &str,
+) -> Vec<i16> {
+    let mut words = Vec::new();
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        let (c, end) = match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some((j, e)) => {
+                    let c = match e {
+                        'n' => '\n',
+                        't' => '\t',
+                        '0' => '\0',
+                        '\\' | '"' => e,
+                        _ => {
+                            let span = start + i..start + j + e.len_utf8();
+                            push_error(errors, AsmError::UnknownEscape(span, e));
+                            continue;
+                        }
+                    };
+                    (c, j + e.len_utf8())
+                }
+                None => break,
+            },
+            c => (c, i + c.len_utf8()),
+        };
+        match u8::try_from(c) {
+            Ok(byte) => words.push(byte as i16),
+            Err(_) => {
+                push_error(errors, AsmError::WideCharacter(start + i..start + end, c))
+            }
+        }
+    }
+    words.push(0);
+    words
+}
+
+fn push_error(errors: &mut Vec<Recovery>, error: AsmError) {
+    errors.push(ErrorRecovery {
+        error: ParseError::User { error },
+        dropped_tokens: Vec::new(),
+    });
+}
+
+/// Record an overflowing literal at `span` if `n` is larger than `max`.
+///
+/// Returns `n` if it is in range, and 0 otherwise so that parsing can continue.
+pub(crate) fn check_range(
+    errors: &mut Vec<Recovery>,
+    span: Range<usize>,
+    n: u32,
+    max: u32,
+) -> u32 {
+    if n <= max {
+        n
+    } else {
+        push_error(errors, AsmError::NumberOverflow(span, max as i64));
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_underlines_span() {
+        let src = "loadl 1\njump   nowhere\nhalt\n";
+        let err = AsmError::UndefinedLabel(15..22, String::from("nowhere"));
+
+        let out = err.render("prog.tasm", src);
+
+        assert_eq!(
+            "error: use of undefined label `nowhere`\n \
+             --> prog.tasm:2:8\n  |\n\
+             2 | jump   nowhere\n  |        ^^^^^^^\n",
+            out
+        );
+    }
+
+    #[test]
+    fn render_at_end_of_file() {
+        let src = "load 1,";
+        let err = AsmError::UnexpectedEof(7, vec![String::from("\"[\"")]);
+
+        let out = err.render("prog.tasm", src);
+
+        assert!(out.starts_with("error: unexpected end of file, expected `[`\n"));
+        assert!(out.contains("--> prog.tasm:1:8\n"));
+        assert!(out.ends_with("1 | load 1,\n  |        ^\n"));
+    }
+}
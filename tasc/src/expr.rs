@@ -0,0 +1,279 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::{Range, RangeInclusive},
+};
+
+use crate::{errors::AsmError, Item, Label, Words};
+
+/// An arithmetic operator in a constant expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// A constant expression in an operand, worked out once every label and constant
+/// in the program is known.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Expr {
+    pub kind: ExprKind,
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ExprKind {
+    Num(i64),
+    /// A code label, standing for its code address, a data label, standing for its
+    /// offset from SB, or a constant.
+    Name(Label),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, BinOp, Box<Expr>),
+}
+
+impl Expr {
+    pub fn num(n: i64, span: Range<usize>) -> Expr {
+        Expr {
+            kind: ExprKind::Num(n),
+            span,
+        }
+    }
+
+    pub fn name(label: Label) -> Expr {
+        Expr {
+            span: label.1.clone(),
+            kind: ExprKind::Name(label),
+        }
+    }
+
+    pub fn neg(e: Expr, span: Range<usize>) -> Expr {
+        Expr {
+            kind: ExprKind::Neg(Box::new(e)),
+            span,
+        }
+    }
+
+    pub fn binary(a: Expr, op: BinOp, b: Expr) -> Expr {
+        Expr {
+            span: a.span.start..b.span.end,
+            kind: ExprKind::Binary(Box::new(a), op, Box::new(b)),
+        }
+    }
+
+    /// Get the labels and constants used in the expression, in order.
+    pub fn names(&self) -> Vec<&Label> {
+        match &self.kind {
+            ExprKind::Num(_) => Vec::new(),
+            ExprKind::Name(label) => vec![label],
+            ExprKind::Neg(e) => e.names(),
+            ExprKind::Binary(a, _, b) => {
+                let mut names = a.names();
+                names.extend(b.names());
+                names
+            }
+        }
+    }
+}
+
+/// The operand field an expression fills, which limits the values it may take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Field {
+    /// The unsigned 8-bit `n` field of an instruction.
+    Byte,
+    /// The 16-bit `d` field of an instruction, or a word of data. Values above
+    /// 0x7fff wrap around to negative numbers.
+    Word,
+    /// The 16-bit `d` field of an instruction, holding an offset from a register.
+    Offset,
+    /// A number of words of data.
+    Size,
+}
+
+impl Field {
+    fn range(self) -> RangeInclusive<i64> {
+        match self {
+            Field::Byte => 0..=u8::MAX as i64,
+            Field::Word => i16::MIN as i64..=u16::MAX as i64,
+            Field::Offset => i16::MIN as i64..=i16::MAX as i64,
+            Field::Size => 0..=u16::MAX as i64,
+        }
+    }
+}
+
+/// What a name in a program is defined as.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Def<'a> {
+    /// A label on the instruction at a code address.
+    Code(usize),
+    /// A label on the data directive at an index into the statements.
+    Data(usize),
+    /// A constant defined by `.equ`.
+    Const(&'a Expr),
+}
+
+/// Get each label and constant defined in `items` and what it is defined as, in
+/// order of definition.
+pub fn definitions(items: &[Item]) -> Vec<(&Label, Def<'_>)> {
+    let mut defs = Vec::new();
+    let mut code_addr = 0;
+    for (i, item) in items.iter().enumerate() {
+        let def = match item {
+            Item::Instr(_) => {
+                code_addr += 1;
+                Def::Code(code_addr - 1)
+            }
+            Item::Data(_) => Def::Data(i),
+            Item::Equ(def) => Def::Const(&def.value),
+        };
+        if let Some(label) = item.label() {
+            defs.push((label, def));
+        }
+    }
+    defs
+}
+
+/// The values of the labels and constants of a program, worked out as they are
+/// needed so that a name can be used before it is defined.
+///
+/// Errors are recorded in `errors` rather than returned, and a value that cannot
+/// be worked out is `None`.
+pub(crate) struct Env<'a> {
+    items: &'a [Item],
+    defs: HashMap<&'a str, Def<'a>>,
+    values: HashMap<&'a str, Option<i64>>,
+    /// The number of words defined by each data directive worked out so far.
+    sizes: HashMap<usize, Option<i64>>,
+    /// The names whose values are being worked out, to catch circular definitions.
+    pending: HashSet<&'a str>,
+    pub errors: Vec<AsmError>,
+}
+
+impl<'a> Env<'a> {
+    /// Collect the names defined in `items`, recording any defined more than once.
+    pub fn new(items: &'a [Item]) -> Env<'a> {
+        let mut defs = HashMap::new();
+        let mut errors = Vec::new();
+        for ((name, span), def) in definitions(items) {
+            if defs.contains_key(name.as_str()) {
+                errors.push(AsmError::DuplicateLabel(span.clone(), name.clone()));
+            } else {
+                defs.insert(name.as_str(), def);
+            }
+        }
+        Env {
+            items,
+            defs,
+            values: HashMap::new(),
+            sizes: HashMap::new(),
+            pending: HashSet::new(),
+            errors,
+        }
+    }
+
+    /// Get what `name` is defined as.
+    pub fn def(&self, name: &str) -> Option<Def<'a>> {
+        self.defs.get(name).copied()
+    }
+
+    /// Get the value of the label or constant `name`, used at `span`.
+    pub fn value(&mut self, name: &str, span: &Range<usize>) -> Option<i64> {
+        let Some((&name, &def)) = self.defs.get_key_value(name) else {
+            self.errors
+                .push(AsmError::UndefinedLabel(span.clone(), name.to_string()));
+            return None;
+        };
+        if let Some(value) = self.values.get(name) {
+            return *value;
+        }
+        if !self.pending.insert(name) {
+            self.errors
+                .push(AsmError::CircularDefinition(span.clone(), name.to_string()));
+            return None;
+        }
+
+        let value = match def {
+            Def::Code(addr) => Some(addr as i64),
+            Def::Data(index) => self.data_offset(index),
+            Def::Const(expr) => self.eval(expr),
+        };
+        self.pending.remove(name);
+        self.values.insert(name, value);
+        value
+    }
+
+    /// Get the value of `expr`.
+    pub fn eval(&mut self, expr: &Expr) -> Option<i64> {
+        let value = match &expr.kind {
+            ExprKind::Num(n) => return Some(*n),
+            ExprKind::Name((name, span)) => return self.value(name, span),
+            ExprKind::Neg(e) => self.eval(e)?.checked_neg(),
+            ExprKind::Binary(a, op, b) => {
+                // Work out both sides, so that errors in each are reported.
+                let (a, b) = (self.eval(a), self.eval(b));
+                let (a, b) = (a?, b?);
+                match op {
+                    BinOp::Add => a.checked_add(b),
+                    BinOp::Sub => a.checked_sub(b),
+                    BinOp::Mul => a.checked_mul(b),
+                    BinOp::Div if b == 0 => {
+                        self.errors
+                            .push(AsmError::DivisionByZero(expr.span.clone()));
+                        return None;
+                    }
+                    BinOp::Div => a.checked_div(b),
+                }
+            }
+        };
+        if value.is_none() {
+            self.errors
+                .push(AsmError::NumberOverflow(expr.span.clone(), i64::MAX));
+        }
+        value
+    }
+
+    /// Get the value of `expr`, checking that it fits in `field`.
+    pub fn operand(&mut self, expr: &Expr, field: Field) -> Option<i64> {
+        let value = self.eval(expr)?;
+        let range = field.range();
+        if value > *range.end() {
+            let max = *range.end();
+            self.errors
+                .push(AsmError::NumberOverflow(expr.span.clone(), max));
+            None
+        } else if value < *range.start() {
+            let min = *range.start();
+            self.errors
+                .push(AsmError::NumberUnderflow(expr.span.clone(), min));
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    /// Get the number of words defined by the data directive at `index`.
+    pub fn size(&mut self, index: usize) -> Option<i64> {
+        if let Some(size) = self.sizes.get(&index) {
+            return *size;
+        }
+        let size = match self.items.get(index) {
+            Some(Item::Data(def)) => match &def.words {
+                Words::Values(values) => Some(values.len() as i64),
+                Words::Space(n) => self.operand(n, Field::Size),
+                Words::String(chars) => Some(chars.len() as i64),
+            },
+            _ => Some(0),
+        };
+        self.sizes.insert(index, size);
+        size
+    }
+
+    /// Get the offset from SB of the data directive at `index`.
+    fn data_offset(&mut self, index: usize) -> Option<i64> {
+        let mut offset = 0;
+        for i in 0..index {
+            offset += self.size(i)?;
+        }
+        Some(offset)
+    }
+}
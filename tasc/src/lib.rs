@@ -0,0 +1,520 @@
+//! An assembler for the Triangle Abstract Machine that can be called from other
+//! tools.
+//!
+//! [`assemble`] turns assembly source into a [`Program`] of instructions and
+//! initialized data, the labels naming their addresses and the source position of
+//! each instruction, or into [`Diagnostics`] listing every error found. A program
+//! is written to a bytecode file by converting it to a [`Container`].
+
+mod codegen;
+pub mod errors;
+mod expr;
+pub mod lsp;
+
+use std::{
+    fmt::{Debug, Display, Error, Formatter},
+    ops::Range,
+};
+
+use common::{
+    container::{Container, Symbol},
+    debug::DebugInfo,
+    instruction::{Instruction, Opcode, Register},
+};
+use lalrpop_util::lalrpop_mod;
+
+use crate::{
+    errors::AsmError,
+    expr::{Expr, ExprKind, Field},
+};
+
+lalrpop_mod!(
+    #[allow(clippy::all)]
+    tasm
+);
+
+/// A label and its span in the source.
+pub(crate) type Label = (String, Range<usize>);
+
+#[derive(Clone)]
+pub(crate) struct InstrData {
+    label: Option<(String, Range<usize>)>,
+    data: Instruction,
+    /// The `n` operand, filled in once every label and constant is known.
+    n: Option<Expr>,
+    /// The `d` operand and the field it must fit, filled in once every label and
+    /// constant is known.
+    d: Option<(Expr, Field)>,
+    /// A label naming the code address of a jump or call.
+    named_dest: Option<(String, Range<usize>)>,
+    /// A label at the base of an address operand, written `[label±d]`.
+    named_addr: Option<(String, Range<usize>)>,
+    span: Range<usize>,
+}
+
+impl InstrData {
+    pub fn new(op: Opcode, r: Register, n: u8, d: i16) -> InstrData {
+        InstrData {
+            label: None,
+            data: Instruction {
+                op: op as u8,
+                r: r as u8,
+                n,
+                d,
+            },
+            n: None,
+            d: None,
+            named_dest: None,
+            named_addr: None,
+            span: 0..0,
+        }
+    }
+
+    /// Set the `n` operand.
+    pub fn with_n(mut self, n: Expr) -> InstrData {
+        self.n = Some(n);
+        self
+    }
+
+    /// Set the `d` operand, which must fit in `field`.
+    pub fn with_d(mut self, d: Expr, field: Field) -> InstrData {
+        self.d = Some((d, field));
+        self
+    }
+
+    /// Set the register and `d` operand from an address operand.
+    pub fn with_addr(mut self, addr: Addr) -> InstrData {
+        self.data.r = addr.r as u8;
+        self.named_addr = addr.label;
+        self.with_d(addr.d, Field::Offset)
+    }
+
+    /// Set the code address of a jump or call, relative to CB.
+    pub fn with_dest(mut self, dest: Expr) -> InstrData {
+        self.data.r = Register::CB as u8;
+        if let ExprKind::Name(label) = &dest.kind {
+            self.named_dest = Some(label.clone());
+        }
+        self.with_d(dest, Field::Offset)
+    }
+}
+
+/// An address operand, relative to a register or to a label.
+pub(crate) struct Addr {
+    r: Register,
+    d: Expr,
+    label: Option<(String, Range<usize>)>,
+}
+
+impl Addr {
+    pub fn new(r: Register, d: Expr, label: Option<(String, Range<usize>)>) -> Addr {
+        Addr { r, d, label }
+    }
+}
+
+/// Words of initialized data defined by a directive.
+#[derive(Clone)]
+pub(crate) struct DataDef {
+    label: Option<(String, Range<usize>)>,
+    words: Words,
+    span: Range<usize>,
+}
+
+impl DataDef {
+    pub fn new(words: Words) -> DataDef {
+        DataDef {
+            label: None,
+            words,
+            span: 0..0,
+        }
+    }
+}
+
+/// The words defined by a data directive.
+#[derive(Clone)]
+pub(crate) enum Words {
+    /// A word holding each value.
+    Values(Vec<Expr>),
+    /// A number of words holding zero.
+    Space(Expr),
+    /// A word for each character of a string, followed by a zero.
+    String(Vec<i16>),
+}
+
+/// A constant defined by `.equ`.
+#[derive(Clone)]
+pub(crate) struct EquDef {
+    name: (String, Range<usize>),
+    value: Expr,
+    span: Range<usize>,
+}
+
+impl EquDef {
+    pub fn new(
+        name: (String, Range<usize>),
+        value: Expr,
+        span: Range<usize>,
+    ) -> EquDef {
+        EquDef { name, value, span }
+    }
+}
+
+/// A statement of a program, which is an instruction, a data directive or the
+/// definition of a constant.
+#[derive(Clone)]
+pub(crate) enum Item {
+    Instr(InstrData),
+    Data(DataDef),
+    Equ(EquDef),
+}
+
+impl Item {
+    /// Get the label on the statement, or the name of the constant it defines.
+    fn label(&self) -> Option<&Label> {
+        match self {
+            Item::Instr(instr) => instr.label.as_ref(),
+            Item::Data(data) => data.label.as_ref(),
+            Item::Equ(def) => Some(&def.name),
+        }
+    }
+
+    fn set_label(&mut self, label: Label) {
+        match self {
+            Item::Instr(instr) => instr.label = Some(label),
+            Item::Data(data) => data.label = Some(label),
+            Item::Equ(def) => def.name = label,
+        }
+    }
+
+    fn span(&self) -> &Range<usize> {
+        match self {
+            Item::Instr(instr) => &instr.span,
+            Item::Data(data) => &data.span,
+            Item::Equ(def) => &def.span,
+        }
+    }
+
+    /// Get the expressions in the statement's operands.
+    fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            Item::Instr(instr) => instr
+                .n
+                .iter()
+                .chain(instr.d.as_ref().map(|(d, _)| d))
+                .collect(),
+            Item::Data(data) => match &data.words {
+                Words::Values(values) => values.iter().collect(),
+                Words::Space(n) => vec![n],
+                Words::String(_) => Vec::new(),
+            },
+            Item::Equ(def) => vec![&def.value],
+        }
+    }
+}
+
+/// Options for assembling a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Name of the source file, recorded in the debug information and shown in
+    /// rendered errors.
+    pub filename: String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            filename: String::from("<input>"),
+        }
+    }
+}
+
+/// An assembled program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    /// The instructions, in order of address.
+    pub code: Vec<Instruction>,
+    /// The initialized data, to be loaded at SB before the program runs.
+    pub data: Vec<i16>,
+    /// Every label, with the code labels sorted by address followed by the data
+    /// labels sorted by address.
+    pub symbols: Vec<Symbol>,
+    /// The source position of each instruction.
+    pub debug: DebugInfo,
+}
+
+impl From<Program> for Container {
+    fn from(value: Program) -> Self {
+        Container {
+            code: value.code,
+            data: value.data,
+            symbols: value.symbols,
+            debug: value.debug.to_bytes(),
+            ..Container::default()
+        }
+    }
+}
+
+/// The errors that stopped a program from being assembled, with the source they
+/// refer to.
+#[derive(Clone, PartialEq)]
+pub struct Diagnostics {
+    filename: String,
+    source: String,
+    errors: Vec<AsmError>,
+}
+
+impl Diagnostics {
+    /// Get every error found, sorted by position.
+    pub fn errors(&self) -> &[AsmError] {
+        &self.errors
+    }
+
+    /// Take the errors found, sorted by position.
+    pub fn into_errors(self) -> Vec<AsmError> {
+        self.errors
+    }
+}
+
+impl Debug for Diagnostics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.debug_struct("Diagnostics")
+            .field("filename", &self.filename)
+            .field("errors", &self.errors)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Render each error with its source line, followed by a count of the errors.
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        for e in &self.errors {
+            writeln!(f, "{}", e.render(&self.filename, &self.source))?;
+        }
+        let plural = if self.errors.len() == 1 { "" } else { "s" };
+        write!(
+            f,
+            "error: could not assemble `{}` due to {} error{plural}",
+            self.filename,
+            self.errors.len()
+        )
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+/// Assemble a program from `source`, returning every error found if it cannot be
+/// assembled.
+pub fn assemble(source: &str, options: &Options) -> Result<Program, Diagnostics> {
+    let mut recovered = Vec::new();
+    let parsed = tasm::ProgramParser::new().parse(&mut recovered, source);
+
+    let mut errors: Vec<AsmError> =
+        recovered.into_iter().map(|e| e.error.into()).collect();
+    let code = match parsed {
+        Ok(data) => codegen::gen_code(data, &options.filename, source),
+        Err(e) => Err(vec![e.into()]),
+    };
+
+    match code {
+        Ok(code) if errors.is_empty() => Ok(code),
+        code => {
+            errors.extend(code.err().unwrap_or_default());
+            errors.sort_by_key(|e| e.span().start);
+            Err(Diagnostics {
+                filename: options.filename.clone(),
+                source: source.to_string(),
+                errors,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::container::SymbolKind;
+
+    use super::*;
+
+    fn options(filename: &str) -> Options {
+        Options {
+            filename: filename.to_string(),
+        }
+    }
+
+    #[test]
+    fn assemble_ok() {
+        let program = assemble("loop: jump loop", &options("loop.tasm")).unwrap();
+        assert_eq!(
+            vec![Instruction {
+                op: 12,
+                r: 0,
+                n: 0,
+                d: 0
+            }],
+            program.code
+        );
+        assert_eq!("loop", program.symbols[0].name);
+    }
+
+    #[test]
+    fn assemble_source_locs() {
+        let src = "# start #\npush 1\n\nend: halt\n";
+        let program = assemble(src, &options("a.tasm")).unwrap();
+        let debug = Container::from(program).debug_info().unwrap();
+        assert_eq!(Some(String::from("a.tasm:2:1")), debug.describe(0));
+        assert_eq!(Some(String::from("a.tasm:4:6")), debug.describe(1));
+    }
+
+    #[test]
+    fn assemble_reports_every_error() {
+        let src = "a: load 256, [sb+0]\n   jump b\na: halt\n   push @";
+
+        let errors = assemble(src, &options("errors.tasm")).unwrap_err();
+        let errors = errors.errors();
+
+        assert_eq!(4, errors.len());
+        assert_eq!(AsmError::NumberOverflow(8..11, 255), errors[0]);
+        assert_eq!(
+            AsmError::UndefinedLabel(28..29, String::from("b")),
+            errors[1]
+        );
+        assert_eq!(
+            AsmError::DuplicateLabel(30..31, String::from("a")),
+            errors[2]
+        );
+        assert!(matches!(errors[3], AsmError::UnexpectedToken(_, _, _)));
+    }
+
+    #[test]
+    fn assemble_data_directives() {
+        let src = "      load 1, [count]\n      loada [msg+1]\n      jump [end]\n\
+                   count: .word -2\n\
+                   table: .words 1, 0x10, 3\n\
+                   buf: .space 2\n\
+                   msg: .string \"hi\\n\"\n\
+                   end: halt\n";
+
+        let program = assemble(src, &options("data.tasm")).unwrap();
+
+        assert_eq!(vec![-2, 1, 16, 3, 0, 0, 104, 105, 10, 0], program.data);
+        let addr = |i: usize| {
+            let instr = program.code[i];
+            (Register::try_from(instr.r).unwrap(), instr.d)
+        };
+        assert_eq!((Register::SB, 0), addr(0));
+        assert_eq!((Register::SB, 7), addr(1));
+        assert_eq!((Register::CB, 3), addr(2));
+        let symbols: Vec<(&str, SymbolKind, u16)> = program
+            .symbols
+            .iter()
+            .map(|sym| (sym.name.as_str(), sym.kind, sym.addr))
+            .collect();
+        assert_eq!(
+            vec![
+                ("end", SymbolKind::Code, 3),
+                ("count", SymbolKind::Data, 0),
+                ("table", SymbolKind::Data, 1),
+                ("buf", SymbolKind::Data, 4),
+                ("msg", SymbolKind::Data, 6),
+            ],
+            symbols
+        );
+        assert_eq!(program.data, Container::from(program.clone()).data);
+    }
+
+    #[test]
+    fn assemble_data_errors() {
+        let src = "jump msg\nmsg: .string \"\\q\u{1F600}\"\n.string 5";
+
+        let errors = assemble(src, &options("data.tasm")).unwrap_err();
+        let errors = errors.errors();
+
+        assert_eq!(4, errors.len());
+        assert_eq!(AsmError::DataLabel(5..8, String::from("msg")), errors[0]);
+        assert_eq!(AsmError::UnknownEscape(23..25, 'q'), errors[1]);
+        assert_eq!(AsmError::WideCharacter(25..29, '\u{1F600}'), errors[2]);
+        assert_eq!("unexpected `5`, expected a string", errors[3].to_string());
+    }
+
+    #[test]
+    fn assemble_data_too_large() {
+        let src = "a: .space 40000\nb: .space 40000\n";
+
+        let errors = assemble(src, &options("data.tasm")).unwrap_err();
+
+        assert_eq!(&[AsmError::DataTooLarge(19..31, 65533)], errors.errors());
+    }
+
+    #[test]
+    fn assemble_expressions() {
+        let src = ".equ COUNT 3\n.equ FRAME_SIZE COUNT+1\n\
+                   start: push FRAME_SIZE\n\
+                   \x20      load COUNT-1, [sb+COUNT*2]\n\
+                   \x20      loada [cb+handler]\n\
+                   \x20      loadl end-start\n\
+                   \x20      store 1, [table+(COUNT-1)]\n\
+                   handler: halt\n\
+                   end: halt\n\
+                   table: .words COUNT, -COUNT, 0xffff\n\
+                   buf: .space COUNT*2/3\n\
+                   last: .word buf\n";
+
+        let program = assemble(src, &options("expr.tasm")).unwrap();
+
+        let fields: Vec<(u8, u8, i16)> = program
+            .code
+            .iter()
+            .map(|instr| (instr.r, instr.n, instr.d))
+            .collect();
+        let (cb, sb) = (Register::CB as u8, Register::SB as u8);
+        assert_eq!(
+            vec![(cb, 0, 4), (sb, 2, 6), (cb, 0, 5), (cb, 0, 6), (sb, 1, 2)],
+            fields[..5]
+        );
+        assert_eq!(vec![3, -3, -1, 0, 0, 3], program.data);
+        assert!(program.symbols.iter().all(|sym| sym.name != "COUNT"));
+    }
+
+    #[test]
+    fn assemble_data_address_as_code() {
+        let src = "jump [msg]\njumpif 0, [msg+1]\ncall sb, [msg]\nloada [msg]\n\
+                   msg: .string \"hi\"";
+
+        let errors = assemble(src, &options("data.tasm")).unwrap_err();
+
+        let msg =
+            |start: usize| AsmError::DataLabel(start..start + 3, String::from("msg"));
+        assert_eq!(&[msg(6), msg(22), msg(39)], errors.errors());
+    }
+
+    #[test]
+    fn assemble_expression_errors() {
+        let src = ".equ BIG 100*3\n.equ LOOP LOOP+1\n      load BIG, [sb+0]\n\
+                   \x20     loada [sb-40000/1]\n      push 1/(BIG-300)\n\
+                   \x20     loada [BIG]\n";
+
+        let errors = assemble(src, &options("expr.tasm")).unwrap_err();
+
+        assert_eq!(
+            &[
+                AsmError::CircularDefinition(25..29, String::from("LOOP")),
+                AsmError::NumberOverflow(43..46, 255),
+                AsmError::NumberUnderflow(70..78, -32768),
+                AsmError::DivisionByZero(91..102),
+                AsmError::NotALabel(116..119, String::from("BIG")),
+            ],
+            errors.errors()
+        );
+    }
+
+    #[test]
+    fn diagnostics_render_every_error() {
+        let diagnostics = assemble("jump a\njump b\n", &options("x.tasm")).unwrap_err();
+        let text = diagnostics.to_string();
+        assert!(
+            text.starts_with("error: use of undefined label `a`\n --> x.tasm:1:6\n")
+        );
+        assert!(text.contains("error: use of undefined label `b`\n --> x.tasm:2:6\n"));
+        assert!(text.ends_with("error: could not assemble `x.tasm` due to 2 errors"));
+    }
+}
@@ -0,0 +1,1021 @@
+//! A server for the Language Server Protocol, so that editors can check and
+//! navigate assembly source as it is written.
+//!
+//! The server reads JSON-RPC messages from one stream and writes to another, each
+//! framed with a `Content-Length` header. Documents are synchronised in full, and
+//! each is parsed with the assembler's grammar whenever it changes, publishing the
+//! errors `tasc` would report as diagnostics. Labels can be followed to their
+//! definition and their uses listed, as can constants defined by `.equ`, and each
+//! is a document symbol. Hovering over a mnemonic or primitive shows its operands
+//! and its effect on the stack, and mnemonics, registers, primitives, labels and
+//! constants are offered as completions where the grammar allows them.
+//!
+//! Positions are lines and UTF-16 code units, as the protocol requires by default.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, Read, Write},
+    ops::Range,
+};
+
+use common::instruction::{Opcode, Primitive, Register};
+use serde_json::{json, Value};
+
+use crate::{
+    assemble,
+    codegen::line_starts,
+    errors::AsmError,
+    expr::{definitions, Def, Env},
+    tasm, Diagnostics, Item, Label, Options,
+};
+
+/// The largest message body that is read, in bytes.
+const MAX_CONTENT_LENGTH: usize = 1 << 24;
+
+/// JSON-RPC error code for a message that is not valid JSON.
+const PARSE_ERROR: i64 = -32700;
+/// JSON-RPC error code for a request the server will not accept.
+const INVALID_REQUEST: i64 = -32600;
+/// JSON-RPC error code for a request the server does not support.
+const METHOD_NOT_FOUND: i64 = -32601;
+/// JSON-RPC error code for a request with bad parameters.
+const INVALID_PARAMS: i64 = -32602;
+
+/// Completion item kinds from the protocol.
+const COMPLETE_FUNCTION: u64 = 3;
+const COMPLETE_VARIABLE: u64 = 6;
+const COMPLETE_KEYWORD: u64 = 14;
+const COMPLETE_REFERENCE: u64 = 18;
+const COMPLETE_CONSTANT: u64 = 21;
+
+/// Symbol kinds from the protocol.
+const SYMBOL_FUNCTION: u64 = 12;
+const SYMBOL_VARIABLE: u64 = 13;
+const SYMBOL_CONSTANT: u64 = 14;
+const SYMBOL_NUMBER: u64 = 16;
+
+/// Get the operands, stack effect and description of an instruction, with the top
+/// of the stack on the right of the effect.
+fn mnemonic_info(op: Opcode) -> (&'static str, &'static str, &'static str) {
+    match op {
+        Opcode::Load => (
+            "n, [r±d]",
+            "-> w1 .. wn",
+            "Push the n words stored at address r±d.",
+        ),
+        Opcode::LoadA => ("[r±d]", "-> a", "Push the address r±d."),
+        Opcode::LoadI => (
+            "n",
+            "a -> w1 .. wn",
+            "Pop an address and push the n words stored there.",
+        ),
+        Opcode::LoadL => ("d", "-> d", "Push the literal d."),
+        Opcode::Store => (
+            "n, [r±d]",
+            "w1 .. wn ->",
+            "Pop n words and store them at address r±d.",
+        ),
+        Opcode::StoreI => (
+            "n",
+            "w1 .. wn a ->",
+            "Pop an address, then pop n words and store them there.",
+        ),
+        Opcode::Call => (
+            "prim | r, label | r, [r±d]",
+            "args -> results",
+            "Call a primitive by name, or the routine at a label or address with the \
+             frame in register r as its static link.",
+        ),
+        Opcode::CallI => (
+            "",
+            "sl a ->",
+            "Pop a code address and a static link and call the routine there.",
+        ),
+        Opcode::Return => (
+            "n, d",
+            "a1 .. ad frame r1 .. rn -> r1 .. rn",
+            "Return from the routine, keeping its n-word result and discarding its \
+             frame and the d words of arguments below it.",
+        ),
+        Opcode::Push => ("d", "-> u1 .. ud", "Reserve d uninitialized words."),
+        Opcode::Pop => (
+            "n, d",
+            "x1 .. xd r1 .. rn -> r1 .. rn",
+            "Discard the d words below the n-word result on top of the stack.",
+        ),
+        Opcode::Jump => ("label | [r±d]", "->", "Jump to a label or code address."),
+        Opcode::JumpI => ("", "a ->", "Pop a code address and jump to it."),
+        Opcode::JumpIf => (
+            "n, label | n, [r±d]",
+            "v ->",
+            "Pop a word and jump to a label or code address if it equals n.",
+        ),
+        Opcode::Halt => ("", "->", "Stop the program."),
+    }
+}
+
+/// Describe the use of a register.
+fn register_info(r: Register) -> &'static str {
+    match r {
+        Register::CB => "code base",
+        Register::CT => "code top",
+        Register::PB => "primitives base",
+        Register::PT => "primitives top",
+        Register::SB => "stack base",
+        Register::ST => "stack top",
+        Register::HB => "heap base",
+        Register::HT => "heap top",
+        Register::LB => "local base",
+        Register::L1 => "local base of the enclosing frame",
+        Register::L2 => "local base of the frame enclosing l1",
+        Register::L3 => "local base of the frame enclosing l2",
+        Register::L4 => "local base of the frame enclosing l3",
+        Register::L5 => "local base of the frame enclosing l4",
+        Register::L6 => "local base of the frame enclosing l5",
+        Register::CP => "code pointer",
+    }
+}
+
+/// Get the operands and description of each directive.
+const DIRECTIVES: [(&str, &str, &str); 5] = [
+    (".word", "v", "Define a word of data holding v."),
+    (
+        ".words",
+        "v1, .., vn",
+        "Define n words of data holding v1 to vn.",
+    ),
+    (".space", "n", "Define n words of data holding zero."),
+    (
+        ".string",
+        "\"text\"",
+        "Define a word of data for each character of the text, followed by a zero.",
+    ),
+    (
+        ".equ",
+        "NAME v",
+        "Define the constant NAME to stand for v in any operand.",
+    ),
+];
+
+fn is_word(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Apply a change to a document's `text`, replacing the range it names, or all of
+/// the text if it names none. Gives `None` if the change is malformed.
+fn apply_change(text: String, change: &Value) -> Option<String> {
+    let new = change["text"].as_str()?;
+    let Some(range) = change.get("range") else {
+        return Some(new.to_string());
+    };
+    let doc = Document {
+        line_starts: line_starts(&text),
+        text,
+        items: Vec::new(),
+        errors: Vec::new(),
+    };
+    let start = doc.offset(&range["start"])?;
+    let end = doc.offset(&range["end"])?.max(start);
+    let mut text = doc.text;
+    text.replace_range(start..end, new);
+    Some(text)
+}
+
+/// An open document and what was parsed from it.
+struct Document {
+    text: String,
+    line_starts: Vec<usize>,
+    /// The statements that parsed, in order.
+    items: Vec<Item>,
+    errors: Vec<AsmError>,
+}
+
+impl Document {
+    fn new(uri: &str, text: String) -> Document {
+        let items = tasm::ProgramParser::new()
+            .parse(&mut Vec::new(), &text)
+            .unwrap_or_default();
+        let options = Options {
+            filename: uri.to_string(),
+        };
+        let errors = assemble(&text, &options)
+            .err()
+            .map(Diagnostics::into_errors)
+            .unwrap_or_default();
+        Document {
+            line_starts: line_starts(&text),
+            text,
+            items,
+            errors,
+        }
+    }
+
+    /// Get the position of the byte at `offset`.
+    fn position(&self, offset: usize) -> Value {
+        let mut offset = offset.min(self.text.len());
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let start = self.line_starts[line];
+        let character: usize =
+            self.text[start..offset].chars().map(char::len_utf16).sum();
+        json!({"line": line, "character": character})
+    }
+
+    /// Get the offset of the byte at `position`, clamped to the end of its line.
+    fn offset(&self, position: &Value) -> Option<usize> {
+        let line = position["line"].as_u64()? as usize;
+        let character = position["character"].as_u64()? as usize;
+        let start = *self.line_starts.get(line)?;
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .map_or(self.text.len(), |next| next - 1);
+
+        let mut units = 0;
+        for (i, c) in self.text[start..end].char_indices() {
+            if units >= character {
+                return Some(start + i);
+            }
+            units += c.len_utf16();
+        }
+        Some(end)
+    }
+
+    fn range(&self, span: &Range<usize>) -> Value {
+        json!({"start": self.position(span.start), "end": self.position(span.end)})
+    }
+
+    fn location(&self, uri: &str, span: &Range<usize>) -> Value {
+        json!({"uri": uri, "range": self.range(span)})
+    }
+
+    fn diagnostics(&self) -> Value {
+        let diagnostics: Vec<Value> = self
+            .errors
+            .iter()
+            .map(|e| {
+                json!({
+                    "range": self.range(&e.span()),
+                    "severity": 1,
+                    "source": "tasc",
+                    "message": e.to_string(),
+                })
+            })
+            .collect();
+        json!(diagnostics)
+    }
+
+    /// Check if the byte at `offset` is inside a comment or a string.
+    fn in_comment(&self, offset: usize) -> bool {
+        let mut delim = None;
+        let mut escaped = false;
+        for c in self.text[..offset].chars() {
+            match (delim, c) {
+                (Some('"'), _) if escaped => escaped = false,
+                (Some('"'), '\\') => escaped = true,
+                (None, '#' | '"') => delim = Some(c),
+                (Some(d), _) if c == d => delim = None,
+                _ => (),
+            }
+        }
+        delim.is_some()
+    }
+
+    /// Get the word around `offset` and its span, unless it is in a comment.
+    fn word_at(&self, offset: usize) -> Option<(&str, Range<usize>)> {
+        let start = self.text[..offset]
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !is_word(*c))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let end = self.text[offset..]
+            .find(|c| !is_word(c))
+            .map_or(self.text.len(), |i| offset + i);
+        if start == end || self.in_comment(start) {
+            return None;
+        }
+        Some((&self.text[start..end], start..end))
+    }
+
+    /// Get the labels and constants used in operands.
+    fn uses(&self) -> impl Iterator<Item = &Label> {
+        self.items
+            .iter()
+            .flat_map(Item::exprs)
+            .flat_map(|expr| expr.names())
+    }
+
+    /// Get the name of the label or constant defined or used around `offset`.
+    fn label_at(&self, offset: usize) -> Option<&str> {
+        self.items
+            .iter()
+            .filter_map(Item::label)
+            .chain(self.uses())
+            .find(|(_, span)| span.start <= offset && offset <= span.end)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Get what the label or constant `name` is first defined as, and the span of
+    /// its definition.
+    fn definition(&self, name: &str) -> Option<(Def<'_>, &Range<usize>)> {
+        definitions(&self.items)
+            .into_iter()
+            .find(|((label, _), _)| label == name)
+            .map(|((_, span), def)| (def, span))
+    }
+
+    /// Describe the address of a label or the value of a constant.
+    fn describe(&self, name: &str, def: Def) -> String {
+        let value = Env::new(&self.items).value(name, &(0..0));
+        match (def, value) {
+            (Def::Code(addr), _) => format!("code address {addr}"),
+            (Def::Data(_), Some(addr)) => format!("data at sb+{addr}"),
+            (Def::Data(_), None) => String::from("data"),
+            (Def::Const(_), Some(value)) => format!("constant equal to {value}"),
+            (Def::Const(_), None) => String::from("constant"),
+        }
+    }
+
+    /// Get the spans of the uses of the label or constant `name`, and of its
+    /// definitions if `declaration` is set, in order.
+    fn references(&self, name: &str, declaration: bool) -> Vec<&Range<usize>> {
+        let defs = self.items.iter().filter_map(Item::label);
+        let mut spans: Vec<&Range<usize>> = defs
+            .filter(|_| declaration)
+            .chain(self.uses())
+            .filter(|(label, _)| label == name)
+            .map(|(_, span)| span)
+            .collect();
+        spans.sort_by_key(|span| span.start);
+        spans
+    }
+
+    /// Check if the label `name` is the destination of a `call`.
+    fn is_routine(&self, name: &str) -> bool {
+        self.items.iter().any(|item| match item {
+            Item::Instr(instr) => {
+                instr.data.op == Opcode::Call as u8
+                    && instr
+                        .named_dest
+                        .as_ref()
+                        .is_some_and(|(dest, _)| dest == name)
+            }
+            Item::Data(_) | Item::Equ(_) => false,
+        })
+    }
+
+    fn hover(&self, offset: usize) -> Value {
+        let Some((word, span)) = self.word_at(offset) else {
+            return Value::Null;
+        };
+        let directive = span
+            .start
+            .checked_sub(1)
+            .filter(|dot| self.text[*dot..].starts_with('.'))
+            .and_then(|dot| {
+                let name = &self.text[dot..span.end];
+                DIRECTIVES.iter().find(|(d, _, _)| *d == name)
+            });
+
+        let text = if let Some(name) = self.label_at(offset) {
+            match self.definition(name) {
+                Some((def @ Def::Const(_), _)) => {
+                    format!("**{name}**: {}", self.describe(name, def))
+                }
+                Some((def, _)) => {
+                    format!("**{name}**: label of {}", self.describe(name, def))
+                }
+                None => format!("**{name}**: undefined label"),
+            }
+        } else if let Some((name, operands, description)) = directive {
+            format!("**{name}** {operands}\n\n{description}")
+        } else if let Some(op) = Opcode::ALL.into_iter().find(|op| op.name() == word) {
+            let (operands, effect, description) = mnemonic_info(op);
+            format!("**{word}** {operands}\n\n`{effect}`\n\n{description}")
+        } else if let Some(prim) = Primitive::ALL.into_iter().find(|p| p.name() == word)
+        {
+            let (args, results) = prim.arity();
+            let s = |n: usize| if n == 1 { "" } else { "s" };
+            format!(
+                "**{word}**: primitive at pb+{}, pops {args} word{}, pushes {results} \
+                 word{}\n\n`{}`",
+                prim as u8,
+                s(args),
+                s(results),
+                prim.effect()
+            )
+        } else if let Some(r) = Register::ALL.into_iter().find(|r| r.name() == word) {
+            format!("**{word}**: {}", register_info(r))
+        } else {
+            return Value::Null;
+        };
+
+        json!({
+            "contents": {"kind": "markdown", "value": text},
+            "range": self.range(&span),
+        })
+    }
+
+    /// Get the completions that the grammar allows at `offset`, given what comes
+    /// before it on its line.
+    fn completions(&self, offset: usize) -> Value {
+        if self.in_comment(offset) {
+            return json!([]);
+        }
+        let line_start = self.text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let before = &self.text[line_start..offset];
+        let before = before.rsplit_once(':').map_or(before, |(_, after)| after);
+        let typed = before.len() - before.trim_end_matches(is_word).len();
+        let before = before.trim_end_matches(is_word);
+
+        let mnemonics = || {
+            Opcode::ALL.into_iter().map(|op| {
+                let (operands, _, _) = mnemonic_info(op);
+                json!({
+                    "label": op.name(),
+                    "kind": COMPLETE_KEYWORD,
+                    "detail": operands,
+                })
+            })
+        };
+        // After a dot, replace it along with the rest of the directive's name.
+        let dot = before
+            .ends_with('.')
+            .then(|| self.range(&(offset - typed - 1..offset)));
+        let directives = || {
+            DIRECTIVES.into_iter().map(|(name, operands, _)| {
+                let mut item = json!({
+                    "label": name,
+                    "kind": COMPLETE_KEYWORD,
+                    "detail": operands,
+                });
+                if let Some(range) = &dot {
+                    item["textEdit"] = json!({"range": range, "newText": name});
+                }
+                item
+            })
+        };
+        let registers = || {
+            Register::ALL.into_iter().map(|r| {
+                json!({
+                    "label": r.name(),
+                    "kind": COMPLETE_VARIABLE,
+                    "detail": register_info(r),
+                })
+            })
+        };
+        let primitives = || {
+            Primitive::ALL.into_iter().map(|p| {
+                json!({
+                    "label": p.name(),
+                    "kind": COMPLETE_FUNCTION,
+                    "detail": p.effect(),
+                })
+            })
+        };
+        // Data labels are left out where a code address is expected.
+        let names = |code_only: bool| {
+            let mut defs = definitions(&self.items);
+            defs.retain(|(_, def)| !code_only || !matches!(def, Def::Data(_)));
+            defs.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+            defs.dedup_by(|a, b| a.0 .0 == b.0 .0);
+            defs.into_iter().map(|((name, _), def)| {
+                let kind = match def {
+                    Def::Const(_) => COMPLETE_CONSTANT,
+                    _ => COMPLETE_REFERENCE,
+                };
+                json!({
+                    "label": name,
+                    "kind": kind,
+                    "detail": self.describe(name, def),
+                })
+            })
+        };
+
+        let open = before.rfind('[');
+        let items: Vec<Value> = if open > before.rfind(']') {
+            registers().chain(names(false)).collect()
+        } else {
+            match (before.split_whitespace().next(), before.contains(',')) {
+                (None, _) => mnemonics().chain(directives()).collect(),
+                (Some("."), _) => directives().collect(),
+                (Some("call"), false) => primitives().chain(registers()).collect(),
+                (Some("call" | "jumpif"), true) | (Some("jump"), _) => {
+                    names(true).collect()
+                }
+                (Some("calli" | "jumpi" | "halt"), _) => Vec::new(),
+                _ => names(false).collect(),
+            }
+        };
+        json!(items)
+    }
+
+    fn symbols(&self) -> Value {
+        let symbols: Vec<Value> = self
+            .items
+            .iter()
+            .filter(|item| item.label().is_some())
+            .zip(definitions(&self.items))
+            .map(|(item, ((name, span), def))| {
+                let symbol_kind = match def {
+                    Def::Data(_) => SYMBOL_VARIABLE,
+                    Def::Code(_) if self.is_routine(name) => SYMBOL_FUNCTION,
+                    Def::Code(_) => SYMBOL_CONSTANT,
+                    Def::Const(_) => SYMBOL_NUMBER,
+                };
+                json!({
+                    "name": name,
+                    "detail": self.describe(name, def),
+                    "kind": symbol_kind,
+                    "range": self.range(&(span.start..item.span().end)),
+                    "selectionRange": self.range(span),
+                })
+            })
+            .collect();
+        json!(symbols)
+    }
+}
+
+/// A Language Server Protocol server that writes its messages to `W`.
+pub struct LspServer<W> {
+    out: W,
+    documents: HashMap<String, Document>,
+    shut_down: bool,
+}
+
+impl<W: Write> LspServer<W> {
+    /// Construct a server that writes responses and notifications to `out`.
+    pub fn new(out: W) -> LspServer<W> {
+        LspServer {
+            out,
+            documents: HashMap::new(),
+            shut_down: false,
+        }
+    }
+
+    /// Check if the editor asked the server to shut down before it exited.
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// Serve messages read from `input` until the editor asks the server to exit or
+    /// closes the stream.
+    pub fn serve(&mut self, input: &mut impl BufRead) -> std::io::Result<()> {
+        loop {
+            let mut len = None;
+            loop {
+                let mut line = String::new();
+                if input.read_line(&mut line)? == 0 {
+                    return Ok(());
+                }
+                let line = line.trim();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("Content-Length") {
+                        len = value.trim().parse::<usize>().ok();
+                    }
+                }
+            }
+            let Some(len) = len else {
+                continue;
+            };
+            if len > MAX_CONTENT_LENGTH {
+                std::io::copy(&mut input.take(len as u64), &mut std::io::sink())?;
+                let error =
+                    (PARSE_ERROR, format!("message of {len} bytes is too large"));
+                self.respond(&Value::Null, Err(error))?;
+                continue;
+            }
+
+            let mut body = vec![0; len];
+            input.read_exact(&mut body)?;
+            let message = match serde_json::from_slice(&body) {
+                Ok(message) => message,
+                Err(e) => {
+                    let error = (PARSE_ERROR, format!("malformed message: {e}"));
+                    self.respond(&Value::Null, Err(error))?;
+                    continue;
+                }
+            };
+            if !self.handle(&message)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handle a single message, writing its response and any notifications that
+    /// follow.
+    ///
+    /// Returns `false` if the editor has asked the server to exit.
+    pub fn handle(&mut self, message: &Value) -> std::io::Result<bool> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let Some(id) = message.get("id") else {
+            return self.notification(method, params);
+        };
+
+        let result = match method {
+            _ if self.shut_down => {
+                Err((INVALID_REQUEST, String::from("the server has shut down")))
+            }
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": {"openClose": true, "change": 1},
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {"triggerCharacters": ["["]},
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": {"name": "tasc", "version": env!("CARGO_PKG_VERSION")},
+            })),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => {
+                self.at_position(params).map(|(doc, offset)| {
+                    let uri =
+                        params["textDocument"]["uri"].as_str().unwrap_or_default();
+                    doc.label_at(offset)
+                        .and_then(|name| doc.definition(name))
+                        .map_or(Value::Null, |(_, span)| doc.location(uri, span))
+                })
+            }
+            "textDocument/references" => {
+                self.at_position(params).map(|(doc, offset)| {
+                    let uri =
+                        params["textDocument"]["uri"].as_str().unwrap_or_default();
+                    let declaration = params["context"]["includeDeclaration"] == true;
+                    let spans = doc
+                        .label_at(offset)
+                        .map(|name| doc.references(name, declaration))
+                        .unwrap_or_default();
+                    let locations: Vec<Value> = spans
+                        .into_iter()
+                        .map(|span| doc.location(uri, span))
+                        .collect();
+                    json!(locations)
+                })
+            }
+            "textDocument/hover" => self
+                .at_position(params)
+                .map(|(doc, offset)| doc.hover(offset)),
+            "textDocument/completion" => self
+                .at_position(params)
+                .map(|(doc, offset)| doc.completions(offset)),
+            "textDocument/documentSymbol" => {
+                self.document(params).map(Document::symbols)
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unsupported method `{method}`"))),
+        };
+        self.respond(id, result)?;
+        Ok(true)
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> std::io::Result<bool> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        match method {
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                let doc = Document::new(uri, text.to_string());
+                self.documents.insert(uri.to_string(), doc);
+                self.publish(uri)?;
+            }
+            "textDocument/didChange" => {
+                let text = self.documents.get(uri).map(|doc| doc.text.clone());
+                let mut changes =
+                    params["contentChanges"].as_array().into_iter().flatten();
+                // A change with a range edits the text left by the changes before it.
+                if let Some(text) =
+                    changes.try_fold(text.unwrap_or_default(), apply_change)
+                {
+                    let doc = Document::new(uri, text);
+                    self.documents.insert(uri.to_string(), doc);
+                    self.publish(uri)?;
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                self.publish(uri)?;
+            }
+            _ => (),
+        }
+        Ok(true)
+    }
+
+    /// Get the open document named in a request.
+    fn document(&self, params: &Value) -> Result<&Document, (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        self.documents
+            .get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("`{uri}` is not open")))
+    }
+
+    /// Get the open document and the offset of the position named in a request.
+    fn at_position(&self, params: &Value) -> Result<(&Document, usize), (i64, String)> {
+        let doc = self.document(params)?;
+        match doc.offset(&params["position"]) {
+            Some(offset) => Ok((doc, offset)),
+            None => Err((INVALID_PARAMS, String::from("invalid position"))),
+        }
+    }
+
+    /// Publish the diagnostics of a document, or clear them if it is closed.
+    fn publish(&mut self, uri: &str) -> std::io::Result<()> {
+        let diagnostics = self
+            .documents
+            .get(uri)
+            .map_or(json!([]), Document::diagnostics);
+        let message = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": uri, "diagnostics": diagnostics},
+        });
+        self.send(&message)
+    }
+
+    fn respond(
+        &mut self,
+        id: &Value,
+        result: Result<Value, (i64, String)>,
+    ) -> std::io::Result<()> {
+        let mut response = json!({"jsonrpc": "2.0", "id": id});
+        match result {
+            Ok(result) => response["result"] = result,
+            Err((code, message)) => {
+                response["error"] = json!({"code": code, "message": message})
+            }
+        }
+        self.send(&response)
+    }
+
+    fn send(&mut self, message: &Value) -> std::io::Result<()> {
+        let body = message.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///prog.tasm";
+
+    const SOURCE: &str = "# é #\n\
+                          main: load 1, [three]\n      call lb, double\n\
+                          \x20     call putint\n      halt\n\
+                          double: load 1, [lb-1]\n        loadl 2\n        call mul\n\
+                          \x20       return 1, 1\n\
+                          three: .word 3\n";
+
+    /// Open `text` in a new server, returning it with its diagnostics.
+    fn open(text: &str) -> (LspServer<Vec<u8>>, Value) {
+        let mut server = LspServer::new(Vec::new());
+        let message = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {
+                "textDocument": {"uri": URI, "languageId": "tasm", "text": text},
+            },
+        });
+        server.handle(&message).unwrap();
+        let diagnostics =
+            messages(&mut server).remove(0)["params"]["diagnostics"].take();
+        (server, diagnostics)
+    }
+
+    /// Take the messages the server has written.
+    fn messages(server: &mut LspServer<Vec<u8>>) -> Vec<Value> {
+        let out = String::from_utf8(std::mem::take(&mut server.out)).unwrap();
+        out.split("Content-Length: ")
+            .filter(|s| !s.is_empty())
+            .map(|s| serde_json::from_str(s.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect()
+    }
+
+    /// Send a request about the position `line:character` and get its result.
+    fn request(
+        server: &mut LspServer<Vec<u8>>,
+        method: &str,
+        line: u64,
+        character: u64,
+    ) -> Value {
+        let message = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": {
+                "textDocument": {"uri": URI},
+                "position": {"line": line, "character": character},
+                "context": {"includeDeclaration": true},
+            },
+        });
+        server.handle(&message).unwrap();
+        messages(server).remove(0)["result"].take()
+    }
+
+    fn range(start: (u64, u64), end: (u64, u64)) -> Value {
+        json!({
+            "start": {"line": start.0, "character": start.1},
+            "end": {"line": end.0, "character": end.1},
+        })
+    }
+
+    #[test]
+    fn diagnostics_in_utf16_positions() {
+        let (_, diagnostics) = open("# é 😀 # jump nowhere\n");
+
+        assert_eq!(1, diagnostics.as_array().unwrap().len());
+        assert_eq!(range((0, 14), (0, 21)), diagnostics[0]["range"]);
+        assert_eq!(
+            "use of undefined label `nowhere`",
+            diagnostics[0]["message"]
+        );
+    }
+
+    #[test]
+    fn definition_and_references() {
+        let (mut server, diagnostics) = open(SOURCE);
+        assert_eq!(json!([]), diagnostics);
+
+        let definition = request(&mut server, "textDocument/definition", 2, 16);
+        assert_eq!(range((5, 0), (5, 6)), definition["range"]);
+        let definition = request(&mut server, "textDocument/definition", 1, 17);
+        assert_eq!(range((9, 0), (9, 5)), definition["range"]);
+
+        let references = request(&mut server, "textDocument/references", 5, 3);
+        let ranges: Vec<&Value> = references
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|loc| &loc["range"])
+            .collect();
+        assert_eq!(
+            vec![&range((2, 15), (2, 21)), &range((5, 0), (5, 6))],
+            ranges
+        );
+    }
+
+    #[test]
+    fn hover_shows_stack_effect() {
+        let (mut server, _) = open(SOURCE);
+
+        let hover = request(&mut server, "textDocument/hover", 3, 12);
+        let text = hover["contents"]["value"].as_str().unwrap();
+        assert!(text.starts_with("**putint**: primitive at pb+26, pops 1 word"));
+        assert!(text.contains("`i -> ; write integer i`"));
+
+        let hover = request(&mut server, "textDocument/hover", 6, 8);
+        let text = hover["contents"]["value"].as_str().unwrap();
+        assert!(text.starts_with("**loadl** d\n\n`-> d`"));
+
+        let hover = request(&mut server, "textDocument/hover", 9, 9);
+        let text = hover["contents"]["value"].as_str().unwrap();
+        assert!(text.starts_with("**.word** v\n\n"));
+
+        assert_eq!(
+            Value::Null,
+            request(&mut server, "textDocument/hover", 0, 2)
+        );
+    }
+
+    #[test]
+    fn completions_follow_grammar() {
+        let labels = |items: Value| -> Vec<String> {
+            let items = items.as_array().unwrap().iter();
+            items
+                .map(|item| item["label"].as_str().unwrap().into())
+                .collect()
+        };
+        let (mut server, _) =
+            open("start: call getint\nmsg: .string \"a\"\n  jump \n  load 1, [s\n  .s");
+
+        let items = labels(request(&mut server, "textDocument/completion", 0, 13));
+        assert!(items.contains(&String::from("getint")));
+        assert!(items.contains(&String::from("lb")));
+        assert!(!items.contains(&String::from("load")));
+
+        let items = labels(request(&mut server, "textDocument/completion", 2, 7));
+        assert_eq!(vec!["start"], items);
+
+        let items = labels(request(&mut server, "textDocument/completion", 3, 12));
+        assert_eq!(18, items.len());
+        assert!(items.contains(&String::from("msg")));
+
+        let items = labels(request(&mut server, "textDocument/completion", 3, 3));
+        assert_eq!(20, items.len());
+
+        let items = request(&mut server, "textDocument/completion", 4, 4);
+        assert_eq!(5, items.as_array().unwrap().len());
+        assert_eq!(range((4, 2), (4, 4)), items[0]["textEdit"]["range"]);
+    }
+
+    #[test]
+    fn constants_in_expressions() {
+        let (mut server, diagnostics) = open(
+            ".equ SIZE 2*N\n.equ N 3\n    push SIZE\n    loadl buf-SIZE\n\
+             buf: .space N\n",
+        );
+        assert_eq!(json!([]), diagnostics);
+
+        let hover = request(&mut server, "textDocument/hover", 2, 10);
+        assert_eq!("**SIZE**: constant equal to 6", hover["contents"]["value"]);
+        let definition = request(&mut server, "textDocument/definition", 0, 12);
+        assert_eq!(range((1, 5), (1, 6)), definition["range"]);
+
+        let references = request(&mut server, "textDocument/references", 3, 15);
+        let ranges: Vec<&Value> = references
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|loc| &loc["range"])
+            .collect();
+        assert_eq!(
+            vec![
+                &range((0, 5), (0, 9)),
+                &range((2, 9), (2, 13)),
+                &range((3, 14), (3, 18)),
+            ],
+            ranges
+        );
+
+        let items = request(&mut server, "textDocument/completion", 2, 9);
+        assert_eq!(3, items.as_array().unwrap().len());
+        assert_eq!("SIZE", items[1]["label"]);
+        assert_eq!(COMPLETE_CONSTANT, items[1]["kind"]);
+
+        let symbols = request(&mut server, "textDocument/documentSymbol", 0, 0);
+        assert_eq!(SYMBOL_NUMBER, symbols[0]["kind"]);
+        assert_eq!("constant equal to 6", symbols[0]["detail"]);
+        assert_eq!("data at sb+0", symbols[2]["detail"]);
+    }
+
+    #[test]
+    fn symbols_for_every_label() {
+        let (mut server, _) = open(SOURCE);
+
+        let symbols = request(&mut server, "textDocument/documentSymbol", 0, 0);
+        let symbols = symbols.as_array().unwrap();
+        assert_eq!(3, symbols.len());
+        assert_eq!("main", symbols[0]["name"]);
+        assert_eq!(SYMBOL_CONSTANT, symbols[0]["kind"]);
+        assert_eq!("double", symbols[1]["name"]);
+        assert_eq!("code address 4", symbols[1]["detail"]);
+        assert_eq!(SYMBOL_FUNCTION, symbols[1]["kind"]);
+        assert_eq!(range((5, 0), (5, 6)), symbols[1]["selectionRange"]);
+        assert_eq!("three", symbols[2]["name"]);
+        assert_eq!("data at sb+0", symbols[2]["detail"]);
+        assert_eq!(SYMBOL_VARIABLE, symbols[2]["kind"]);
+    }
+
+    #[test]
+    fn incremental_changes() {
+        let (mut server, diagnostics) = open("main: jump nowhere\nhalt\n");
+        assert_eq!(1, diagnostics.as_array().unwrap().len());
+
+        let message = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": {"uri": URI, "version": 2},
+                "contentChanges": [
+                    {"range": range((0, 11), (0, 18)), "text": "main"},
+                    {"range": range((1, 0), (1, 0)), "text": "loadl 1\n"},
+                ],
+            },
+        });
+        server.handle(&message).unwrap();
+
+        assert_eq!(
+            "main: jump main\nloadl 1\nhalt\n",
+            server.documents[URI].text
+        );
+        let diagnostics = &messages(&mut server)[0]["params"]["diagnostics"];
+        assert_eq!(json!([]), *diagnostics);
+    }
+
+    #[test]
+    fn serve_oversized_message() {
+        let body = r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#;
+        let input = format!(
+            "Content-Length: {}\r\n\r\n{}Content-Length: {}\r\n\r\n{body}",
+            MAX_CONTENT_LENGTH + 1,
+            " ".repeat(MAX_CONTENT_LENGTH + 1),
+            body.len()
+        );
+        let mut server = LspServer::new(Vec::new());
+        server.serve(&mut input.as_bytes()).unwrap();
+
+        let replies = messages(&mut server);
+        assert_eq!(json!(PARSE_ERROR), replies[0]["error"]["code"]);
+        assert_eq!(json!(null), replies[0]["id"]);
+        assert_eq!(json!(2), replies[1]["id"]);
+    }
+}
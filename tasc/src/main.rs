@@ -1,57 +1,91 @@
-mod codegen;
+use std::{fs, fs::OpenOptions, io::Write, process::exit};
 
-use std::{fs, fs::OpenOptions};
-
-use byteorder::{WriteBytesExt, BE};
-use clap::Parser;
-use common::instruction::Instruction;
-use lalrpop_util::lalrpop_mod;
-
-lalrpop_mod!(pub tasm);
-
-#[derive(Clone)]
-pub struct InstrData {
-    label: Option<String>,
-    data: Instruction,
-    named_dest: Option<String>,
-}
-
-impl InstrData {
-    pub fn new(op: u8, r: u8, n: u8, d: i16) -> InstrData {
-        InstrData {
-            label: None,
-            data: Instruction { op, r, n, d },
-            named_dest: None,
-        }
-    }
-}
+use clap::{Parser, Subcommand};
+use common::container::Container;
+use tasc::{assemble, lsp::LspServer, Options};
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Assembly file to compile
-    infile: String,
+    #[arg(required = true)]
+    infile: Option<String>,
 
     ///Name of bytecode file to create
     #[arg(short, default_value_t = String::from("a.out"))]
     outfile: String,
+
+    /// Write a legacy bytecode file of bare instructions with no header
+    #[arg(long)]
+    raw: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve the Language Server Protocol on stdin and stdout, so that an editor can
+    /// check and navigate assembly source
+    Lsp,
 }
 
 fn main() -> std::io::Result<()> {
     let args = Args::parse();
-    let input = fs::read_to_string(args.infile)?;
+    if let Some(Command::Lsp) = &args.command {
+        let mut server = LspServer::new(std::io::stdout());
+        server.serve(&mut std::io::stdin().lock())?;
+        if !server.is_shut_down() {
+            exit(1);
+        }
+        return Ok(());
+    }
+
+    let infile = args.infile.as_deref().unwrap_or_default();
+    let input = fs::read_to_string(infile)?;
 
-    let parser = tasm::ProgramParser::new();
-    let data = parser.parse(&input).unwrap();
-    let code = codegen::gen_code(data);
+    let options = Options {
+        filename: infile.to_string(),
+    };
+    let program = match assemble(&input, &options) {
+        Ok(program) => program,
+        Err(diagnostics) => {
+            eprintln!("{diagnostics}");
+            exit(1);
+        }
+    };
+    // The legacy format has nowhere to put static data, which would be lost.
+    if args.raw && !program.data.is_empty() {
+        eprintln!(
+            "error: `{infile}` defines static data, which cannot be written with \
+             `--raw`"
+        );
+        exit(1);
+    }
+
+    let bytes = if args.raw {
+        program
+            .code
+            .iter()
+            .flat_map(|instr| u32::from(*instr).to_be_bytes())
+            .collect()
+    } else {
+        match Container::from(program).to_bytes() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("error: `{infile}`: {e}");
+                exit(1);
+            }
+        }
+    };
 
     let mut f = OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(&args.outfile)?;
 
-    for instr in code {
-        f.write_u32::<BE>(u32::from(instr))?;
-    }
+    f.write_all(&bytes)?;
 
     Ok(())
 }
@@ -0,0 +1,35 @@
+use std::{fs, path::PathBuf};
+
+use common::container::Container;
+use tasc::{assemble, Options};
+
+fn example(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("examples")
+        .join(name)
+}
+
+#[test]
+fn examples_match_their_bytecode() {
+    for name in ["fac", "gcd", "hyp"] {
+        let filename = format!("{name}.tasm");
+        let source = fs::read_to_string(example(&filename)).unwrap();
+        let options = Options { filename };
+
+        let program = assemble(&source, &options).unwrap();
+
+        let bytecode = fs::read(example(name)).unwrap();
+        assert_eq!(
+            bytecode,
+            Container::from(program).to_bytes().unwrap(),
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn errors_are_returned_not_printed() {
+    let diagnostics = assemble("jump nowhere", &Options::default()).unwrap_err();
+    assert_eq!(1, diagnostics.errors().len());
+    assert!(diagnostics.to_string().contains("--> <input>:1:6"));
+}